@@ -0,0 +1,21 @@
+(module
+  ;; Calls functions of registered modules, chosen at runtime by name.
+  (import "modules" "call" (func $call (param i32 i32 i32 i32 i32 i32) (result i64)))
+
+  (memory (export "memory") 1)
+  (data (i32.const 0) "utils")
+  (data (i32.const 8) "double")
+  (data (i32.const 16) "caller")
+  (data (i32.const 24) "recurse")
+
+  ;; Calls utils.double with the value.
+  (func (export "call_double") (param i32) (result i64)
+    (i64.store (i32.const 32) (i64.extend_i32_s (local.get 0)))
+    (call $call (i32.const 0) (i32.const 5) (i32.const 8) (i32.const 6) (i32.const 32) (i32.const 8))
+  )
+
+  ;; Calls itself through the registered caller module, until the depth limit is hit.
+  (func (export "recurse") (result i64)
+    (call $call (i32.const 16) (i32.const 6) (i32.const 24) (i32.const 7) (i32.const 0) (i32.const 0))
+  )
+)
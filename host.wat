@@ -0,0 +1,28 @@
+(module
+  ;; Import native host functions, which need to be enabled on the request.
+  (import "env" "log" (func $log (param i32 i32)))
+  (import "env" "panic" (func $panic (param i32 i32)))
+  (import "random" "next_u64" (func $next_u64 (result i64)))
+
+  (memory (export "memory") 1)
+  (data (i32.const 0) "hello")
+  (data (i32.const 16) "oh no")
+
+  (func (export "greet") (result i32)
+    i32.const 0
+    i32.const 5
+    call $log
+    i32.const 1
+  )
+
+  (func (export "fail")
+    i32.const 16
+    i32.const 5
+    call $panic
+    unreachable
+  )
+
+  (func (export "random") (result i64)
+    call $next_u64
+  )
+)
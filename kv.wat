@@ -0,0 +1,34 @@
+(module
+  ;; Import key-value storage, scoped to the registered module.
+  (import "kv" "get" (func $get (param i32 i32 i32 i32) (result i32)))
+  (import "kv" "set" (func $set (param i32 i32 i32 i32)))
+  (import "kv" "delete" (func $delete (param i32 i32) (result i32)))
+
+  (memory (export "memory") 1)
+  (data (i32.const 0) "count")
+
+  ;; Increments the stored counter and returns the new value.
+  (func $increment (export "increment") (result i32)
+    (if (i32.lt_s (call $get (i32.const 0) (i32.const 5) (i32.const 16) (i32.const 4)) (i32.const 0))
+      (then (i32.store (i32.const 16) (i32.const 0))))
+    (i32.store (i32.const 16) (i32.add (i32.load (i32.const 16)) (i32.const 1)))
+    (call $set (i32.const 0) (i32.const 5) (i32.const 16) (i32.const 4))
+    (i32.load (i32.const 16))
+  )
+
+  ;; Increments the counter, and then traps.
+  (func (export "increment_trap")
+    (drop (call $increment))
+    unreachable
+  )
+
+  ;; Deletes the counter, returns 1 if it existed.
+  (func (export "reset") (result i32)
+    (call $delete (i32.const 0) (i32.const 5))
+  )
+
+  ;; Tries to store a value larger than allowed.
+  (func (export "set_large")
+    (call $set (i32.const 0) (i32.const 5) (i32.const 0) (i32.const 32768))
+  )
+)
@@ -0,0 +1,8 @@
+(module
+  ;; Parameter names are kept in the name section, to be able to call with named arguments.
+  (func (export "sub") (param $lhs i32) (param $rhs i32) (result i32)
+    local.get $lhs
+    local.get $rhs
+    i32.sub
+  )
+)
@@ -0,0 +1,359 @@
+use crate::auth::ApiKeys;
+use crate::namespaces::NamespaceConfig;
+use crate::utils::limits::{Charge, StoreLimits, UsageCounters};
+use crate::utils::namespace::{namespace_of, resolve};
+use crate::utils::record::{decode_module, encode_module};
+use crate::utils::wasm::exported_functions;
+use crate::utils::{store_wasm_module, WasmModuleRef, WasmStore};
+use anyhow::{anyhow, Error};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures::future::{join_all, try_join_all};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{Read, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Version of the archive layout, checked on import.
+const ARCHIVE_VERSION: u32 = 1;
+/// Path of the manifest in the archive.
+const MANIFEST_PATH: &str = "manifest.json";
+
+/// Entry of a module in the archive manifest.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ManifestEntry {
+    pub name: String,
+    /// Path of the CBOR `WasmModule` record in the archive.
+    pub path: String,
+    /// Hex SHA-256 hash of the record.
+    pub sha256: String,
+    pub host_modules: Vec<String>,
+    /// Id of the key which owns the module.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+}
+
+/// Manifest of an archive, listing the modules in dependency order.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Manifest {
+    pub version: u32,
+    /// Unix timestamp in milliseconds of the export.
+    pub created: u64,
+    pub modules: Vec<ManifestEntry>,
+}
+
+/// Module which failed verification.
+#[derive(Debug)]
+pub struct VerifyFailure {
+    pub name: String,
+    pub error: Error,
+}
+
+/// Orders the modules so that every module comes after its host modules. Host modules are
+/// resolved in the namespace of the module, and ignored if they aren't in the list.
+fn dependency_order(modules: &BTreeMap<String, Vec<String>>) -> Result<Vec<String>, Error> {
+    fn visit(
+        name: &str,
+        modules: &BTreeMap<String, Vec<String>>,
+        visiting: &mut HashSet<String>,
+        order: &mut Vec<String>,
+    ) -> Result<(), Error> {
+        if order.iter().any(|n| n == name) {
+            return Ok(());
+        }
+        if !visiting.insert(name.to_owned()) {
+            return Err(anyhow!("Dependency cycle through module {}", name));
+        }
+        for dep in &modules[name] {
+            let dep = resolve(dep, namespace_of(name));
+            if modules.contains_key(&dep) {
+                visit(&dep, modules, visiting, order)?;
+            }
+        }
+        order.push(name.to_owned());
+        Ok(())
+    }
+
+    let mut order = Vec::with_capacity(modules.len());
+    let mut visiting = HashSet::new();
+    for name in modules.keys() {
+        visit(name, modules, &mut visiting, &mut order)?;
+    }
+    Ok(order)
+}
+
+/// Writes all modules of the store to a gzipped tar archive, with their owners from `keys`.
+/// Returns the number of modules.
+pub async fn export<S, W>(store: &S, keys: &ApiKeys, out: W) -> Result<usize, Error>
+where
+    S: WasmStore + ?Sized,
+    W: Write,
+{
+    let names = store.module_names().await?;
+    let loaded = try_join_all(names.iter().map(|name| store.load_module(name))).await?;
+    let mut records = HashMap::new();
+    let mut deps = BTreeMap::new();
+    for (name, module) in names.into_iter().zip(loaded) {
+        deps.insert(name.clone(), module.host_modules.clone());
+        records.insert(name, encode_module(&module)?);
+    }
+    let mut owners: HashMap<_, _> = keys.owners()?.into_iter().collect();
+
+    let mut builder = tar::Builder::new(GzEncoder::new(out, Compression::default()));
+    let append = |builder: &mut tar::Builder<_>, path: &str, data: &[u8]| {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, path, data)
+    };
+
+    let mut modules = Vec::with_capacity(records.len());
+    for (i, name) in dependency_order(&deps)?.into_iter().enumerate() {
+        let record = &records[&name];
+        let path = format!("modules/{}.cbor", i);
+        append(&mut builder, &path, record)?;
+        modules.push(ManifestEntry {
+            path,
+            sha256: hex::encode(Sha256::digest(record)),
+            host_modules: deps.remove(&name).unwrap_or_default(),
+            owner: owners.remove(&name),
+            name,
+        });
+    }
+    let manifest = Manifest {
+        version: ARCHIVE_VERSION,
+        created: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default(),
+        modules,
+    };
+    append(
+        &mut builder,
+        MANIFEST_PATH,
+        &serde_json::to_vec_pretty(&manifest)?,
+    )?;
+    builder.into_inner()?.finish()?;
+    Ok(manifest.modules.len())
+}
+
+/// Reads an archive into the store, in dependency order, and restores the module owners in `keys`.
+/// The modules are added to the `usage` counters, without checking quotas. Fails if a module
+/// already exists, or if a record doesn't match its hash. Returns the number of modules.
+pub async fn import<S, R>(
+    store: &S,
+    keys: &ApiKeys,
+    usage: &UsageCounters,
+    input: R,
+) -> Result<usize, Error>
+where
+    S: WasmStore + ?Sized,
+    R: Read,
+{
+    let mut files = HashMap::new();
+    let mut archive = tar::Archive::new(GzDecoder::new(input));
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.to_string_lossy().into_owned();
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data)?;
+        files.insert(path, data);
+    }
+
+    let manifest: Manifest = serde_json::from_slice(
+        files
+            .get(MANIFEST_PATH)
+            .ok_or_else(|| anyhow!("Archive has no {}", MANIFEST_PATH))?,
+    )?;
+    if manifest.version != ARCHIVE_VERSION {
+        return Err(anyhow!(
+            "Unsupported archive version {}, expected {}",
+            manifest.version,
+            ARCHIVE_VERSION
+        ));
+    }
+
+    let mut modules = HashMap::new();
+    let mut deps = BTreeMap::new();
+    for entry in &manifest.modules {
+        let record = files
+            .get(&entry.path)
+            .ok_or_else(|| anyhow!("Archive has no record for module {}", entry.name))?;
+        if hex::encode(Sha256::digest(record)) != entry.sha256 {
+            return Err(anyhow!("Record of module {} is corrupted", entry.name));
+        }
+        let (module, _) = decode_module(record)
+            .map_err(|e| anyhow!("Could not read module {}: {}", entry.name, e))?;
+        deps.insert(entry.name.clone(), module.host_modules.clone());
+        modules.insert(entry.name.clone(), (module, entry.owner.as_deref()));
+    }
+
+    // Existing modules are checked first, so a failed import doesn't leave a partial store.
+    for name in modules.keys() {
+        if store.contains_module(name).await? {
+            return Err(anyhow!("Module {} already exists in the store", name));
+        }
+    }
+    for name in dependency_order(&deps)? {
+        let (module, owner) = &modules[&name];
+        let host_modules: Vec<Cow<str>> = module.host_modules.iter().map(|m| m.into()).collect();
+        store_wasm_module(
+            store,
+            &name,
+            &WasmModuleRef {
+                code: &module.code,
+                host_modules: &host_modules,
+                param_names: &module.param_names,
+                metadata: &module.metadata,
+                expires: module.expires,
+            },
+            &StoreLimits::default(),
+            Some(&Charge {
+                counters: usage,
+                owner: *owner,
+                namespace: NamespaceConfig::default(),
+            }),
+        )
+        .await?;
+        if let Some(owner) = owner {
+            keys.set_owner(&name, owner)?;
+        }
+    }
+    Ok(modules.len())
+}
+
+/// Checks that every record of the store deserializes, compiles and has all of its host modules.
+/// Returns the modules which failed.
+pub async fn verify<S>(store: &S) -> Result<Vec<VerifyFailure>, Error>
+where
+    S: WasmStore + ?Sized,
+{
+    let names = store.module_names().await?;
+    let loaded = join_all(names.iter().map(|name| store.load_module(name))).await;
+    let mut failures = Vec::new();
+    for (name, module) in names.iter().zip(loaded) {
+        let res = module.and_then(|module| {
+            exported_functions(&module.code)?;
+            let namespace = namespace_of(name);
+            match module
+                .host_modules
+                .iter()
+                .find(|m| !names.contains(&resolve(m, namespace)))
+            {
+                Some(missing) => Err(anyhow!("Host module {} does not exist", missing)),
+                None => Ok(()),
+            }
+        });
+        if let Err(error) = res {
+            failures.push(VerifyFailure {
+                name: name.clone(),
+                error,
+            });
+        }
+    }
+    Ok(failures)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::local_db::LocalDB;
+    use crate::utils::{Blocking, ModuleMetadata, WasmModule};
+
+    fn store() -> (Blocking<LocalDB>, ApiKeys, UsageCounters) {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let keys = ApiKeys::new(&db).unwrap();
+        let usage = UsageCounters::new(&db).unwrap();
+        (Blocking::new(LocalDB(db)), keys, usage)
+    }
+
+    async fn put<S: WasmStore>(store: &S, name: &str, code: &[u8], host_modules: &[Cow<'_, str>]) {
+        let module = WasmModuleRef {
+            code,
+            host_modules,
+            param_names: &HashMap::new(),
+            metadata: &ModuleMetadata::default(),
+            expires: None,
+        };
+        store_wasm_module(store, name, &module, &StoreLimits::default(), None)
+            .await
+            .unwrap()
+    }
+
+    #[async_std::test]
+    async fn export_import_verify() {
+        let (source, source_keys, _) = store();
+        let utils = include_bytes!("../utils.wasm");
+        let linking = include_bytes!("../linking.wasm");
+        put(&source, "utils", utils, &[]).await;
+        // Named so that it sorts before its host module.
+        put(&source, "a_linking", linking, &["utils".into()]).await;
+        source_keys.set_owner("utils", "owner").unwrap();
+
+        let mut archive = Vec::new();
+        assert_eq!(
+            export(&source, &source_keys, &mut archive).await.unwrap(),
+            2
+        );
+
+        let (target, keys, usage) = store();
+        assert_eq!(
+            import(&target, &keys, &usage, archive.as_slice())
+                .await
+                .unwrap(),
+            2
+        );
+        assert_eq!(
+            target.load_module("utils").await.unwrap().code,
+            utils.as_ref()
+        );
+        assert_eq!(
+            target.load_module("a_linking").await.unwrap().host_modules,
+            ["utils"]
+        );
+        assert_eq!(
+            keys.owners().unwrap(),
+            [("utils".to_owned(), "owner".to_owned())]
+        );
+        let totals = usage.totals().unwrap();
+        assert_eq!(totals.store.modules, 2);
+        assert_eq!(totals.owners["owner"].bytes, utils.len() as u64);
+        assert!(verify(&target).await.unwrap().is_empty());
+
+        // Importing again fails before storing anything.
+        assert!(import(&target, &keys, &usage, archive.as_slice())
+            .await
+            .is_err());
+
+        // Records which don't compile or miss host modules fail verification.
+        let db = &target.0 .0;
+        db.insert(
+            "broken",
+            encode_module(&WasmModule {
+                code: b"not wasm".to_vec(),
+                host_modules: vec!["missing".to_owned()],
+                param_names: HashMap::new(),
+                metadata: ModuleMetadata::default(),
+                expires: None,
+            })
+            .unwrap(),
+        )
+        .unwrap();
+        db.insert("corrupted", b"not cbor".as_ref()).unwrap();
+        let failures = verify(&target).await.unwrap();
+        let names: Vec<_> = failures.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, ["broken", "corrupted"]);
+    }
+
+    #[test]
+    fn dependency_cycle() {
+        let mut deps = BTreeMap::new();
+        deps.insert("a".to_owned(), vec!["b".to_owned()]);
+        deps.insert("b".to_owned(), vec!["a".to_owned()]);
+        assert!(dependency_order(&deps).is_err());
+    }
+}
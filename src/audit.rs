@@ -0,0 +1,195 @@
+use crate::server::limits::client_id;
+use crate::server::module_key;
+use crate::utils::WasmStore;
+use anyhow::Error;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use sled::{Db, Tree};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tide::http::Method;
+use tide::utils::async_trait;
+use tide::{Middleware, Next, Request};
+
+/// Name of the sled tree for audit entries, keyed by a monotonic id.
+const AUDIT_TREE: &str = "audit";
+/// Default number of entries returned by a query.
+pub const DEFAULT_QUERY_LIMIT: usize = 100;
+
+/// Audit entry of a registration, deletion or execution.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    /// Unix timestamp in milliseconds of when the request was received.
+    pub timestamp: u64,
+    /// API key id, or IP if authentication is disabled.
+    pub client: String,
+    pub route: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub module: Option<String>,
+    /// Hex SHA-256 hash of the executed or registered code.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub module_hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub function: Option<String>,
+    /// Hex SHA-256 hash of the params JSON, so values are not stored.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub params_digest: Option<String>,
+    /// Response status code.
+    pub status: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub duration_ms: u64,
+}
+
+/// Filter of an audit query. Entries are returned newest first.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default, Clone, PartialEq)]
+#[serde(default)]
+pub struct AuditQuery {
+    pub client: Option<String>,
+    pub route: Option<String>,
+    pub module: Option<String>,
+    /// Only successful entries if true, only failed entries if false.
+    pub success: Option<bool>,
+    /// Unix timestamp in milliseconds of the oldest entry.
+    pub since: Option<u64>,
+    /// Unix timestamp in milliseconds of the newest entry.
+    pub until: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+impl AuditQuery {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        self.client.as_ref().map_or(true, |c| *c == entry.client)
+            && self.route.as_ref().map_or(true, |r| *r == entry.route)
+            && self
+                .module
+                .as_ref()
+                .map_or(true, |m| Some(m) == entry.module.as_ref())
+            && self.success.map_or(true, |s| s == (entry.status < 400))
+            && self.since.map_or(true, |t| entry.timestamp >= t)
+            && self.until.map_or(true, |t| entry.timestamp <= t)
+    }
+}
+
+/// Append-only audit trail, stored in a sled tree.
+pub struct AuditLog {
+    db: Db,
+    entries: Tree,
+}
+
+impl AuditLog {
+    pub fn new(db: &Db) -> Result<Self, Error> {
+        Ok(Self {
+            db: db.clone(),
+            entries: db.open_tree(AUDIT_TREE)?,
+        })
+    }
+
+    /// Appends an entry to the log.
+    pub fn append(&self, entry: &AuditEntry) -> Result<(), Error> {
+        let id = self.db.generate_id()?;
+        self.entries
+            .insert(id.to_be_bytes(), serde_json::to_vec(entry)?)?;
+        Ok(())
+    }
+
+    /// Returns the entries matching the query, newest first.
+    pub fn query(&self, query: &AuditQuery) -> Result<Vec<AuditEntry>, Error> {
+        let limit = query.limit.unwrap_or(DEFAULT_QUERY_LIMIT);
+        let mut entries = Vec::new();
+        for value in self.entries.iter().values().rev() {
+            if entries.len() >= limit {
+                break;
+            }
+            let entry: AuditEntry = serde_json::from_slice(&value?)?;
+            if query.since.map_or(false, |t| entry.timestamp < t) {
+                break;
+            }
+            if query.matches(&entry) {
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+}
+
+/// Middleware which records an audit entry for registrations, deletions and executions. The log
+/// is set as a request extension, to be queried by the handler.
+pub struct Audit(pub Arc<AuditLog>);
+
+#[async_trait]
+impl<S> Middleware<Arc<S>> for Audit
+where
+    S: WasmStore + Send + Sync + 'static,
+{
+    async fn handle(&self, mut req: Request<Arc<S>>, next: Next<'_, Arc<S>>) -> tide::Result {
+        req.set_ext(self.0.clone());
+        let path = req.url().path().to_owned();
+        let route = match (req.method(), path.as_str()) {
+            (Method::Post, "/") | (Method::Post, "/register") | (Method::Post, "/execute") => {
+                path.clone()
+            }
+            (Method::Delete, p) if p.starts_with("/modules/") => "/modules/:name".to_owned(),
+            _ => return Ok(next.run(req).await),
+        };
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+        let start = Instant::now();
+        let client = client_id(&req);
+
+        // The body is read here to record the request details, and set again for the handler.
+        let body = req.body_bytes().await?;
+        let request: Value = serde_json::from_slice(&body).unwrap_or_default();
+        req.set_body(body);
+        let field = |name: &str| request.get(name).and_then(Value::as_str).map(str::to_owned);
+
+        let reference = match path.strip_prefix("/modules/") {
+            Some(name) => Some(name.to_owned()),
+            None => field("module_name"),
+        };
+        // Modules are recorded by store key, resolved in the namespace of the API key like the
+        // handlers do. References the key may not use are recorded as sent.
+        let key = reference
+            .as_deref()
+            .and_then(|reference| module_key(&req, reference).ok());
+        let module = key.clone().or(reference);
+        let mut module_hash = field("wasm_hex")
+            .and_then(|code| hex::decode(code).ok())
+            .map(|code| hex::encode(Sha256::digest(&code)));
+        // Executed and deleted modules are hashed from the stored code.
+        if route == "/execute" || route == "/modules/:name" {
+            module_hash = match &key {
+                Some(key) => req.state().load_module(key).await.ok(),
+                None => None,
+            }
+            .map(|m| hex::encode(Sha256::digest(&m.code)));
+        }
+        let params_digest = request
+            .get("params")
+            .map(|params| hex::encode(Sha256::digest(params.to_string().as_bytes())));
+
+        let res = next.run(req).await;
+
+        let entry = AuditEntry {
+            timestamp,
+            client,
+            route,
+            module,
+            module_hash,
+            function: field("function_name"),
+            params_digest,
+            status: res.status().into(),
+            error: res.error().map(|e| e.to_string()),
+            duration_ms: start.elapsed().as_millis() as u64,
+        };
+        if let Err(e) = self.0.append(&entry) {
+            log::error!("Failed to append audit entry: {}", e);
+        }
+        Ok(res)
+    }
+}
@@ -0,0 +1,317 @@
+use crate::utils::namespace::validate_namespace;
+use anyhow::{anyhow, Error};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_cbor::{from_slice, to_vec};
+use sha2::{Digest, Sha256};
+use sled::{Db, Tree};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tide::http::Method;
+use tide::utils::async_trait;
+use tide::{Middleware, Next, Request, Response, StatusCode};
+
+/// Name of the sled tree for API keys, keyed by the hash of the key.
+const KEYS_TREE: &str = "api_keys";
+/// Name of the sled tree for the owning key id of each module.
+const OWNERS_TREE: &str = "module_owners";
+/// Prefix of generated API keys, to make them recognizable.
+const KEY_PREFIX: &str = "wea_";
+
+/// Permission granted to an API key.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Scope {
+    /// Execute code sent with the request, through `/`.
+    ExecuteAdhoc,
+    /// Execute registered modules, through `/execute`.
+    ExecuteRegistered,
+    /// Register modules and manage the modules owned by the key.
+    Register,
+    /// All permissions, including managing modules owned by other keys.
+    Admin,
+}
+
+impl FromStr for Scope {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "execute-adhoc" => Ok(Scope::ExecuteAdhoc),
+            "execute-registered" => Ok(Scope::ExecuteRegistered),
+            "register" => Ok(Scope::Register),
+            "admin" => Ok(Scope::Admin),
+            _ => Err(anyhow!(
+                "Invalid scope {}, expected one of execute-adhoc, execute-registered, register \
+                 or admin",
+                s
+            )),
+        }
+    }
+}
+
+/// Stored details of an API key. The key itself is only stored as a hash.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ApiKey {
+    /// Public identifier of the key, used to revoke it and to record module ownership.
+    pub id: String,
+    /// Name to describe what the key is used for.
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    /// Unix timestamp in seconds of when the key was created.
+    pub created: u64,
+    /// Namespace the key is limited to, if any.
+    #[serde(default)]
+    pub namespace: Option<String>,
+}
+
+impl ApiKey {
+    /// Returns true if the key has the scope, or is an admin key.
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope) || self.scopes.contains(&Scope::Admin)
+    }
+}
+
+/// API keys and module ownership, stored in sled trees.
+pub struct ApiKeys {
+    keys: Tree,
+    owners: Tree,
+}
+
+impl ApiKeys {
+    pub fn new(db: &Db) -> Result<Self, Error> {
+        Ok(Self {
+            keys: db.open_tree(KEYS_TREE)?,
+            owners: db.open_tree(OWNERS_TREE)?,
+        })
+    }
+
+    /// Creates a new API key with the scopes, limited to the namespace if given. Returns the
+    /// secret key, which can't be retrieved again, along with the stored details.
+    pub fn create(
+        &self,
+        name: &str,
+        scopes: Vec<Scope>,
+        namespace: Option<&str>,
+    ) -> Result<(String, ApiKey), Error> {
+        if let Some(namespace) = namespace {
+            validate_namespace(namespace)?;
+        }
+        let mut secret = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut secret);
+        let token = format!("{}{}", KEY_PREFIX, hex::encode(secret));
+
+        let hash = Sha256::digest(token.as_bytes());
+        let key = ApiKey {
+            id: hex::encode(&hash[..8]),
+            name: name.to_owned(),
+            scopes,
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            namespace: namespace.map(str::to_owned),
+        };
+        self.keys.insert(hash.as_slice(), to_vec(&key)?)?;
+        Ok((token, key))
+    }
+
+    /// Returns the details of the key, if it exists.
+    pub fn authenticate(&self, token: &str) -> Result<Option<ApiKey>, Error> {
+        let hash = Sha256::digest(token.as_bytes());
+        match self.keys.get(hash.as_slice())? {
+            Some(bytes) => Ok(Some(from_slice(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Lists the details of all keys.
+    pub fn list(&self) -> Result<Vec<ApiKey>, Error> {
+        self.keys
+            .iter()
+            .values()
+            .map(|v| Ok(from_slice(&v?)?))
+            .collect()
+    }
+
+    /// Revokes the key with the id. Returns false if no key has the id.
+    pub fn revoke(&self, id: &str) -> Result<bool, Error> {
+        for entry in self.keys.iter() {
+            let (hash, value) = entry?;
+            let key: ApiKey = from_slice(&value)?;
+            if key.id == id {
+                self.keys.remove(hash)?;
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Records the key id as the owner of the module.
+    pub fn set_owner(&self, module: &str, key_id: &str) -> Result<(), Error> {
+        self.owners.insert(module, key_id)?;
+        Ok(())
+    }
+
+    /// Returns the id of the key which owns the module, if any.
+    pub fn owner(&self, module: &str) -> Result<Option<String>, Error> {
+        Ok(self
+            .owners
+            .get(module)?
+            .map(|id| String::from_utf8_lossy(&id).into_owned()))
+    }
+
+    /// Lists the modules with an owner, with the id of the owning key.
+    pub fn owners(&self) -> Result<Vec<(String, String)>, Error> {
+        self.owners
+            .iter()
+            .map(|entry| {
+                let (module, id) = entry?;
+                Ok((
+                    String::from_utf8_lossy(&module).into_owned(),
+                    String::from_utf8_lossy(&id).into_owned(),
+                ))
+            })
+            .collect()
+    }
+
+    /// Returns the names of the modules owned by the key.
+    pub fn owned_modules(&self, key_id: &str) -> Result<Vec<String>, Error> {
+        Ok(self
+            .owners()?
+            .into_iter()
+            .filter(|(_, id)| id == key_id)
+            .map(|(module, _)| module)
+            .collect())
+    }
+
+    /// Removes the owner of a module.
+    pub fn remove_owner(&self, module: &str) -> Result<(), Error> {
+        self.owners.remove(module)?;
+        Ok(())
+    }
+
+    /// Returns an error if the key is not allowed to manage the module. Admin keys can manage all
+    /// modules, otherwise the key must own it.
+    pub fn check_owner(&self, module: &str, key: &ApiKey) -> Result<(), Error> {
+        if key.allows(Scope::Admin) || self.owner(module)?.as_ref() == Some(&key.id) {
+            Ok(())
+        } else {
+            Err(anyhow!("Module {} is not owned by key {}", module, key.id))
+        }
+    }
+}
+
+/// Authenticated key of a request, set by the [`Authenticate`] middleware.
+pub struct Authenticated {
+    pub keys: Arc<ApiKeys>,
+    pub key: ApiKey,
+}
+
+/// Middleware which checks the API key of requests, from the `Authorization: Bearer` or
+/// `X-Api-Key` header, has the scope needed for the route.
+pub struct Authenticate(pub Arc<ApiKeys>);
+
+#[async_trait]
+impl<State> Middleware<State> for Authenticate
+where
+    State: Clone + Send + Sync + 'static,
+{
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let scope = match required_scope(req.method(), req.url().path()) {
+            Some(scope) => scope,
+            None => return Ok(next.run(req).await),
+        };
+
+        let token = req
+            .header("Authorization")
+            .and_then(|v| v.as_str().strip_prefix("Bearer "))
+            .or_else(|| req.header("X-Api-Key").map(|v| v.as_str()))
+            .map(str::to_owned);
+        let key = match token {
+            Some(token) => self.0.authenticate(&token)?,
+            None => None,
+        };
+        let key = match key {
+            Some(key) => key,
+            None => {
+                return Ok(Response::builder(StatusCode::Unauthorized)
+                    .body("Missing or invalid API key")
+                    .build())
+            }
+        };
+
+        if !key.allows(scope) {
+            return Ok(Response::builder(StatusCode::Forbidden)
+                .body(format!(
+                    "API key {} does not have the {:?} scope",
+                    key.id, scope
+                ))
+                .build());
+        }
+
+        req.set_ext(Authenticated {
+            keys: self.0.clone(),
+            key,
+        });
+        Ok(next.run(req).await)
+    }
+}
+
+/// Middleware used when authentication is disabled, which only lets local clients delete modules
+/// and run sweeps.
+/// Requests are local if they come from a loopback address or a Unix socket, or are handled in
+/// process, without a peer address.
+pub struct LocalOnly;
+
+#[async_trait]
+impl<State> Middleware<State> for LocalOnly
+where
+    State: Clone + Send + Sync + 'static,
+{
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        if local_only(req.method(), req.url().path()) && !is_local(req.peer_addr()) {
+            return Ok(Response::builder(StatusCode::Forbidden)
+                .body("Only local clients can use this route without --auth")
+                .build());
+        }
+        Ok(next.run(req).await)
+    }
+}
+
+/// Returns true for the routes which only local clients can use without authentication.
+fn local_only(method: Method, path: &str) -> bool {
+    match (method, path) {
+        (Method::Delete, p) => p.starts_with("/modules/"),
+        (Method::Post, "/admin/gc") => true,
+        _ => false,
+    }
+}
+
+fn is_local(peer_addr: Option<&str>) -> bool {
+    match peer_addr {
+        None => true,
+        Some(addr) if addr.starts_with("http+unix://") => true,
+        Some(addr) => addr
+            .parse::<SocketAddr>()
+            .map_or(false, |addr| addr.ip().is_loopback()),
+    }
+}
+
+/// Scope needed to access the route. Returns `None` for routes which don't need a key.
+fn required_scope(method: Method, path: &str) -> Option<Scope> {
+    match (method, path) {
+        // Health checks are used by orchestrators, which don't have keys.
+        (Method::Get, "/healthz") | (Method::Get, "/readyz") => None,
+        (Method::Get, "/openapi.json") => None,
+        (Method::Post, "/") => Some(Scope::ExecuteAdhoc),
+        (Method::Post, "/execute") => Some(Scope::ExecuteRegistered),
+        (Method::Post, "/register") => Some(Scope::Register),
+        (Method::Get, "/modules") => Some(Scope::ExecuteRegistered),
+        (Method::Get, p) if p.starts_with("/modules/") => Some(Scope::ExecuteRegistered),
+        // Ownership of the module is checked by the handler.
+        (Method::Delete, p) if p.starts_with("/modules/") => Some(Scope::Register),
+        _ => Some(Scope::Admin),
+    }
+}
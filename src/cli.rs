@@ -0,0 +1,239 @@
+use crate::client::Client;
+use crate::config::Command;
+use crate::server::modules::ModuleQuery;
+use crate::server::{execute, index, register, ExecutionResponse};
+use crate::utils::wasm::Params;
+use crate::utils::ModuleMetadata;
+use anyhow::{anyhow, Error};
+use serde_json::Number;
+use std::collections::HashMap;
+
+/// Reads a wasm file, or compiles a wat file.
+fn read_module(path: &str) -> Result<Vec<u8>, Error> {
+    wat::parse_file(path).map_err(|e| anyhow!("Could not read {}: {}", path, e))
+}
+
+/// Parses the arguments as positional numbers, or as named params if given as `name=number`.
+fn parse_args(args: &[String]) -> Result<Params, Error> {
+    let number = |s: &str| {
+        serde_json::from_str::<Number>(s).map_err(|_| anyhow!("Invalid number argument {}", s))
+    };
+    if !args.iter().any(|a| a.contains('=')) {
+        return Ok(Params::Positional(
+            args.iter().map(|a| number(a)).collect::<Result<_, _>>()?,
+        ));
+    }
+    let named = args
+        .iter()
+        .map(|a| {
+            let (name, value) = split_pair(a, '=')
+                .ok_or_else(|| anyhow!("Can't mix positional and named arguments"))?;
+            Ok((name.to_owned(), number(value)?))
+        })
+        .collect::<Result<HashMap<_, _>, Error>>()?;
+    Ok(Params::Named(named))
+}
+
+/// Splits the string at the first separator.
+fn split_pair(s: &str, separator: char) -> Option<(&str, &str)> {
+    let mut parts = s.splitn(2, separator);
+    Some((parts.next()?, parts.next()?))
+}
+
+/// Prints the result values, along with the guest logs and trace if any.
+fn print_execution(res: &ExecutionResponse) -> Result<(), Error> {
+    if res.logs.is_empty() && res.trace.is_none() {
+        println!("{}", serde_json::to_string(&res.result)?);
+    } else {
+        println!("{}", serde_json::to_string_pretty(res)?);
+    }
+    Ok(())
+}
+
+/// Runs a module subcommand against the server of the client.
+pub async fn run_module_command(client: &Client, command: Command) -> Result<(), Error> {
+    match command {
+        Command::Keys(_) => return Err(anyhow!("API keys are managed in the data directory")),
+        Command::Namespaces(_) => {
+            return Err(anyhow!("Namespaces are managed in the data directory"))
+        }
+        #[cfg(not(feature = "p2p"))]
+        Command::Store(_) => return Err(anyhow!("The store is managed in the data directory")),
+        Command::Register(cmd) => {
+            let code = hex::encode(read_module(&cmd.file)?);
+            let functions = cmd
+                .function_doc
+                .iter()
+                .map(|doc| {
+                    let (name, description) = split_pair(doc, '=')
+                        .ok_or_else(|| anyhow!("Function doc {} is not name=description", doc))?;
+                    Ok((name.to_owned(), description.to_owned()))
+                })
+                .collect::<Result<_, Error>>()?;
+            let res = client
+                .register(&register::Request {
+                    module_name: cmd.name.into(),
+                    wasm_hex: code.into(),
+                    host_modules: cmd.host_module.into_iter().map(Into::into).collect(),
+                    param_names: HashMap::new(),
+                    metadata: ModuleMetadata {
+                        description: cmd.description,
+                        owner: cmd.owner,
+                        tags: cmd.tag,
+                        license: cmd.license,
+                        source_url: cmd.source_url,
+                        functions,
+                    },
+                    ttl: cmd.ttl,
+                })
+                .await?;
+            println!("{}", res);
+        }
+        Command::Execute(cmd) => {
+            let res = client
+                .execute(&execute::Request {
+                    module_name: cmd.module.into(),
+                    function_name: cmd.function.into(),
+                    params: parse_args(&cmd.args)?,
+                    host_functions: cmd.host_function,
+                    random_seed: cmd.random_seed,
+                    kv_transactional: false,
+                    trace: cmd.trace,
+                })
+                .await?;
+            print_execution(&res)?;
+        }
+        Command::Run(cmd) => {
+            let params = match parse_args(&cmd.args)? {
+                Params::Positional(params) => params,
+                Params::Named(_) => {
+                    return Err(anyhow!(
+                        "Named arguments are only supported for registered modules"
+                    ))
+                }
+            };
+            let code = hex::encode(read_module(&cmd.file)?);
+            let res = client
+                .run(&index::Request {
+                    wasm_hex: code.into(),
+                    function_name: cmd.function.into(),
+                    params,
+                    host_modules: cmd.host_module.into_iter().map(Into::into).collect(),
+                    host_functions: cmd.host_function,
+                    random_seed: cmd.random_seed,
+                    kv_transactional: false,
+                    trace: cmd.trace,
+                })
+                .await?;
+            print_execution(&res)?;
+        }
+        Command::List(cmd) => {
+            let query = ModuleQuery {
+                tag: cmd.tag,
+                namespace: cmd.namespace,
+            };
+            for module in client.list_modules(&query).await? {
+                match module.metadata.description {
+                    Some(description) => println!("{}\t{}", module.name, description),
+                    None => println!("{}", module.name),
+                }
+            }
+        }
+        Command::Inspect(cmd) => {
+            let info = client.inspect_module(&cmd.module).await?;
+            println!("{}", serde_json::to_string_pretty(&info)?);
+        }
+        Command::Delete(cmd) => {
+            println!("{}", client.delete_module(&cmd.module).await?);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{DeleteCommand, ExecuteCommand, ListCommand, RegisterCommand};
+    use crate::server::{app, Options};
+    use crate::utils::{Blocking, WasmStore};
+    use std::sync::Arc;
+    use wasm_exec_api::local_db::LocalDB;
+
+    #[test]
+    fn args() {
+        let args = |a: &[&str]| parse_args(&a.iter().map(|s| s.to_string()).collect::<Vec<_>>());
+        assert_eq!(
+            args(&["2", "1.5"]).unwrap(),
+            Params::Positional(vec![2.into(), serde_json::from_str("1.5").unwrap()])
+        );
+        let mut named = HashMap::new();
+        named.insert("value".to_owned(), 2.into());
+        assert_eq!(args(&["value=2"]).unwrap(), Params::Named(named));
+        assert!(args(&["value=2", "3"]).is_err());
+        assert!(args(&["x"]).is_err());
+    }
+
+    #[async_std::test]
+    async fn local_commands() {
+        let db = Arc::new(Blocking::new(LocalDB(
+            sled::Config::new().temporary(true).open().unwrap(),
+        )));
+        let client = Client::in_process(app(db.clone(), Options::default()));
+
+        run_module_command(
+            &client,
+            Command::Register(RegisterCommand {
+                file: "utils.wat".to_owned(),
+                name: "utils".to_owned(),
+                host_module: Vec::new(),
+                description: Some("Math helpers".to_owned()),
+                owner: None,
+                tag: vec!["math".to_owned()],
+                license: None,
+                source_url: None,
+                function_doc: vec!["double=Doubles the value".to_owned()],
+                ttl: Some(3600),
+            }),
+        )
+        .await
+        .unwrap();
+        let module = db.load_module("utils").await.unwrap();
+        assert!(module.expires.is_some());
+        let metadata = module.metadata;
+        assert_eq!(metadata.tags, ["math"]);
+        assert_eq!(metadata.functions["double"], "Doubles the value");
+
+        run_module_command(
+            &client,
+            Command::Execute(ExecuteCommand {
+                module: "utils".to_owned(),
+                function: "double".to_owned(),
+                args: vec!["2".to_owned()],
+                host_function: Vec::new(),
+                random_seed: None,
+                trace: false,
+            }),
+        )
+        .await
+        .unwrap();
+        run_module_command(
+            &client,
+            Command::List(ListCommand {
+                tag: Some("math".to_owned()),
+                namespace: None,
+            }),
+        )
+        .await
+        .unwrap();
+
+        run_module_command(
+            &client,
+            Command::Delete(DeleteCommand {
+                module: "utils".to_owned(),
+            }),
+        )
+        .await
+        .unwrap();
+        assert!(!db.contains_module("utils").await.unwrap());
+    }
+}
@@ -0,0 +1,321 @@
+use crate::audit::{AuditEntry, AuditQuery};
+use crate::server::admin::UsageReport;
+use crate::server::health::Readiness;
+use crate::server::modules::{ModuleInfo, ModuleQuery, ModuleSummary};
+use crate::server::{execute, index, register, ExecutionResponse};
+use crate::utils::expiry::SweepReport;
+use anyhow::{anyhow, Error};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use surf::http::Method;
+use surf::{Body, RequestBuilder, Response, Url};
+
+/// Client of the API, with a typed method for each endpoint.
+#[derive(Clone)]
+pub struct Client {
+    base: Url,
+    api_key: Option<String>,
+    http: surf::Client,
+}
+
+impl Client {
+    /// Creates a client of the server at the base URL, like `http://localhost:4000`.
+    pub fn new(base_url: &str) -> Result<Self, Error> {
+        let mut base = Url::parse(base_url)?;
+        // Routes are joined to the base, which keeps the path only if it ends with a slash.
+        if !base.path().ends_with('/') {
+            base.set_path(&format!("{}/", base.path()));
+        }
+        Ok(Self {
+            base,
+            api_key: None,
+            http: surf::Client::new(),
+        })
+    }
+
+    /// Creates a client which handles requests with the server in the same process.
+    pub fn in_process<State>(app: tide::Server<State>) -> Self
+    where
+        State: Clone + Send + Sync + Unpin + 'static,
+    {
+        Self {
+            base: Url::parse("http://localhost/").unwrap(),
+            api_key: None,
+            http: surf::Client::with_http_client(app),
+        }
+    }
+
+    /// Sends the API key with every request, for servers with authentication enabled.
+    pub fn with_api_key(mut self, key: impl Into<String>) -> Self {
+        self.api_key = Some(key.into());
+        self
+    }
+
+    /// Executes a function of a module given in the request.
+    pub async fn run(&self, req: &index::Request<'_>) -> Result<ExecutionResponse, Error> {
+        let res = self.send(self.json(Method::Post, "", req)?).await?;
+        execution_response(res).await
+    }
+
+    /// Registers a module, returning the message of the server.
+    pub async fn register(&self, req: &register::Request<'_>) -> Result<String, Error> {
+        let res = self.send(self.json(Method::Post, "register", req)?).await?;
+        text(res).await
+    }
+
+    /// Executes a function of a registered module.
+    pub async fn execute(&self, req: &execute::Request<'_>) -> Result<ExecutionResponse, Error> {
+        let res = self.send(self.json(Method::Post, "execute", req)?).await?;
+        execution_response(res).await
+    }
+
+    /// Registered modules matching the query, sorted by name.
+    pub async fn list_modules(&self, query: &ModuleQuery) -> Result<Vec<ModuleSummary>, Error> {
+        let req = self
+            .request(Method::Get, "modules")?
+            .query(query)
+            .map_err(|e| anyhow!(e))?;
+        json(self.send(req).await?).await
+    }
+
+    /// Details of a registered module.
+    pub async fn inspect_module(&self, name: &str) -> Result<ModuleInfo, Error> {
+        let path = format!("modules/{}", name);
+        json(self.send(self.request(Method::Get, &path)?).await?).await
+    }
+
+    /// Deletes a registered module, returning the message of the server.
+    pub async fn delete_module(&self, name: &str) -> Result<String, Error> {
+        let path = format!("modules/{}", name);
+        let res = self.send(self.request(Method::Delete, &path)?).await?;
+        text(res).await
+    }
+
+    /// Metrics in the Prometheus text format.
+    pub async fn metrics(&self) -> Result<String, Error> {
+        let res = self.send(self.request(Method::Get, "metrics")?).await?;
+        text(res).await
+    }
+
+    /// Audit entries matching the query, newest first.
+    pub async fn audit(&self, query: &AuditQuery) -> Result<Vec<AuditEntry>, Error> {
+        let req = self
+            .request(Method::Get, "audit")?
+            .query(query)
+            .map_err(|e| anyhow!(e))?;
+        json(self.send(req).await?).await
+    }
+
+    /// Usage of the store, along with its limits.
+    pub async fn usage(&self) -> Result<UsageReport, Error> {
+        json(self.send(self.request(Method::Get, "admin/usage")?).await?).await
+    }
+
+    /// Removes the expired modules no live module depends on.
+    pub async fn gc(&self) -> Result<SweepReport, Error> {
+        json(self.send(self.request(Method::Post, "admin/gc")?).await?).await
+    }
+
+    /// Succeeds while the server process is up.
+    pub async fn healthz(&self) -> Result<(), Error> {
+        self.send(self.request(Method::Get, "healthz")?).await?;
+        Ok(())
+    }
+
+    /// Readiness checks of the store. Failed checks are returned, not an error.
+    pub async fn readyz(&self) -> Result<Readiness, Error> {
+        let req = self.request(Method::Get, "readyz")?;
+        let res = self.http.send(req).await.map_err(|e| anyhow!(e))?;
+        json(res).await
+    }
+
+    /// OpenAPI document of the server.
+    pub async fn openapi(&self) -> Result<Value, Error> {
+        json(
+            self.send(self.request(Method::Get, "openapi.json")?)
+                .await?,
+        )
+        .await
+    }
+
+    fn request(&self, method: Method, path: &str) -> Result<RequestBuilder, Error> {
+        let mut req = RequestBuilder::new(method, self.base.join(path)?);
+        if let Some(key) = &self.api_key {
+            req = req.header("Authorization", format!("Bearer {}", key));
+        }
+        Ok(req)
+    }
+
+    fn json<T: Serialize>(
+        &self,
+        method: Method,
+        path: &str,
+        body: &T,
+    ) -> Result<RequestBuilder, Error> {
+        let body = Body::from_json(body).map_err(|e| anyhow!(e))?;
+        Ok(self.request(method, path)?.body(body))
+    }
+
+    /// Sends the request, with the body of error responses as the error.
+    async fn send(&self, req: RequestBuilder) -> Result<Response, Error> {
+        let mut res = self.http.send(req).await.map_err(|e| anyhow!(e))?;
+        if !res.status().is_success() {
+            let body = res.body_string().await.unwrap_or_default();
+            return Err(anyhow!("{}: {}", res.status(), body));
+        }
+        Ok(res)
+    }
+}
+
+async fn text(mut res: Response) -> Result<String, Error> {
+    res.body_string().await.map_err(|e| anyhow!(e))
+}
+
+async fn json<T: DeserializeOwned>(mut res: Response) -> Result<T, Error> {
+    res.body_json().await.map_err(|e| anyhow!(e))
+}
+
+/// Parses an execution response, which is only the result values if host namespaces and tracing
+/// are disabled.
+async fn execution_response(res: Response) -> Result<ExecutionResponse, Error> {
+    // Parsed through a value, untagged enums don't support arbitrary precision numbers.
+    let value: Value = json(res).await?;
+    if value.is_array() {
+        Ok(ExecutionResponse {
+            result: serde_json::from_value(value)?,
+            logs: Vec::new(),
+            trace: None,
+        })
+    } else {
+        Ok(serde_json::from_value(value)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::AuditLog;
+    use crate::local_db::LocalDB;
+    use crate::server::{app, Options};
+    use crate::utils::host::HostNamespace;
+    use crate::utils::Blocking;
+    use crate::utils::ModuleMetadata;
+    use async_std::task;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use wasmer_runtime::Value as WasmValue;
+
+    #[async_std::test]
+    async fn all_endpoints() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let options = Options {
+            audit: Some(Arc::new(AuditLog::new(&db).unwrap())),
+            ..Options::default()
+        };
+        let app = app(Arc::new(Blocking::new(LocalDB(db))), options);
+        let port = portpicker::pick_unused_port().unwrap();
+        task::spawn(app.listen(format!("localhost:{}", port)));
+        task::sleep(Duration::from_millis(100)).await;
+
+        let client = Client::new(&format!("http://localhost:{}", port)).unwrap();
+        let utils = hex::encode(include_bytes!("../utils.wasm").as_ref());
+
+        let res = client
+            .run(&index::Request {
+                wasm_hex: utils.as_str().into(),
+                function_name: "double".into(),
+                params: vec![2i32.into()],
+                host_modules: Vec::new(),
+                host_functions: Vec::new(),
+                random_seed: None,
+                kv_transactional: false,
+                trace: false,
+            })
+            .await
+            .unwrap();
+        assert_eq!(res.result, [WasmValue::I32(4)]);
+
+        client
+            .register(&register::Request {
+                module_name: "utils".into(),
+                wasm_hex: utils.as_str().into(),
+                host_modules: Vec::new(),
+                param_names: Default::default(),
+                metadata: ModuleMetadata {
+                    tags: vec!["math".to_owned()],
+                    ..ModuleMetadata::default()
+                },
+                ttl: None,
+            })
+            .await
+            .unwrap();
+        let res = client
+            .execute(&execute::Request {
+                module_name: "utils".into(),
+                function_name: "double".into(),
+                params: vec![3i32.into()].into(),
+                host_functions: vec![HostNamespace::Env],
+                random_seed: None,
+                kv_transactional: false,
+                trace: true,
+            })
+            .await
+            .unwrap();
+        assert_eq!(res.result, [WasmValue::I32(6)]);
+        assert!(res.trace.is_some());
+
+        let tagged = |tag: &str| ModuleQuery {
+            tag: Some(tag.to_owned()),
+            ..ModuleQuery::default()
+        };
+        let modules = client.list_modules(&tagged("math")).await.unwrap();
+        assert_eq!(modules.len(), 1);
+        assert_eq!(modules[0].name, "utils");
+        assert!(client.list_modules(&tagged("io")).await.unwrap().is_empty());
+        let info = client.inspect_module("utils").await.unwrap();
+        assert_eq!(info.functions, ["double"]);
+        assert_eq!(info.metadata.tags, ["math"]);
+        assert_eq!(info.size_bytes, include_bytes!("../utils.wasm").len());
+        assert!(client.inspect_module("missing").await.is_err());
+
+        assert!(client
+            .register(&register::Request {
+                module_name: "tagged".into(),
+                wasm_hex: utils.as_str().into(),
+                host_modules: Vec::new(),
+                param_names: Default::default(),
+                metadata: ModuleMetadata {
+                    tags: vec!["two words".to_owned()],
+                    ..ModuleMetadata::default()
+                },
+                ttl: None,
+            })
+            .await
+            .is_err());
+        client.delete_module("utils").await.unwrap();
+        assert!(client.delete_module("utils").await.is_err());
+
+        let entries = client
+            .audit(&AuditQuery {
+                route: Some("/execute".to_owned()),
+                ..AuditQuery::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(entries.len(), 1);
+
+        let usage = client.usage().await.unwrap();
+        assert_eq!(usage.store.modules, 0);
+        assert_eq!(client.gc().await.unwrap(), SweepReport::default());
+
+        client.healthz().await.unwrap();
+        assert!(client.readyz().await.unwrap().ready);
+        assert!(client
+            .metrics()
+            .await
+            .unwrap()
+            .contains("wasm_exec_http_requests_total"));
+        assert_eq!(client.openapi().await.unwrap()["openapi"], "3.0.3");
+    }
+}
@@ -1,14 +1,112 @@
+use crate::audit::AuditLog;
+use crate::auth::ApiKeys;
+use crate::logger::LogFormat;
+use crate::namespaces::Namespaces;
+use crate::server::admin::DEFAULT_SWEEP_INTERVAL;
+use crate::server::limits::{Limits, DEFAULT_BURST, DEFAULT_MAX_QUEUED};
+use crate::server::listen::{Listen, TlsFiles};
+use crate::server::shutdown::DEFAULT_GRACE_PERIOD;
+use crate::server::Options;
+use crate::utils::host::HostNamespace;
+use crate::utils::limits::{StoreLimits, UsageCounters};
+use anyhow::{anyhow, Error};
 use argh::FromArgs;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Prefix of the environment variables which override the config file.
+const ENV_PREFIX: &str = "WASM_EXEC_API_";
+
+/// Backend storing the registered modules.
+#[cfg(not(feature = "p2p"))]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub enum StoreBackend {
+    /// The sled database in the data directory.
+    Sled,
+    /// A `.wasm` file and a JSON sidecar for each module, in the directory.
+    Dir(String),
+}
+
+#[cfg(not(feature = "p2p"))]
+impl Default for StoreBackend {
+    fn default() -> Self {
+        StoreBackend::Sled
+    }
+}
+
+#[cfg(not(feature = "p2p"))]
+impl FromStr for StoreBackend {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("dir:") {
+            _ if s == "sled" => Ok(StoreBackend::Sled),
+            Some(path) if !path.is_empty() => Ok(StoreBackend::Dir(path.to_owned())),
+            _ => Err(anyhow!("Invalid store {}, expected sled or dir:<path>", s)),
+        }
+    }
+}
+
+#[cfg(not(feature = "p2p"))]
+impl std::convert::TryFrom<String> for StoreBackend {
+    type Error = Error;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+#[cfg(not(feature = "p2p"))]
+impl From<StoreBackend> for String {
+    fn from(store: StoreBackend) -> Self {
+        match store {
+            StoreBackend::Sled => "sled".to_owned(),
+            StoreBackend::Dir(path) => format!("dir:{}", path),
+        }
+    }
+}
 
 #[derive(FromArgs)]
 /// Start a wasm execution server with specified config.
+///
+/// Settings are read from the config file, then overridden by WASM_EXEC_API_* environment
+/// variables, then by flags.
 pub(super) struct Config {
-    /// port to start the server on.
-    #[argh(option, default = "4000", short = 'p')]
-    pub port: u16,
+    /// TOML file to read settings from.
+    #[argh(option, short = 'c')]
+    pub config: Option<String>,
 
-    /// data directory for storing registered Wasm functions.
-    #[cfg(not(feature = "p2p"))]
+    /// print the effective settings as TOML and exit.
+    #[argh(switch)]
+    pub print_config: bool,
+
+    /// port to start the server on (default 4000).
+    #[argh(option, short = 'p')]
+    pub port: Option<u16>,
+
+    /// address to bind the port to (default localhost).
+    #[argh(option)]
+    pub host: Option<String>,
+
+    /// additional address to listen on, as host:port.
+    #[argh(option)]
+    pub listen: Vec<String>,
+
+    /// certificate file in PEM format, to serve TCP listeners over TLS.
+    #[argh(option)]
+    pub tls_cert: Option<String>,
+
+    /// private key file in PEM format for the TLS certificate.
+    #[argh(option)]
+    pub tls_key: Option<String>,
+
+    /// path of a Unix domain socket to listen on.
+    #[argh(option)]
+    pub unix_socket: Option<String>,
+
+    /// data directory for storing registered Wasm functions and API keys.
     #[argh(option, short = 'd')]
     pub data_directory: Option<String>,
 
@@ -16,4 +114,804 @@ pub(super) struct Config {
     #[cfg(not(feature = "p2p"))]
     #[argh(switch, short = 'm')]
     pub memory: bool,
+
+    /// backend storing the modules: sled, or dir:<path> for a directory of files (default sled).
+    #[cfg(not(feature = "p2p"))]
+    #[argh(option)]
+    pub store: Option<StoreBackend>,
+
+    /// if flag is set, requests need an API key with the scope for the route
+    #[argh(switch)]
+    pub auth: bool,
+
+    /// if flag is set, registrations, deletions and executions are recorded in an audit log
+    #[argh(switch)]
+    pub audit: bool,
+
+    /// requests per second allowed for each API key, or client IP without authentication.
+    #[argh(option)]
+    pub rate_limit: Option<f64>,
+
+    /// number of requests a client can make at once before being rate limited (default 10).
+    #[argh(option)]
+    pub rate_burst: Option<u32>,
+
+    /// maximum number of guest executions running at once.
+    #[argh(option)]
+    pub max_executions: Option<usize>,
+
+    /// maximum number of executions waiting for a free slot when at the execution limit
+    /// (default 64).
+    #[argh(option)]
+    pub max_queued: Option<usize>,
+
+    /// maximum size of the wasm code of a registered module in bytes.
+    #[argh(option)]
+    pub max_module_size: Option<u64>,
+
+    /// maximum number of host modules of a registered module.
+    #[argh(option)]
+    pub max_host_modules: Option<usize>,
+
+    /// maximum total size of the wasm code of all registered modules in bytes.
+    #[argh(option)]
+    pub max_store_size: Option<u64>,
+
+    /// maximum total size of the wasm code of the modules registered by each API key in bytes.
+    #[argh(option)]
+    pub max_owner_size: Option<u64>,
+
+    /// format of log records: text or json (default text).
+    #[argh(option)]
+    pub log_format: Option<LogFormat>,
+
+    /// seconds to wait for in-flight requests when shutting down (default 30).
+    #[argh(option)]
+    pub grace_period: Option<u64>,
+
+    /// seconds between sweeps of expired modules, or 0 to only sweep on request (default 60).
+    #[argh(option)]
+    pub sweep_interval: Option<u64>,
+
+    /// number of modules to cache in memory in front of the store.
+    #[argh(option)]
+    pub cache_size: Option<usize>,
+
+    /// directory of read-only modules, in the layout of the dir store, consulted before the store.
+    #[argh(option)]
+    pub overlay: Option<String>,
+
+    /// if flag is set, modules fetched from the DHT are cached in the data directory
+    #[cfg(feature = "p2p")]
+    #[argh(switch)]
+    pub dht_cache: bool,
+
+    /// number of peers needed for the node to be ready (default 1).
+    #[cfg(feature = "p2p")]
+    #[argh(option)]
+    pub min_peers: Option<usize>,
+
+    /// URL of a running server for the module subcommands, instead of the data directory.
+    #[argh(option)]
+    pub server: Option<String>,
+
+    /// API key for the module subcommands, if the server has authentication enabled. Also read
+    /// from WASM_EXEC_API_API_KEY.
+    #[argh(option)]
+    pub api_key: Option<String>,
+
+    #[argh(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Effective settings of the server, merged from the config file, environment variables and
+/// flags.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub(super) struct Settings {
+    pub port: u16,
+    pub host: String,
+    pub listen: Vec<String>,
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    pub unix_socket: Option<String>,
+    pub data_directory: Option<String>,
+    #[cfg(not(feature = "p2p"))]
+    pub memory: bool,
+    #[cfg(not(feature = "p2p"))]
+    pub store: StoreBackend,
+    pub auth: bool,
+    pub audit: bool,
+    pub rate_limit: Option<f64>,
+    pub rate_burst: u32,
+    pub max_executions: Option<usize>,
+    pub max_queued: usize,
+    pub max_module_size: Option<u64>,
+    pub max_host_modules: Option<usize>,
+    pub max_store_size: Option<u64>,
+    pub max_owner_size: Option<u64>,
+    /// Seconds to wait for in-flight requests when shutting down.
+    pub grace_period: u64,
+    /// Seconds between sweeps of expired modules, 0 to disable them.
+    pub sweep_interval: u64,
+    pub log_format: LogFormat,
+    /// Modules to cache in memory, 0 to disable the cache.
+    pub cache_size: usize,
+    pub overlay: Option<String>,
+    #[cfg(feature = "p2p")]
+    pub dht_cache: bool,
+    #[cfg(feature = "p2p")]
+    pub min_peers: usize,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            port: 4000,
+            host: "localhost".to_owned(),
+            listen: Vec::new(),
+            tls_cert: None,
+            tls_key: None,
+            unix_socket: None,
+            data_directory: None,
+            #[cfg(not(feature = "p2p"))]
+            memory: false,
+            #[cfg(not(feature = "p2p"))]
+            store: StoreBackend::default(),
+            auth: false,
+            audit: false,
+            rate_limit: None,
+            rate_burst: DEFAULT_BURST,
+            max_executions: None,
+            max_queued: DEFAULT_MAX_QUEUED,
+            max_module_size: None,
+            max_host_modules: None,
+            max_store_size: None,
+            max_owner_size: None,
+            grace_period: DEFAULT_GRACE_PERIOD,
+            sweep_interval: DEFAULT_SWEEP_INTERVAL,
+            log_format: LogFormat::default(),
+            cache_size: 0,
+            overlay: None,
+            #[cfg(feature = "p2p")]
+            dht_cache: false,
+            #[cfg(feature = "p2p")]
+            min_peers: 1,
+        }
+    }
+}
+
+impl Settings {
+    /// Loads the settings of the config file, if any, then applies the environment variables
+    /// and flags.
+    pub fn load(args: &Config) -> Result<Self, Error> {
+        let mut settings = match &args.config {
+            Some(path) => {
+                let contents = std::fs::read_to_string(path)
+                    .map_err(|e| anyhow!("Could not read config file {}: {}", path, e))?;
+                toml::from_str(&contents)
+                    .map_err(|e| anyhow!("Invalid config file {}: {}", path, e))?
+            }
+            None => Settings::default(),
+        };
+        settings.apply_env(|name| std::env::var(name).ok())?;
+        settings.apply_args(args);
+        Ok(settings)
+    }
+
+    /// Overrides the settings with the `WASM_EXEC_API_*` variables. Lists are comma separated.
+    fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<(), Error> {
+        let env = |name: &str| var(&format!("{}{}", ENV_PREFIX, name));
+        fn parse<T>(name: &str, value: String) -> Result<T, Error>
+        where
+            T: FromStr,
+            T::Err: Display,
+        {
+            value
+                .parse()
+                .map_err(|e| anyhow!("Invalid value for {}{}: {}", ENV_PREFIX, name, e))
+        }
+
+        if let Some(v) = env("PORT") {
+            self.port = parse("PORT", v)?;
+        }
+        if let Some(v) = env("HOST") {
+            self.host = v;
+        }
+        if let Some(v) = env("LISTEN") {
+            self.listen = v
+                .split(',')
+                .filter(|a| !a.is_empty())
+                .map(str::to_owned)
+                .collect();
+        }
+        if let Some(v) = env("TLS_CERT") {
+            self.tls_cert = Some(v);
+        }
+        if let Some(v) = env("TLS_KEY") {
+            self.tls_key = Some(v);
+        }
+        if let Some(v) = env("UNIX_SOCKET") {
+            self.unix_socket = Some(v);
+        }
+        if let Some(v) = env("DATA_DIRECTORY") {
+            self.data_directory = Some(v);
+        }
+        #[cfg(not(feature = "p2p"))]
+        if let Some(v) = env("MEMORY") {
+            self.memory = parse("MEMORY", v)?;
+        }
+        #[cfg(not(feature = "p2p"))]
+        if let Some(v) = env("STORE") {
+            self.store = parse("STORE", v)?;
+        }
+        if let Some(v) = env("AUTH") {
+            self.auth = parse("AUTH", v)?;
+        }
+        if let Some(v) = env("AUDIT") {
+            self.audit = parse("AUDIT", v)?;
+        }
+        if let Some(v) = env("RATE_LIMIT") {
+            self.rate_limit = Some(parse("RATE_LIMIT", v)?);
+        }
+        if let Some(v) = env("RATE_BURST") {
+            self.rate_burst = parse("RATE_BURST", v)?;
+        }
+        if let Some(v) = env("MAX_EXECUTIONS") {
+            self.max_executions = Some(parse("MAX_EXECUTIONS", v)?);
+        }
+        if let Some(v) = env("MAX_QUEUED") {
+            self.max_queued = parse("MAX_QUEUED", v)?;
+        }
+        if let Some(v) = env("MAX_MODULE_SIZE") {
+            self.max_module_size = Some(parse("MAX_MODULE_SIZE", v)?);
+        }
+        if let Some(v) = env("MAX_HOST_MODULES") {
+            self.max_host_modules = Some(parse("MAX_HOST_MODULES", v)?);
+        }
+        if let Some(v) = env("MAX_STORE_SIZE") {
+            self.max_store_size = Some(parse("MAX_STORE_SIZE", v)?);
+        }
+        if let Some(v) = env("MAX_OWNER_SIZE") {
+            self.max_owner_size = Some(parse("MAX_OWNER_SIZE", v)?);
+        }
+        if let Some(v) = env("LOG_FORMAT") {
+            self.log_format = parse("LOG_FORMAT", v)?;
+        }
+        if let Some(v) = env("GRACE_PERIOD") {
+            self.grace_period = parse("GRACE_PERIOD", v)?;
+        }
+        if let Some(v) = env("SWEEP_INTERVAL") {
+            self.sweep_interval = parse("SWEEP_INTERVAL", v)?;
+        }
+        if let Some(v) = env("CACHE_SIZE") {
+            self.cache_size = parse("CACHE_SIZE", v)?;
+        }
+        if let Some(v) = env("OVERLAY") {
+            self.overlay = Some(v);
+        }
+        #[cfg(feature = "p2p")]
+        if let Some(v) = env("DHT_CACHE") {
+            self.dht_cache = parse("DHT_CACHE", v)?;
+        }
+        #[cfg(feature = "p2p")]
+        if let Some(v) = env("MIN_PEERS") {
+            self.min_peers = parse("MIN_PEERS", v)?;
+        }
+        Ok(())
+    }
+
+    /// Overrides the settings with the flags given. Switches can only enable a setting.
+    fn apply_args(&mut self, args: &Config) {
+        if let Some(port) = args.port {
+            self.port = port;
+        }
+        if let Some(host) = &args.host {
+            self.host = host.clone();
+        }
+        if !args.listen.is_empty() {
+            self.listen = args.listen.clone();
+        }
+        if args.tls_cert.is_some() {
+            self.tls_cert = args.tls_cert.clone();
+        }
+        if args.tls_key.is_some() {
+            self.tls_key = args.tls_key.clone();
+        }
+        if args.unix_socket.is_some() {
+            self.unix_socket = args.unix_socket.clone();
+        }
+        if args.data_directory.is_some() {
+            self.data_directory = args.data_directory.clone();
+        }
+        #[cfg(not(feature = "p2p"))]
+        {
+            self.memory |= args.memory;
+            if let Some(store) = &args.store {
+                self.store = store.clone();
+            }
+        }
+        self.auth |= args.auth;
+        self.audit |= args.audit;
+        if args.rate_limit.is_some() {
+            self.rate_limit = args.rate_limit;
+        }
+        if let Some(burst) = args.rate_burst {
+            self.rate_burst = burst;
+        }
+        if args.max_executions.is_some() {
+            self.max_executions = args.max_executions;
+        }
+        if let Some(max) = args.max_queued {
+            self.max_queued = max;
+        }
+        if args.max_module_size.is_some() {
+            self.max_module_size = args.max_module_size;
+        }
+        if args.max_host_modules.is_some() {
+            self.max_host_modules = args.max_host_modules;
+        }
+        if args.max_store_size.is_some() {
+            self.max_store_size = args.max_store_size;
+        }
+        if args.max_owner_size.is_some() {
+            self.max_owner_size = args.max_owner_size;
+        }
+        if let Some(format) = args.log_format {
+            self.log_format = format;
+        }
+        if let Some(grace_period) = args.grace_period {
+            self.grace_period = grace_period;
+        }
+        if let Some(interval) = args.sweep_interval {
+            self.sweep_interval = interval;
+        }
+        if let Some(size) = args.cache_size {
+            self.cache_size = size;
+        }
+        if args.overlay.is_some() {
+            self.overlay = args.overlay.clone();
+        }
+        #[cfg(feature = "p2p")]
+        {
+            self.dht_cache |= args.dht_cache;
+        }
+        #[cfg(feature = "p2p")]
+        if let Some(min) = args.min_peers {
+            self.min_peers = min;
+        }
+    }
+
+    /// URL of the server's first TCP address, used by the module subcommands.
+    pub fn url(&self) -> String {
+        let scheme = if self.tls_cert.is_some() {
+            "https"
+        } else {
+            "http"
+        };
+        format!("{}://{}:{}", scheme, self.host, self.port)
+    }
+
+    /// Addresses for the server to listen on.
+    pub fn listen(&self) -> Result<Listen, Error> {
+        let tls = match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => Some(TlsFiles {
+                cert: cert.clone(),
+                key: key.clone(),
+            }),
+            (None, None) => None,
+            _ => return Err(anyhow!("Both tls_cert and tls_key are needed for TLS")),
+        };
+        let mut addrs = vec![format!("{}:{}", self.host, self.port)];
+        addrs.extend(self.listen.iter().cloned());
+        Ok(Listen {
+            addrs,
+            tls,
+            unix_socket: self.unix_socket.clone(),
+        })
+    }
+
+    /// Options of the server, with the API keys and audit log if enabled.
+    pub fn server_options(
+        &self,
+        auth: Option<Arc<ApiKeys>>,
+        audit: Option<Arc<AuditLog>>,
+        namespaces: Option<Arc<Namespaces>>,
+        usage: Option<Arc<UsageCounters>>,
+    ) -> Options {
+        Options {
+            auth,
+            audit,
+            namespaces,
+            usage,
+            limits: Limits {
+                requests_per_second: self.rate_limit,
+                burst: self.rate_burst,
+                max_executions: self.max_executions,
+                max_queued: self.max_queued,
+                store: StoreLimits {
+                    max_module_size: self.max_module_size,
+                    max_host_modules: self.max_host_modules,
+                    max_store_size: self.max_store_size,
+                    max_owner_size: self.max_owner_size,
+                },
+            },
+            grace_period: Duration::from_secs(self.grace_period),
+            sweep_interval: match self.sweep_interval {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+        }
+    }
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+pub(super) enum Command {
+    Keys(KeysCommand),
+    Namespaces(NamespacesCommand),
+    #[cfg(not(feature = "p2p"))]
+    Store(StoreCommand),
+    Register(RegisterCommand),
+    Execute(ExecuteCommand),
+    Run(RunCommand),
+    List(ListCommand),
+    Inspect(InspectCommand),
+    Delete(DeleteCommand),
+}
+
+#[derive(FromArgs)]
+/// Manage API keys.
+#[argh(subcommand, name = "keys")]
+pub(super) struct KeysCommand {
+    #[argh(subcommand)]
+    pub action: KeysAction,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+pub(super) enum KeysAction {
+    Create(CreateKey),
+    Revoke(RevokeKey),
+    List(ListKeys),
+}
+
+#[derive(FromArgs)]
+/// Create an API key, which is only printed once.
+#[argh(subcommand, name = "create")]
+pub(super) struct CreateKey {
+    /// name to describe what the key is used for.
+    #[argh(option)]
+    pub name: String,
+
+    /// scope granted to the key: execute-adhoc, execute-registered, register or admin.
+    #[argh(option)]
+    pub scope: Vec<String>,
+
+    /// namespace to limit the key to.
+    #[argh(option)]
+    pub namespace: Option<String>,
+}
+
+#[derive(FromArgs)]
+/// Revoke an API key by id.
+#[argh(subcommand, name = "revoke")]
+pub(super) struct RevokeKey {
+    /// id of the key to revoke.
+    #[argh(positional)]
+    pub id: String,
+}
+
+#[derive(FromArgs)]
+/// List API keys.
+#[argh(subcommand, name = "list")]
+pub(super) struct ListKeys {}
+
+#[derive(FromArgs)]
+/// Manage the quotas and import grants of namespaces.
+#[argh(subcommand, name = "namespaces")]
+pub(super) struct NamespacesCommand {
+    #[argh(subcommand)]
+    pub action: NamespacesAction,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+pub(super) enum NamespacesAction {
+    Quota(SetQuota),
+    Grant(GrantImports),
+    Revoke(RevokeImports),
+    List(ListNamespaces),
+}
+
+#[derive(FromArgs)]
+/// Set the quotas of a namespace. Quotas which aren't given are removed.
+#[argh(subcommand, name = "quota")]
+pub(super) struct SetQuota {
+    /// namespace to set the quotas of.
+    #[argh(positional)]
+    pub namespace: String,
+
+    /// maximum number of modules.
+    #[argh(option)]
+    pub max_modules: Option<usize>,
+
+    /// maximum total size of the wasm code in bytes.
+    #[argh(option)]
+    pub max_bytes: Option<u64>,
+}
+
+#[derive(FromArgs)]
+/// Allow the modules of another namespace to import the modules of a namespace.
+#[argh(subcommand, name = "grant")]
+pub(super) struct GrantImports {
+    /// namespace of the imported modules.
+    #[argh(positional)]
+    pub namespace: String,
+
+    /// namespace of the importing modules.
+    #[argh(option)]
+    pub to: String,
+}
+
+#[derive(FromArgs)]
+/// Remove an import grant. Modules already registered keep their imports.
+#[argh(subcommand, name = "revoke")]
+pub(super) struct RevokeImports {
+    /// namespace of the imported modules.
+    #[argh(positional)]
+    pub namespace: String,
+
+    /// namespace of the importing modules.
+    #[argh(option)]
+    pub to: String,
+}
+
+#[derive(FromArgs)]
+/// List the namespaces with quotas or grants.
+#[argh(subcommand, name = "list")]
+pub(super) struct ListNamespaces {}
+
+#[derive(FromArgs)]
+/// Export, import and verify the modules in the data directory, while the server isn't running.
+#[cfg(not(feature = "p2p"))]
+#[argh(subcommand, name = "store")]
+pub(super) struct StoreCommand {
+    #[argh(subcommand)]
+    pub action: StoreAction,
+}
+
+#[derive(FromArgs)]
+#[cfg(not(feature = "p2p"))]
+#[argh(subcommand)]
+pub(super) enum StoreAction {
+    Export(ExportStore),
+    Import(ImportStore),
+    Verify(VerifyStore),
+    Migrate(MigrateStore),
+}
+
+#[derive(FromArgs)]
+/// Export all modules to a gzipped tar archive.
+#[cfg(not(feature = "p2p"))]
+#[argh(subcommand, name = "export")]
+pub(super) struct ExportStore {
+    /// path of the archive to write.
+    #[argh(positional)]
+    pub file: String,
+}
+
+#[derive(FromArgs)]
+/// Import the modules of an archive, which must not exist in the store.
+#[cfg(not(feature = "p2p"))]
+#[argh(subcommand, name = "import")]
+pub(super) struct ImportStore {
+    /// path of the archive to read.
+    #[argh(positional)]
+    pub file: String,
+}
+
+#[derive(FromArgs)]
+/// Check that every module deserializes, compiles and has its host modules.
+#[cfg(not(feature = "p2p"))]
+#[argh(subcommand, name = "verify")]
+pub(super) struct VerifyStore {}
+
+#[derive(FromArgs)]
+/// Upgrade all modules stored by older versions to the current record format.
+#[cfg(not(feature = "p2p"))]
+#[argh(subcommand, name = "migrate")]
+pub(super) struct MigrateStore {
+    /// only list the modules which would be upgraded.
+    #[argh(switch)]
+    pub dry_run: bool,
+}
+
+#[derive(FromArgs)]
+/// Register a module from a wasm or wat file.
+#[argh(subcommand, name = "register")]
+pub(super) struct RegisterCommand {
+    /// wasm or wat file of the module.
+    #[argh(positional)]
+    pub file: String,
+
+    /// name to register the module as.
+    #[argh(option)]
+    pub name: String,
+
+    /// registered module to link as a host module.
+    #[argh(option)]
+    pub host_module: Vec<String>,
+
+    /// description of the module.
+    #[argh(option)]
+    pub description: Option<String>,
+
+    /// owner of the module.
+    #[argh(option)]
+    pub owner: Option<String>,
+
+    /// tag to search the module by.
+    #[argh(option)]
+    pub tag: Vec<String>,
+
+    /// license of the module.
+    #[argh(option)]
+    pub license: Option<String>,
+
+    /// URL of the source of the module.
+    #[argh(option)]
+    pub source_url: Option<String>,
+
+    /// description of an exported function, as name=description.
+    #[argh(option)]
+    pub function_doc: Vec<String>,
+
+    /// seconds after which the module expires.
+    #[argh(option)]
+    pub ttl: Option<u64>,
+}
+
+#[derive(FromArgs)]
+/// Execute a function of a registered module. Args are positional numbers, or name=number.
+#[argh(subcommand, name = "execute")]
+pub(super) struct ExecuteCommand {
+    /// name of the registered module.
+    #[argh(positional)]
+    pub module: String,
+
+    /// name of the exported function.
+    #[argh(positional)]
+    pub function: String,
+
+    /// arguments of the function.
+    #[argh(positional)]
+    pub args: Vec<String>,
+
+    /// native host namespace to make available: env, clock, random, kv or modules.
+    #[argh(option)]
+    pub host_function: Vec<HostNamespace>,
+
+    /// seed for the random host namespace.
+    #[argh(option)]
+    pub random_seed: Option<u64>,
+
+    /// print the timings of the execution.
+    #[argh(switch)]
+    pub trace: bool,
+}
+
+#[derive(FromArgs)]
+/// Execute a function of a wasm or wat file. Args are positional numbers, or name=number.
+#[argh(subcommand, name = "run")]
+pub(super) struct RunCommand {
+    /// wasm or wat file to execute.
+    #[argh(positional)]
+    pub file: String,
+
+    /// name of the exported function.
+    #[argh(positional)]
+    pub function: String,
+
+    /// arguments of the function.
+    #[argh(positional)]
+    pub args: Vec<String>,
+
+    /// registered module to link as a host module.
+    #[argh(option)]
+    pub host_module: Vec<String>,
+
+    /// native host namespace to make available: env, clock, random, kv or modules.
+    #[argh(option)]
+    pub host_function: Vec<HostNamespace>,
+
+    /// seed for the random host namespace.
+    #[argh(option)]
+    pub random_seed: Option<u64>,
+
+    /// print the timings of the execution.
+    #[argh(switch)]
+    pub trace: bool,
+}
+
+#[derive(FromArgs)]
+/// List registered modules.
+#[argh(subcommand, name = "list")]
+pub(super) struct ListCommand {
+    /// only list modules with this tag.
+    #[argh(option)]
+    pub tag: Option<String>,
+
+    /// only list modules of this namespace.
+    #[argh(option)]
+    pub namespace: Option<String>,
+}
+
+#[derive(FromArgs)]
+/// Show the details of a registered module.
+#[argh(subcommand, name = "inspect")]
+pub(super) struct InspectCommand {
+    /// name of the registered module.
+    #[argh(positional)]
+    pub module: String,
+}
+
+#[derive(FromArgs)]
+/// Delete a registered module.
+#[argh(subcommand, name = "delete")]
+pub(super) struct DeleteCommand {
+    /// name of the registered module.
+    #[argh(positional)]
+    pub module: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_precedence() {
+        let mut settings: Settings = toml::from_str(
+            r#"
+            port = 5000
+            host = "0.0.0.0"
+            auth = true
+            rate_burst = 20
+            "#,
+        )
+        .unwrap();
+        assert_eq!(settings.max_queued, DEFAULT_MAX_QUEUED);
+
+        settings
+            .apply_env(|name| match name {
+                "WASM_EXEC_API_PORT" => Some("6000".to_owned()),
+                "WASM_EXEC_API_AUTH" => Some("false".to_owned()),
+                "WASM_EXEC_API_LISTEN" => Some("127.0.0.1:1,127.0.0.1:2".to_owned()),
+                _ => None,
+            })
+            .unwrap();
+        let args: Config = Config::from_args(&["wasm-exec-api"], &["-p", "7000"]).unwrap();
+        settings.apply_args(&args);
+
+        assert_eq!(settings.port, 7000);
+        assert_eq!(settings.host, "0.0.0.0");
+        assert!(!settings.auth);
+        assert_eq!(settings.rate_burst, 20);
+        assert_eq!(
+            settings.listen().unwrap().addrs,
+            ["0.0.0.0:7000", "127.0.0.1:1", "127.0.0.1:2"]
+        );
+
+        assert!(Settings::default()
+            .apply_env(|_| Some("invalid".to_owned()))
+            .is_err());
+        assert!(toml::from_str::<Settings>("unknown = 1").is_err());
+
+        #[cfg(not(feature = "p2p"))]
+        {
+            let settings: Settings = toml::from_str(r#"store = "dir:modules""#).unwrap();
+            assert_eq!(settings.store, StoreBackend::Dir("modules".to_owned()));
+            assert!(toml::from_str::<Settings>(r#"store = "dir:""#).is_err());
+        }
+    }
 }
@@ -0,0 +1,266 @@
+use super::metrics::METRICS;
+use super::utils::namespace;
+use super::utils::*;
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Extension of the wasm code files.
+const CODE_EXTENSION: &str = "wasm";
+/// Extension of the sidecar files.
+const SIDECAR_EXTENSION: &str = "json";
+
+/// Number of temporary files created by the process, to give each a unique name.
+static TEMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// Everything stored for a module except its code, written next to the code file.
+#[derive(Serialize, Deserialize)]
+struct Sidecar {
+    #[serde(default)]
+    host_modules: Vec<String>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    param_names: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    metadata: ModuleMetadata,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires: Option<u64>,
+}
+
+/// Stores each module as a `.wasm` file of its code and a `.json` sidecar with the rest, in a
+/// directory. Modules of other namespaces than the default one are in a subdirectory of their
+/// namespace. A module exists once its sidecar does.
+pub struct DirStore {
+    root: PathBuf,
+}
+
+impl DirStore {
+    /// Opens the directory, creating it if it doesn't exist.
+    pub fn open(root: impl Into<PathBuf>) -> Result<Self, Error> {
+        let root = root.into();
+        fs::create_dir_all(&root)
+            .map_err(|e| anyhow!("Could not open store directory {:?}: {}", root, e))?;
+        Ok(Self { root })
+    }
+
+    /// Paths of the code and sidecar files of the module.
+    fn paths(&self, name: &str) -> Result<(PathBuf, PathBuf), Error> {
+        namespace::validate_reference(name)?;
+        let (dir, name) = match namespace::split(name) {
+            Some((namespace, name)) => (self.root.join(namespace), name),
+            None => (self.root.clone(), name),
+        };
+        Ok((
+            dir.join(format!("{}.{}", name, CODE_EXTENSION)),
+            dir.join(format!("{}.{}", name, SIDECAR_EXTENSION)),
+        ))
+    }
+}
+
+/// Writes the data to a new temporary file next to the path.
+fn write_temp(path: &Path, data: &[u8]) -> Result<PathBuf, Error> {
+    let temp = path.with_file_name(format!(
+        ".{}.{}.{}.tmp",
+        path.file_name().unwrap_or_default().to_string_lossy(),
+        std::process::id(),
+        TEMP_FILES.fetch_add(1, Ordering::SeqCst)
+    ));
+    fs::write(&temp, data)?;
+    Ok(temp)
+}
+
+/// Names of the modules with a sidecar in the directory, prefixed with the namespace if any.
+fn sidecar_names(dir: &Path, namespace: Option<&str>) -> Result<Vec<String>, Error> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !path.is_file() || path.extension().map_or(true, |e| e != SIDECAR_EXTENSION) {
+            continue;
+        }
+        if let Some(name) = path.file_stem().and_then(|s| s.to_str()) {
+            names.push(match namespace {
+                Some(namespace) => namespace::key(namespace, name),
+                None => name.to_owned(),
+            });
+        }
+    }
+    Ok(names)
+}
+
+impl SyncWasmStore for DirStore {
+    fn load_module(&self, name: &str) -> Result<WasmModule, Error> {
+        let (code_path, sidecar_path) = self.paths(name)?;
+        METRICS.time_store_op("load_module", || {
+            let sidecar = match fs::read(&sidecar_path) {
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    return Err(anyhow!("Could not find module {} in the directory", name))
+                }
+                res => res?,
+            };
+            let Sidecar {
+                host_modules,
+                param_names,
+                metadata,
+                expires,
+            } = serde_json::from_slice(&sidecar)
+                .map_err(|e| anyhow!("Invalid sidecar of module {}: {}", name, e))?;
+            Ok(WasmModule {
+                code: fs::read(&code_path)
+                    .map_err(|e| anyhow!("Could not read code of module {}: {}", name, e))?,
+                host_modules,
+                param_names,
+                metadata,
+                expires,
+            })
+        })
+    }
+    fn contains_module(&self, name: &str) -> Result<bool, Error> {
+        let (_, sidecar_path) = self.paths(name)?;
+        Ok(METRICS.time_store_op("contains_module", || sidecar_path.is_file()))
+    }
+    fn put_module(&self, name: &str, module: &WasmModuleRef<'_, '_>) -> Result<(), Error> {
+        let (code_path, sidecar_path) = self.paths(name)?;
+        let sidecar = serde_json::to_vec_pretty(&Sidecar {
+            host_modules: module.host_modules.iter().map(|m| m.to_string()).collect(),
+            param_names: module.param_names.clone(),
+            metadata: module.metadata.clone(),
+            expires: module.expires,
+        })?;
+        METRICS.time_store_op("put_module", || {
+            if let Some(dir) = code_path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            // The code is linked into place rather than renamed, as linking fails if the file
+            // exists, so that concurrent writes of a module can't replace each other.
+            let temp = write_temp(&code_path, module.code)?;
+            let linked = fs::hard_link(&temp, &code_path);
+            fs::remove_file(&temp)?;
+            match linked {
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                    return Err(anyhow!("Module {} already exists in the directory", name))
+                }
+                res => res?,
+            }
+            // Code without a sidecar isn't a module, and would block registering it again.
+            let written = write_temp(&sidecar_path, &sidecar).and_then(|temp| {
+                fs::rename(&temp, &sidecar_path).map_err(|e| {
+                    let _ = fs::remove_file(&temp);
+                    e.into()
+                })
+            });
+            if written.is_err() {
+                let _ = fs::remove_file(&code_path);
+            }
+            written
+        })
+    }
+    fn module_names(&self) -> Result<Vec<String>, Error> {
+        let mut names = sidecar_names(&self.root, None)?;
+        for entry in fs::read_dir(&self.root)? {
+            let path = entry?.path();
+            let namespace = path.file_name().and_then(|n| n.to_str());
+            if let Some(namespace) = namespace.filter(|_| path.is_dir()) {
+                if namespace::validate_namespace(namespace).is_ok() {
+                    names.extend(sidecar_names(&path, Some(namespace))?);
+                }
+            }
+        }
+        Ok(names)
+    }
+    fn remove_module(&self, name: &str) -> Result<(), Error> {
+        let (code_path, sidecar_path) = self.paths(name)?;
+        METRICS.time_store_op("remove_module", || {
+            // The module is gone once its sidecar is.
+            for path in &[sidecar_path, code_path] {
+                match fs::remove_file(path) {
+                    Err(e) if e.kind() == ErrorKind::NotFound => {}
+                    res => res?,
+                }
+            }
+            Ok(())
+        })
+    }
+    fn readiness_checks(&self) -> Vec<ReadinessCheck> {
+        let res = fs::read_dir(&self.root)
+            .map(|_| ())
+            .map_err(|e| anyhow!("Could not read {:?}: {}", self.root, e));
+        vec![ReadinessCheck::from_result("directory", res)]
+    }
+    fn stats(&self) -> Result<Option<StoreStats>, Error> {
+        let mut stats = StoreStats {
+            modules: 0,
+            size_bytes: 0,
+        };
+        for name in self.module_names()? {
+            let (code_path, sidecar_path) = self.paths(&name)?;
+            stats.modules += 1;
+            for path in &[code_path, sidecar_path] {
+                stats.size_bytes += fs::metadata(path).map(|m| m.len()).unwrap_or_default();
+            }
+        }
+        Ok(Some(stats))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::borrow::Cow;
+
+    #[test]
+    fn dir_store() {
+        let root = std::env::temp_dir().join(format!("wasm-exec-api-dir-{}", std::process::id()));
+        let store = DirStore::open(&root).unwrap();
+        let metadata = ModuleMetadata {
+            tags: vec!["math".to_owned()],
+            ..ModuleMetadata::default()
+        };
+        let put = |name: &str, host_modules: &[Cow<str>]| {
+            let module = WasmModuleRef {
+                code: b"code",
+                host_modules,
+                param_names: &HashMap::new(),
+                metadata: &metadata,
+                expires: None,
+            };
+            store.put_module(name, &module)
+        };
+        put("utils", &[]).unwrap();
+        put("team-a/link", &["utils".into()]).unwrap();
+        assert!(root.join("utils.wasm").is_file());
+        assert!(root.join("team-a").join("link.json").is_file());
+
+        // Writes are unique
+        assert!(put("utils", &[]).is_err());
+
+        let loaded = store.load_module("team-a/link").unwrap();
+        assert_eq!(loaded.code, b"code");
+        assert_eq!(loaded.host_modules, ["utils"]);
+        assert_eq!(loaded.metadata.tags, ["math"]);
+        let mut names = store.module_names().unwrap();
+        names.sort();
+        assert_eq!(names, ["team-a/link", "utils"]);
+        assert_eq!(store.stats().unwrap().unwrap().modules, 2);
+
+        // Sidecars can be written by hand, with only the fields needed
+        fs::write(root.join("hand.wasm"), b"hand code").unwrap();
+        fs::write(root.join("hand.json"), b"{}").unwrap();
+        assert_eq!(store.load_module("hand").unwrap().code, b"hand code");
+
+        // Code isn't left behind when the sidecar can't be written
+        fs::create_dir_all(root.join("blocked.json").join("dir")).unwrap();
+        assert!(put("blocked", &[]).is_err());
+        assert!(!root.join("blocked.wasm").exists());
+
+        store.remove_module("utils").unwrap();
+        assert!(!store.contains_module("utils").unwrap());
+        assert!(!root.join("utils.wasm").exists());
+        assert!(store.load_module("utils").is_err());
+        assert!(store.load_module("../utils").is_err());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
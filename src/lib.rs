@@ -0,0 +1,17 @@
+#![recursion_limit = "1024"]
+
+#[cfg(not(feature = "p2p"))]
+pub mod archive;
+pub mod audit;
+pub mod auth;
+pub mod client;
+pub mod dir_store;
+pub mod local_db;
+pub mod logger;
+pub mod metrics;
+pub mod namespaces;
+pub mod server;
+pub mod utils;
+
+#[cfg(feature = "p2p")]
+pub mod p2p;
@@ -1,36 +1,158 @@
-use super::utils::*;
+use super::metrics::METRICS;
+use super::utils::record::{self, decode_module, encode_module};
+use super::utils::{kv::GuestKv, *};
 use anyhow::{anyhow, Error};
-use serde_cbor::{from_slice, to_vec};
 use sled::Db;
-use std::borrow::Cow;
+
+/// Name of the sled tree for guest key-value storage.
+const GUEST_KV_TREE: &str = "guest_kv";
+/// Name of the sled tree used to check the database is writable.
+const HEALTH_TREE: &str = "health";
+
+/// Record upgraded to the current version by a migration.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MigratedRecord {
+    pub name: String,
+    /// Version of the record before the migration.
+    pub from: u32,
+}
 
 /// Represents a sled db to load and store Wasm code.
 pub struct LocalDB(pub Db);
-impl WasmStore for LocalDB {
+
+impl LocalDB {
+    /// Upgrades all records written by older versions to the current version. With `dry_run`,
+    /// only returns the records which would be upgraded.
+    pub fn migrate(&self, dry_run: bool) -> Result<Vec<MigratedRecord>, Error> {
+        let mut migrated = Vec::new();
+        for entry in self.0.iter() {
+            let (key, bytes) = entry?;
+            let name = String::from_utf8_lossy(&key).into_owned();
+            let from = record::record_version(&bytes)
+                .map_err(|e| anyhow!("Could not read module {}: {}", name, e))?;
+            if from == record::CURRENT_VERSION {
+                continue;
+            }
+            if !dry_run {
+                let (module, _) = decode_module(&bytes)?;
+                // Records changed since they were read are left for the next run.
+                let _ =
+                    self.0
+                        .compare_and_swap(&key, Some(bytes), Some(encode_module(&module)?))?;
+            }
+            migrated.push(MigratedRecord { name, from });
+        }
+        Ok(migrated)
+    }
+}
+
+impl SyncWasmStore for LocalDB {
     fn load_module(&self, name: &str) -> Result<WasmModule, Error> {
-        let bytes = self.0.get(name)?.ok_or_else(|| {
-            anyhow!(
-                "Could not find module {} in the database",
-                // TODO this may not always be utf8 in future
-                String::from_utf8_lossy(name.as_ref())
-            )
-        })?;
-        Ok(from_slice(bytes.as_ref())?)
+        let bytes = METRICS
+            .time_store_op("load_module", || self.0.get(name))?
+            .ok_or_else(|| {
+                anyhow!(
+                    "Could not find module {} in the database",
+                    // TODO this may not always be utf8 in future
+                    String::from_utf8_lossy(name.as_ref())
+                )
+            })?;
+        let (module, upgraded) = decode_module(bytes.as_ref())?;
+        if upgraded {
+            // Failing to store the upgraded record only means it's upgraded again on next read.
+            let _ = self
+                .0
+                .compare_and_swap(name, Some(bytes), Some(encode_module(&module)?));
+        }
+        Ok(module)
     }
     fn contains_module(&self, name: &str) -> Result<bool, Error> {
-        Ok(self.0.contains_key(name)?)
-    }
-    fn put_module(
-        &self,
-        name: &str,
-        code: &[u8],
-        host_modules: &[Cow<'_, str>],
-    ) -> Result<(), Error> {
-        let serialized = to_vec(&WasmModuleRef { code, host_modules })?;
+        Ok(METRICS.time_store_op("contains_module", || self.0.contains_key(name))?)
+    }
+    fn put_module(&self, name: &str, module: &WasmModuleRef<'_, '_>) -> Result<(), Error> {
+        let serialized = encode_module(module)?;
         // Compare and swap to do unique insertion to enforce modules can't be overwritten
         // with race condition.
+        METRICS.time_store_op("put_module", || {
+            self.0
+                .compare_and_swap(name, None as Option<&[u8]>, Some(serialized))
+        })??;
+        Ok(())
+    }
+    fn module_names(&self) -> Result<Vec<String>, Error> {
         self.0
-            .compare_and_swap(name, None as Option<&[u8]>, Some(serialized))??;
+            .iter()
+            .keys()
+            .map(|k| Ok(String::from_utf8(k?.to_vec())?))
+            .collect()
+    }
+    fn remove_module(&self, name: &str) -> Result<(), Error> {
+        METRICS.time_store_op("remove_module", || self.0.remove(name))?;
         Ok(())
     }
+    fn guest_kv(&self, transactional: bool) -> Result<Option<GuestKv>, Error> {
+        let tree = self.0.open_tree(GUEST_KV_TREE)?;
+        Ok(Some(GuestKv::new(tree, transactional)))
+    }
+    fn readiness_checks(&self) -> Vec<ReadinessCheck> {
+        let res = (|| {
+            let tree = self.0.open_tree(HEALTH_TREE)?;
+            tree.insert("probe", "ok")?;
+            if tree.get("probe")?.as_deref() != Some(b"ok".as_ref()) {
+                return Err(anyhow!("Read back a different value than written"));
+            }
+            tree.remove("probe")?;
+            Ok(())
+        })();
+        vec![ReadinessCheck::from_result("database", res)]
+    }
+    fn stats(&self) -> Result<Option<StoreStats>, Error> {
+        Ok(Some(StoreStats {
+            modules: self.0.len(),
+            size_bytes: self.0.size_on_disk()?,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::record::CURRENT_VERSION;
+    use std::collections::HashMap;
+
+    #[test]
+    fn migrate_records() {
+        let db = LocalDB(sled::Config::new().temporary(true).open().unwrap());
+        let v1 = |code: &[u8]| serde_cbor::to_vec(&(code, ["utils"])).unwrap();
+        db.0.insert("old", v1(b"old code")).unwrap();
+        db.0.insert("read", v1(b"read code")).unwrap();
+        db.put_module(
+            "new",
+            &WasmModuleRef {
+                code: b"new code",
+                host_modules: &[],
+                param_names: &HashMap::new(),
+                metadata: &ModuleMetadata::default(),
+                expires: None,
+            },
+        )
+        .unwrap();
+
+        // Records are upgraded when read.
+        assert_eq!(db.load_module("read").unwrap().code, b"read code");
+        let version =
+            |name: &str| record::record_version(&db.0.get(name).unwrap().unwrap()).unwrap();
+        assert_eq!(version("read"), CURRENT_VERSION);
+
+        let expected = vec![MigratedRecord {
+            name: "old".to_owned(),
+            from: 1,
+        }];
+        assert_eq!(db.migrate(true).unwrap(), expected);
+        assert_eq!(version("old"), 1);
+        assert_eq!(db.migrate(false).unwrap(), expected);
+        assert_eq!(version("old"), CURRENT_VERSION);
+        assert_eq!(db.load_module("old").unwrap().host_modules, ["utils"]);
+        assert!(db.migrate(false).unwrap().is_empty());
+    }
 }
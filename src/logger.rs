@@ -1,15 +1,240 @@
-use log::LevelFilter;
+use async_std::task::{self, JoinHandle};
+use log::{LevelFilter, Log, Metadata, Record};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::future::Future;
+use std::io::Write;
+use std::time::SystemTime;
+
+async_std::task_local! {
+    /// Id of the request being handled by the task, attached to its log records.
+    static REQUEST_ID: RefCell<Option<String>> = RefCell::new(None);
+}
+
+thread_local! {
+    /// Id of the request whose blocking work runs on the thread, see [`spawn_blocking`].
+    static THREAD_REQUEST_ID: RefCell<Option<String>> = RefCell::new(None);
+}
+
+/// Format of log records.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// Human readable lines.
+    Text,
+    /// A JSON object per line.
+    Json,
+}
+
+impl Default for LogFormat {
+    fn default() -> Self {
+        LogFormat::Text
+    }
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(anyhow::anyhow!(
+                "Invalid log format {}, expected text or json",
+                s
+            )),
+        }
+    }
+}
+
+/// Returns the id of the request being handled by the current task, or by the blocking work on
+/// the current thread, if any.
+pub fn request_id() -> Option<String> {
+    REQUEST_ID
+        .try_with(|id| id.borrow().clone())
+        .ok()
+        .flatten()
+        .or_else(|| THREAD_REQUEST_ID.with(|id| id.borrow().clone()))
+}
+
+/// Sets the request id of the current task, returning the previous id.
+pub fn set_request_id(id: Option<String>) -> Option<String> {
+    REQUEST_ID.with(|current| current.replace(id))
+}
+
+/// Runs the future with the request id attached to the log records of the current task.
+pub async fn with_request_id<F: Future>(id: String, fut: F) -> F::Output {
+    let previous = set_request_id(Some(id));
+    let res = fut.await;
+    set_request_id(previous);
+    res
+}
+
+/// Runs the closure on the blocking thread pool, with the request id of the current task attached
+/// to its log records.
+pub fn spawn_blocking<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let id = request_id();
+    task::spawn_blocking(move || {
+        let previous = THREAD_REQUEST_ID.with(|current| current.replace(id));
+        let res = f();
+        THREAD_REQUEST_ID.with(|current| current.replace(previous));
+        res
+    })
+}
+
+/// Appends the request id to the messages of the wrapped logger.
+struct RequestIdLogger<L>(L);
+
+impl<L: Log> Log for RequestIdLogger<L> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        match request_id() {
+            Some(id) => self.0.log(
+                &Record::builder()
+                    .args(format_args!("{}, request_id={}", record.args(), id))
+                    .metadata(record.metadata().clone())
+                    .module_path(record.module_path())
+                    .file(record.file())
+                    .line(record.line())
+                    .build(),
+            ),
+            None => self.0.log(record),
+        }
+    }
+
+    fn flush(&self) {
+        self.0.flush()
+    }
+}
+
+#[derive(Serialize)]
+struct JsonRecord<'a> {
+    timestamp: u128,
+    level: String,
+    target: &'a str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+}
+
+/// Writes log records as JSON lines to stderr.
+struct JsonLogger(env_logger::filter::Filter);
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.0.matches(record) {
+            return;
+        }
+        let line = JsonRecord {
+            timestamp: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_millis())
+                .unwrap_or_default(),
+            level: record.level().to_string(),
+            target: record.target(),
+            message: record.args().to_string(),
+            request_id: request_id(),
+        };
+        if let Ok(line) = serde_json::to_string(&line) {
+            let _ = writeln!(std::io::stderr(), "{}", line);
+        }
+    }
+
+    fn flush(&self) {}
+}
 
 /// Parses RUST_LOG configuration, as well as initializing async logger.
-pub fn setup_logger() {
-    let mut logger_builder = pretty_env_logger::formatted_timed_builder();
-    if let Ok(s) = ::std::env::var("RUST_LOG") {
-        logger_builder.parse_filters(&s);
-    } else {
-        logger_builder.filter(None, LevelFilter::Info);
-    }
-    let logger = logger_builder.build();
-    async_log::Logger::wrap(logger, || 12)
-        .start(log::LevelFilter::Trace)
-        .unwrap();
+pub fn setup_logger(format: LogFormat) {
+    let filters = std::env::var("RUST_LOG").ok();
+    match format {
+        LogFormat::Text => {
+            let mut logger_builder = pretty_env_logger::formatted_timed_builder();
+            if let Some(s) = &filters {
+                logger_builder.parse_filters(s);
+            } else {
+                logger_builder.filter(None, LevelFilter::Info);
+            }
+            let logger = logger_builder.build();
+            async_log::Logger::wrap(RequestIdLogger(logger), || 12)
+                .start(log::LevelFilter::Trace)
+                .unwrap();
+        }
+        LogFormat::Json => {
+            let mut filter_builder = env_logger::filter::Builder::new();
+            if let Some(s) = &filters {
+                filter_builder.parse(s);
+            } else {
+                filter_builder.filter(None, LevelFilter::Info);
+            }
+            let filter = filter_builder.build();
+            log::set_max_level(filter.filter());
+            log::set_boxed_logger(Box::new(JsonLogger(filter))).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::local_db::LocalDB;
+    use crate::server::request_id::REQUEST_ID_HEADER;
+    use crate::server::{app, Options};
+    use crate::utils::Blocking;
+    use http_types::{Method, Request, Url};
+    use std::sync::{Arc, Mutex};
+
+    /// Keeps the messages of the log records.
+    struct Capture(Arc<Mutex<Vec<String>>>);
+
+    impl Log for Capture {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+        fn log(&self, record: &Record) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+        fn flush(&self) {}
+    }
+
+    #[async_std::test]
+    async fn guest_logs_carry_request_id() {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        log::set_boxed_logger(Box::new(RequestIdLogger(Capture(lines.clone())))).unwrap();
+        log::set_max_level(LevelFilter::Info);
+
+        let app = app(
+            Arc::new(Blocking::new(LocalDB(
+                sled::Config::new().temporary(true).open().unwrap(),
+            ))),
+            Options::default(),
+        );
+        let url = Url::parse("http://localhost/").unwrap();
+        let mut req = Request::new(Method::Post, url);
+        req.insert_header(REQUEST_ID_HEADER, "guest-req");
+        req.set_body(
+            http_types::Body::from_json(&serde_json::json!({
+                "wasm_hex": hex::encode(include_bytes!("../host.wasm").as_ref()),
+                "function_name": "greet",
+                "host_functions": ["env", "random"],
+            }))
+            .unwrap(),
+        );
+        let res: http_types::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), http_types::StatusCode::Ok);
+
+        let lines = lines.lock().unwrap();
+        assert!(lines
+            .iter()
+            .any(|l| l == "Guest log: hello, request_id=guest-req"));
+    }
 }
@@ -1,40 +1,377 @@
 #![recursion_limit = "1024"]
 
+mod cli;
 mod config;
-mod local_db;
-mod logger;
-mod server;
-mod utils;
 
 #[cfg(feature = "p2p")]
-mod p2p;
+use wasm_exec_api::p2p;
+#[cfg(not(feature = "p2p"))]
+use wasm_exec_api::{archive, local_db};
+use wasm_exec_api::{audit, auth, client, dir_store, logger, namespaces, server, utils};
+
+use audit::AuditLog;
+use auth::{ApiKeys, Scope};
+use client::Client;
+use config::{
+    Command, Config, KeysAction, KeysCommand, NamespacesAction, NamespacesCommand, Settings,
+};
+use namespaces::Namespaces;
+use std::sync::Arc;
+use utils::layered::{Cached, MemoryCache};
+use utils::limits::UsageCounters;
+use utils::{Blocking, WasmStore};
+
+/// Environment variable of the API key for the module subcommands.
+const API_KEY_ENV: &str = "WASM_EXEC_API_API_KEY";
+
+/// Opens the sled database in the data directory, or the default directory if not provided.
+fn open_db(data_directory: Option<String>) -> sled::Result<sled::Db> {
+    let path = data_directory.unwrap_or_else(|| {
+        format!(
+            "{}/.wasm_exec_api",
+            dirs::home_dir().unwrap().to_str().unwrap()
+        )
+    });
+    sled::open(path)
+}
+
+/// Runs a command to manage the API keys stored in the database.
+fn run_keys_command(db: &sled::Db, command: KeysCommand) -> Result<(), anyhow::Error> {
+    let keys = ApiKeys::new(db)?;
+    match command.action {
+        KeysAction::Create(create) => {
+            let scopes = create
+                .scope
+                .iter()
+                .map(|s| s.parse())
+                .collect::<Result<Vec<Scope>, _>>()?;
+            let (token, key) = keys.create(&create.name, scopes, create.namespace.as_deref())?;
+            println!("Created key {} ({}): {}", key.id, key.name, token);
+        }
+        KeysAction::Revoke(revoke) => {
+            if !keys.revoke(&revoke.id)? {
+                return Err(anyhow::anyhow!("No API key with id {}", revoke.id));
+            }
+            println!("Revoked key {}", revoke.id);
+        }
+        KeysAction::List(_) => {
+            for key in keys.list()? {
+                match &key.namespace {
+                    Some(namespace) => {
+                        println!("{}\t{}\t{:?}\t{}", key.id, key.name, key.scopes, namespace)
+                    }
+                    None => println!("{}\t{}\t{:?}", key.id, key.name, key.scopes),
+                }
+            }
+        }
+    }
+    db.flush()?;
+    Ok(())
+}
+
+/// Runs a command to manage the namespace settings stored in the database.
+fn run_namespaces_command(db: &sled::Db, command: NamespacesCommand) -> Result<(), anyhow::Error> {
+    let namespaces = Namespaces::new(db)?;
+    match command.action {
+        NamespacesAction::Quota(quota) => {
+            let mut config = namespaces.get(&quota.namespace)?;
+            config.max_modules = quota.max_modules;
+            config.max_bytes = quota.max_bytes;
+            namespaces.set(&quota.namespace, &config)?;
+            println!("Updated the quotas of namespace {}", quota.namespace);
+        }
+        NamespacesAction::Grant(grant) => {
+            namespaces.grant(&grant.namespace, &grant.to)?;
+            println!(
+                "Namespace {} can import modules of namespace {}",
+                grant.to, grant.namespace
+            );
+        }
+        NamespacesAction::Revoke(revoke) => {
+            if !namespaces.revoke(&revoke.namespace, &revoke.to)? {
+                return Err(anyhow::anyhow!(
+                    "Namespace {} has no grant to namespace {}",
+                    revoke.namespace,
+                    revoke.to
+                ));
+            }
+            println!(
+                "Namespace {} can no longer import modules of namespace {}",
+                revoke.to, revoke.namespace
+            );
+        }
+        NamespacesAction::List(_) => {
+            let limit = |max: Option<String>| max.unwrap_or_else(|| "-".to_owned());
+            for (name, config) in namespaces.list()? {
+                println!(
+                    "{}\tmax modules: {}\tmax bytes: {}\tgrants: {}",
+                    name,
+                    limit(config.max_modules.map(|m| m.to_string())),
+                    limit(config.max_bytes.map(|m| m.to_string())),
+                    config.grants.join(",")
+                );
+            }
+        }
+    }
+    db.flush()?;
+    Ok(())
+}
+
+/// Runs a maintenance command on the modules of the store, with module owners in `keys` and the
+/// usage counters in `usage`. Only the records of the sled store, given as `local`, are versioned
+/// and can be migrated.
+#[cfg(not(feature = "p2p"))]
+async fn run_store_command<S>(
+    store: &S,
+    keys: &ApiKeys,
+    usage: &UsageCounters,
+    local: Option<&local_db::LocalDB>,
+    command: config::StoreCommand,
+) -> Result<(), anyhow::Error>
+where
+    S: utils::WasmStore,
+{
+    use config::StoreAction;
+    use std::fs::File;
+
+    match command.action {
+        StoreAction::Export(export) => {
+            let count = archive::export(store, keys, File::create(&export.file)?).await?;
+            println!("Exported {} modules to {}", count, export.file);
+        }
+        StoreAction::Import(import) => {
+            let count = archive::import(store, keys, usage, File::open(&import.file)?).await?;
+            println!("Imported {} modules from {}", count, import.file);
+        }
+        StoreAction::Verify(_) => {
+            let failures = archive::verify(store).await?;
+            for failure in &failures {
+                println!("{}: {}", failure.name, failure.error);
+            }
+            if !failures.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "{} modules failed verification",
+                    failures.len()
+                ));
+            }
+            println!("All modules verified");
+        }
+        StoreAction::Migrate(migrate) => {
+            let local = local.ok_or_else(|| {
+                anyhow::anyhow!("Only the records of the sled store are versioned")
+            })?;
+            let migrated = local.migrate(migrate.dry_run)?;
+            for record in &migrated {
+                let steps: Vec<_> = utils::record::migrations_from(record.from)
+                    .map(|m| m.description)
+                    .collect();
+                println!(
+                    "{}: version {} -> {} ({})",
+                    record.name,
+                    record.from,
+                    utils::record::CURRENT_VERSION,
+                    steps.join(", ")
+                );
+            }
+            if migrate.dry_run {
+                println!("{} modules would be upgraded", migrated.len());
+            } else {
+                println!("Upgraded {} modules", migrated.len());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Store wrapped in the layers enabled in the settings: the read-only overlay directory, then the
+/// memory cache in front.
+fn layered_store<S>(
+    store: S,
+    settings: &Settings,
+) -> Result<Box<dyn WasmStore + Send + Sync>, anyhow::Error>
+where
+    S: WasmStore + Send + Sync + 'static,
+{
+    let mut store: Box<dyn WasmStore + Send + Sync> = Box::new(store);
+    if let Some(path) = &settings.overlay {
+        let overlay = Blocking::new(dir_store::DirStore::open(path)?);
+        store = Box::new(utils::layered::Overlay::new(overlay, store));
+    }
+    if settings.cache_size > 0 {
+        store = Box::new(Cached::new(store, MemoryCache::new(settings.cache_size)));
+    }
+    Ok(store)
+}
+
+/// Opens the usage counters in the database, counting the modules of the store the first time.
+async fn usage_counters<S>(db: &sled::Db, store: &S) -> Result<Arc<UsageCounters>, anyhow::Error>
+where
+    S: WasmStore + ?Sized,
+{
+    let counters = UsageCounters::new(db)?;
+    let owners = ApiKeys::new(db)?.owners()?.into_iter().collect();
+    counters.init(store, &owners).await?;
+    Ok(Arc::new(counters))
+}
+
+/// Creates the client for the module subcommands: the server given with `--server`, or the data
+/// directory handled in process. If the data directory is held by a running server, the server at
+/// the configured address is used. Returns the database if opened, to be flushed.
+async fn module_client(
+    config: &Config,
+    settings: &Settings,
+) -> Result<(Client, Option<sled::Db>), anyhow::Error> {
+    let api_key = config
+        .api_key
+        .clone()
+        .or_else(|| std::env::var(API_KEY_ENV).ok());
+    let with_key = |client: Client| match &api_key {
+        Some(key) => client.with_api_key(key.clone()),
+        None => client,
+    };
+    if let Some(url) = &config.server {
+        return Ok((with_key(Client::new(url)?), None));
+    }
+
+    #[cfg(not(feature = "p2p"))]
+    match open_db(settings.data_directory.clone()) {
+        Ok(db) => {
+            // Local use is trusted like the admin, but keeps the same limits and audit log.
+            let audit = if settings.audit {
+                Some(Arc::new(AuditLog::new(&db)?))
+            } else {
+                None
+            };
+            let namespaces = Some(Arc::new(Namespaces::new(&db)?));
+            let store = match &settings.store {
+                config::StoreBackend::Sled => {
+                    layered_store(Blocking::new(local_db::LocalDB(db.clone())), settings)?
+                }
+                config::StoreBackend::Dir(path) => {
+                    layered_store(Blocking::new(dir_store::DirStore::open(path)?), settings)?
+                }
+            };
+            let usage = Some(usage_counters(&db, &store).await?);
+            let options = settings.server_options(None, audit, namespaces, usage);
+            let app = server::app(Arc::new(store), options);
+            return Ok((Client::in_process(app), Some(db)));
+        }
+        Err(e) => log::debug!("Could not open the data directory, using the server: {}", e),
+    }
+    Ok((with_key(Client::new(&settings.url())?), None))
+}
+
+/// Runs a module subcommand. Logs are hidden unless enabled with `RUST_LOG`, errors are returned
+/// by the command.
+async fn run_module_command(
+    config: &Config,
+    settings: &Settings,
+    command: Command,
+) -> Result<(), anyhow::Error> {
+    if std::env::var("RUST_LOG").is_err() {
+        log::set_max_level(log::LevelFilter::Off);
+    }
+    let (client, db) = module_client(config, settings).await?;
+    cli::run_module_command(&client, command).await?;
+    if let Some(db) = db {
+        db.flush_async().await?;
+    }
+    Ok(())
+}
+
+/// Prints the effective settings as TOML.
+fn print_settings(settings: &config::Settings) -> Result<(), anyhow::Error> {
+    print!("{}", toml::to_string(settings)?);
+    Ok(())
+}
 
 #[cfg(not(feature = "p2p"))]
 #[async_std::main]
 async fn main() -> tide::Result<()> {
-    use config::Config;
-    use dirs::home_dir;
+    use config::StoreBackend;
+    use dir_store::DirStore;
     use local_db::LocalDB;
-    use std::sync::Arc;
 
-    logger::setup_logger();
-
-    let Config {
-        port,
-        memory,
-        data_directory,
-    } = argh::from_env();
+    let mut config: Config = argh::from_env();
+    let settings = Settings::load(&config)?;
+    logger::setup_logger(settings.log_format);
+    if config.print_config {
+        return Ok(print_settings(&settings)?);
+    }
+    match config.command.take() {
+        Some(Command::Keys(command)) => {
+            let db = open_db(settings.data_directory.clone())?;
+            return Ok(run_keys_command(&db, command)?);
+        }
+        Some(Command::Namespaces(command)) => {
+            let db = open_db(settings.data_directory.clone())?;
+            return Ok(run_namespaces_command(&db, command)?);
+        }
+        Some(Command::Store(command)) => {
+            // Module owners are kept in the database with either backend.
+            let db = open_db(settings.data_directory.clone())?;
+            let keys = ApiKeys::new(&db)?;
+            match &settings.store {
+                StoreBackend::Sled => {
+                    let store = Blocking::new(LocalDB(db.clone()));
+                    let usage = usage_counters(&db, &store).await?;
+                    run_store_command(&store, &keys, &usage, Some(store.0.as_ref()), command)
+                        .await?;
+                }
+                StoreBackend::Dir(path) => {
+                    let store = Blocking::new(DirStore::open(path)?);
+                    let usage = usage_counters(&db, &store).await?;
+                    run_store_command(&store, &keys, &usage, None, command).await?
+                }
+            }
+            db.flush()?;
+            return Ok(());
+        }
+        Some(command) => return Ok(run_module_command(&config, &settings, command).await?),
+        None => {}
+    }
+    let listen = settings.listen()?;
 
-    let db = if memory {
+    let db = if settings.memory {
         sled::Config::new().temporary(true).open().unwrap()
     } else {
-        let path = data_directory
-            .unwrap_or_else(|| format!("{}/.wasm_exec_api", home_dir().unwrap().to_str().unwrap()));
-        sled::open(path).unwrap()
+        open_db(settings.data_directory.clone()).unwrap()
     };
-    let db = Arc::new(LocalDB(db));
 
-    server::start(port, db).await
+    let auth = if settings.auth {
+        Some(Arc::new(ApiKeys::new(&db)?))
+    } else {
+        None
+    };
+    let audit = if settings.audit {
+        Some(Arc::new(AuditLog::new(&db)?))
+    } else {
+        None
+    };
+    let namespaces = Some(Arc::new(Namespaces::new(&db)?));
+    let store = match &settings.store {
+        StoreBackend::Sled => {
+            let store = LocalDB(db.clone());
+            let migrated = store.migrate(false)?;
+            if !migrated.is_empty() {
+                log::info!("Upgraded {} stored modules", migrated.len());
+            }
+            layered_store(Blocking::new(store), &settings)?
+        }
+        StoreBackend::Dir(path) => layered_store(Blocking::new(DirStore::open(path)?), &settings)?,
+    };
+    let usage = Some(usage_counters(&db, &store).await?);
+    let options = settings.server_options(auth, audit, namespaces, usage);
+
+    server::start(
+        &listen,
+        Arc::new(store),
+        options,
+        server::shutdown::signal(),
+    )
+    .await?;
+    db.flush_async().await?;
+    Ok(())
 }
 
 #[cfg(feature = "p2p")]
@@ -42,14 +379,50 @@ async fn main() -> tide::Result<()> {
 async fn main() -> Result<(), anyhow::Error> {
     use anyhow::anyhow;
     use async_std::{sync::channel, task};
-    use config::Config;
     use libp2p::{build_development_transport, identity, PeerId, Swarm};
-    use p2p::{behaviour::MyBehaviour, service::P2pService, store};
-    use std::sync::Arc;
+    use p2p::behaviour::MyBehaviour;
+    use p2p::service::{NetworkRequest, NetworkStatus, P2pService};
+    use p2p::store;
+    use utils::layered::SledCache;
 
-    logger::setup_logger();
-
-    let Config { port } = argh::from_env();
+    let mut config: Config = argh::from_env();
+    let settings = Settings::load(&config)?;
+    logger::setup_logger(settings.log_format);
+    if config.print_config {
+        return print_settings(&settings);
+    }
+    // The p2p node only uses the local database for API keys, namespace settings and the audit
+    // log, so module subcommands always use the server.
+    match config.command.take() {
+        Some(Command::Keys(command)) => {
+            return run_keys_command(&open_db(settings.data_directory.clone())?, command)
+        }
+        Some(Command::Namespaces(command)) => {
+            return run_namespaces_command(&open_db(settings.data_directory.clone())?, command)
+        }
+        Some(command) => return run_module_command(&config, &settings, command).await,
+        None => {}
+    }
+    let listen = settings.listen()?;
+    let quotas = settings.max_store_size.is_some() || settings.max_owner_size.is_some();
+    let local_db = if settings.auth || settings.audit || settings.dht_cache || quotas {
+        Some(open_db(settings.data_directory.clone())?)
+    } else {
+        None
+    };
+    let auth = match &local_db {
+        Some(db) if settings.auth => Some(Arc::new(ApiKeys::new(db)?)),
+        _ => None,
+    };
+    let audit = match &local_db {
+        Some(db) if settings.audit => Some(Arc::new(AuditLog::new(db)?)),
+        _ => None,
+    };
+    // Namespace settings are only read when the local database is used.
+    let namespaces = match &local_db {
+        Some(db) => Some(Arc::new(Namespaces::new(db)?)),
+        None => None,
+    };
 
     // Create a random key for ourselves.
     let local_key = identity::Keypair::generate_ed25519();
@@ -59,8 +432,9 @@ async fn main() -> Result<(), anyhow::Error> {
     let transport = build_development_transport(local_key)?;
 
     // Create a swarm to manage peers and events.
+    let status = Arc::new(NetworkStatus::default());
     let mut swarm = {
-        let behaviour = MyBehaviour::new(local_peer_id.clone());
+        let behaviour = MyBehaviour::new(local_peer_id.clone(), status.clone());
         Swarm::new(transport, behaviour, local_peer_id)
     };
 
@@ -78,11 +452,41 @@ async fn main() -> Result<(), anyhow::Error> {
         .run(),
     );
 
-    server::start(port, Arc::new(store::P2pStore(network_sender)))
-        .await
-        .map_err(|e| anyhow!("{}", e))?;
+    let store = store::P2pStore {
+        sender: network_sender.clone(),
+        status,
+        min_peers: settings.min_peers,
+    };
+    let store = match &local_db {
+        Some(db) if settings.dht_cache => {
+            let cache = SledCache(db.open_tree(store::CACHE_TREE)?);
+            layered_store(Cached::new(store, cache), &settings)?
+        }
+        _ => layered_store(store, &settings)?,
+    };
+    // The DHT can't be listed, so usage counts the modules registered through this node.
+    let usage = match &local_db {
+        Some(db) => Some(usage_counters(db, &store).await?),
+        None => None,
+    };
+    let mut options = settings.server_options(auth, audit, namespaces, usage);
+    // Peers drop records once they expire, the DHT can't be listed to sweep it.
+    options.sweep_interval = None;
+    server::start(
+        &listen,
+        Arc::new(store),
+        options,
+        server::shutdown::signal(),
+    )
+    .await
+    .map_err(|e| anyhow!("{}", e))?;
 
-    p2p.cancel().await;
+    // Stop the p2p service after the requests using it have finished.
+    network_sender.send(NetworkRequest::Shutdown).await;
+    p2p.await;
+    if let Some(db) = local_db {
+        db.flush_async().await?;
+    }
 
     Ok(())
 }
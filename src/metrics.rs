@@ -0,0 +1,306 @@
+use crate::utils::StoreStats;
+use once_cell::sync::Lazy;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+#[cfg(feature = "p2p")]
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tide::utils::async_trait;
+use tide::{Middleware, Next, Request};
+
+/// Upper bounds of the latency histogram buckets, in seconds.
+const BUCKETS: [f64; 12] = [
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Metrics of the process, rendered in the Prometheus text format.
+pub static METRICS: Lazy<Metrics> = Lazy::new(Metrics::default);
+
+/// Counters keyed by their rendered label set.
+#[derive(Default)]
+struct Counters(Mutex<BTreeMap<String, u64>>);
+
+impl Counters {
+    fn inc(&self, labels: &[(&str, &str)]) {
+        *self
+            .0
+            .lock()
+            .unwrap()
+            .entry(render_labels(labels))
+            .or_default() += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter", name, help, name);
+        for (labels, value) in self.0.lock().unwrap().iter() {
+            let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+        }
+    }
+}
+
+#[derive(Default, Clone)]
+struct Histogram {
+    buckets: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+/// Latency histograms keyed by their rendered label set.
+#[derive(Default)]
+struct Histograms(Mutex<BTreeMap<String, Histogram>>);
+
+impl Histograms {
+    fn observe(&self, labels: &[(&str, &str)], duration: Duration) {
+        let secs = duration.as_secs_f64();
+        let mut histograms = self.0.lock().unwrap();
+        let histogram = histograms.entry(render_labels(labels)).or_default();
+        for (bucket, bound) in histogram.buckets.iter_mut().zip(BUCKETS.iter()) {
+            if secs <= *bound {
+                *bucket += 1;
+            }
+        }
+        histogram.sum += secs;
+        histogram.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} histogram", name, help, name);
+        for (labels, histogram) in self.0.lock().unwrap().iter() {
+            let sep = if labels.is_empty() { "" } else { "," };
+            for (count, bound) in histogram.buckets.iter().zip(BUCKETS.iter()) {
+                let _ = writeln!(
+                    out,
+                    "{}_bucket{{{}{}le=\"{}\"}} {}",
+                    name, labels, sep, bound, count
+                );
+            }
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}{}le=\"+Inf\"}} {}",
+                name, labels, sep, histogram.count
+            );
+            let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, histogram.sum);
+            let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, histogram.count);
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    http_requests: Counters,
+    http_duration: Histograms,
+    executions: Histograms,
+    traps: Counters,
+    store_ops: Histograms,
+    cache_lookups: Counters,
+    #[cfg(feature = "p2p")]
+    dht_queries: Counters,
+    #[cfg(feature = "p2p")]
+    peers: std::sync::atomic::AtomicUsize,
+}
+
+impl Metrics {
+    /// Records a handled HTTP request.
+    pub fn record_request(&self, route: &str, method: &str, status: u16, duration: Duration) {
+        let status = status.to_string();
+        self.http_requests
+            .inc(&[("route", route), ("method", method), ("status", &status)]);
+        self.http_duration
+            .observe(&[("route", route), ("method", method)], duration);
+    }
+
+    /// Records the time of a guest execution, `adhoc` or `registered`.
+    pub fn record_execution(&self, kind: &str, duration: Duration) {
+        self.executions.observe(&[("kind", kind)], duration);
+    }
+
+    /// Records a trap of a guest call.
+    pub fn record_trap(&self, kind: &str) {
+        self.traps.inc(&[("kind", kind)]);
+    }
+
+    /// Runs a store operation, recording its latency.
+    pub fn time_store_op<T>(&self, op: &str, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let res = f();
+        self.store_ops.observe(&[("op", op)], start.elapsed());
+        res
+    }
+
+    /// Awaits a store operation, recording its latency.
+    #[cfg(feature = "p2p")]
+    pub async fn time_store_future<T>(&self, op: &str, f: impl Future<Output = T>) -> T {
+        let start = Instant::now();
+        let res = f.await;
+        self.store_ops.observe(&[("op", op)], start.elapsed());
+        res
+    }
+
+    /// Records a module load from a cache, `memory` or `sled`, which hit or missed.
+    pub fn record_cache_lookup(&self, cache: &str, hit: bool) {
+        let outcome = if hit { "hit" } else { "miss" };
+        self.cache_lookups
+            .inc(&[("cache", cache), ("outcome", outcome)]);
+    }
+
+    /// Records the outcome of a DHT query, `get` or `put`.
+    #[cfg(feature = "p2p")]
+    pub fn record_dht_query(&self, query: &str, ok: bool) {
+        let outcome = if ok { "ok" } else { "error" };
+        self.dht_queries
+            .inc(&[("query", query), ("outcome", outcome)]);
+    }
+
+    /// Sets the number of peers in the routing table.
+    #[cfg(feature = "p2p")]
+    pub fn set_peers(&self, peers: usize) {
+        self.peers
+            .store(peers, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// Renders all metrics, along with the stats of the store if it provides them.
+    pub fn render(&self, store: Option<StoreStats>) -> String {
+        let mut out = String::new();
+        self.http_requests.render(
+            &mut out,
+            "wasm_exec_http_requests_total",
+            "HTTP requests by route, method and status.",
+        );
+        self.http_duration.render(
+            &mut out,
+            "wasm_exec_http_request_duration_seconds",
+            "HTTP request latency by route and method.",
+        );
+        self.executions.render(
+            &mut out,
+            "wasm_exec_execution_duration_seconds",
+            "Guest execution time, including loading and compiling modules.",
+        );
+        self.traps.render(
+            &mut out,
+            "wasm_exec_traps_total",
+            "Guest calls which trapped, by kind.",
+        );
+        self.store_ops.render(
+            &mut out,
+            "wasm_exec_store_operation_duration_seconds",
+            "Latency of module store operations.",
+        );
+        self.cache_lookups.render(
+            &mut out,
+            "wasm_exec_module_cache_lookups_total",
+            "Module loads from a cache by cache and outcome.",
+        );
+        if let Some(stats) = store {
+            let _ = writeln!(
+                out,
+                "# HELP wasm_exec_store_modules Modules in the store.\n\
+                 # TYPE wasm_exec_store_modules gauge\n\
+                 wasm_exec_store_modules {}\n\
+                 # HELP wasm_exec_store_size_bytes Size of the store on disk.\n\
+                 # TYPE wasm_exec_store_size_bytes gauge\n\
+                 wasm_exec_store_size_bytes {}",
+                stats.modules, stats.size_bytes
+            );
+        }
+        #[cfg(feature = "p2p")]
+        {
+            self.dht_queries.render(
+                &mut out,
+                "wasm_exec_dht_queries_total",
+                "DHT queries by query and outcome.",
+            );
+            let _ = writeln!(
+                out,
+                "# HELP wasm_exec_peers Peers in the routing table.\n\
+                 # TYPE wasm_exec_peers gauge\n\
+                 wasm_exec_peers {}",
+                self.peers.load(std::sync::atomic::Ordering::Relaxed)
+            );
+        }
+        out
+    }
+}
+
+fn render_labels(labels: &[(&str, &str)]) -> String {
+    let mut out = String::new();
+    for (i, (name, value)) in labels.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let value = value
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n");
+        let _ = write!(out, "{}=\"{}\"", name, value);
+    }
+    out
+}
+
+/// Route label of a request path, so that module names don't create a label per module.
+fn route_label(path: &str) -> &'static str {
+    match path {
+        "/" => "/",
+        "/register" => "/register",
+        "/execute" => "/execute",
+        "/metrics" => "/metrics",
+        "/audit" => "/audit",
+        "/healthz" => "/healthz",
+        "/readyz" => "/readyz",
+        "/openapi.json" => "/openapi.json",
+        "/admin/usage" => "/admin/usage",
+        "/admin/gc" => "/admin/gc",
+        "/modules" => "/modules",
+        p if p.starts_with("/modules/") => "/modules/:name",
+        _ => "other",
+    }
+}
+
+/// Middleware which records the count and latency of requests.
+pub struct RequestMetrics;
+
+#[async_trait]
+impl<State> Middleware<State> for RequestMetrics
+where
+    State: Clone + Send + Sync + 'static,
+{
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let start = Instant::now();
+        let route = route_label(req.url().path());
+        let method = req.method().to_string();
+        let res = next.run(req).await;
+        METRICS.record_request(route, &method, res.status().into(), start.elapsed());
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_text_format() {
+        let metrics = Metrics::default();
+        metrics.record_request("/", "POST", 200, Duration::from_millis(20));
+        metrics.record_trap("unreachable");
+        metrics.record_trap("unreachable");
+
+        let out = metrics.render(Some(StoreStats {
+            modules: 3,
+            size_bytes: 1024,
+        }));
+        assert!(out.contains(
+            "wasm_exec_http_requests_total{route=\"/\",method=\"POST\",status=\"200\"} 1"
+        ));
+        assert!(out.contains(
+            "wasm_exec_http_request_duration_seconds_bucket{route=\"/\",method=\"POST\",le=\"0.01\"} 0"
+        ));
+        assert!(out.contains(
+            "wasm_exec_http_request_duration_seconds_bucket{route=\"/\",method=\"POST\",le=\"0.025\"} 1"
+        ));
+        assert!(out.contains("wasm_exec_traps_total{kind=\"unreachable\"} 2"));
+        assert!(out.contains("wasm_exec_store_modules 3"));
+        assert_eq!(render_labels(&[("a", "\"x\"")]), "a=\"\\\"x\\\"\"");
+    }
+}
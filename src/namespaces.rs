@@ -0,0 +1,112 @@
+use crate::utils::namespace::{namespace_of, validate_namespace};
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+use serde_cbor::{from_slice, to_vec};
+use sled::{Db, Tree};
+use std::sync::Arc;
+use tide::utils::async_trait;
+use tide::{Middleware, Next, Request};
+
+/// Name of the sled tree for the settings of each namespace.
+const NAMESPACES_TREE: &str = "namespaces";
+
+/// Settings of a namespace. Namespaces without settings have no quotas and grant no imports.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct NamespaceConfig {
+    /// Namespaces whose modules may import the modules of this namespace.
+    pub grants: Vec<String>,
+    pub max_modules: Option<usize>,
+    /// Maximum total size of the wasm code of the modules.
+    pub max_bytes: Option<u64>,
+}
+
+/// Settings of the namespaces, stored in a sled tree.
+pub struct Namespaces {
+    tree: Tree,
+}
+
+impl Namespaces {
+    pub fn new(db: &Db) -> Result<Self, Error> {
+        Ok(Self {
+            tree: db.open_tree(NAMESPACES_TREE)?,
+        })
+    }
+
+    /// Returns the settings of the namespace, or the defaults if it has none.
+    pub fn get(&self, namespace: &str) -> Result<NamespaceConfig, Error> {
+        match self.tree.get(namespace)? {
+            Some(bytes) => Ok(from_slice(&bytes)?),
+            None => Ok(NamespaceConfig::default()),
+        }
+    }
+
+    pub fn set(&self, namespace: &str, config: &NamespaceConfig) -> Result<(), Error> {
+        validate_namespace(namespace)?;
+        self.tree.insert(namespace, to_vec(config)?)?;
+        Ok(())
+    }
+
+    /// Lists the namespaces with settings.
+    pub fn list(&self) -> Result<Vec<(String, NamespaceConfig)>, Error> {
+        self.tree
+            .iter()
+            .map(|entry| {
+                let (name, value) = entry?;
+                Ok((String::from_utf8(name.to_vec())?, from_slice(&value)?))
+            })
+            .collect()
+    }
+
+    /// Allows modules of `to` to import the modules of the namespace.
+    pub fn grant(&self, namespace: &str, to: &str) -> Result<(), Error> {
+        validate_namespace(to)?;
+        let mut config = self.get(namespace)?;
+        if !config.grants.iter().any(|g| g == to) {
+            config.grants.push(to.to_owned());
+        }
+        self.set(namespace, &config)
+    }
+
+    /// Removes the grant of the namespace to `to`. Returns false if it had no grant. Modules
+    /// registered with imports of the namespace keep them.
+    pub fn revoke(&self, namespace: &str, to: &str) -> Result<bool, Error> {
+        let mut config = self.get(namespace)?;
+        let len = config.grants.len();
+        config.grants.retain(|g| g != to);
+        if config.grants.len() == len {
+            return Ok(false);
+        }
+        self.set(namespace, &config)?;
+        Ok(true)
+    }
+
+    /// Returns an error unless modules of `from` may import the module at the store key. Modules
+    /// can always import modules of their own namespace.
+    pub fn check_import(&self, from: &str, key: &str) -> Result<(), Error> {
+        let target = namespace_of(key);
+        if target == from || self.get(target)?.grants.iter().any(|g| g == from) {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "Namespace {} does not grant imports to namespace {}",
+                target,
+                from
+            ))
+        }
+    }
+}
+
+/// Middleware which makes the namespace settings available to the handlers.
+pub struct WithNamespaces(pub Arc<Namespaces>);
+
+#[async_trait]
+impl<State> Middleware<State> for WithNamespaces
+where
+    State: Clone + Send + Sync + 'static,
+{
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        req.set_ext(self.0.clone());
+        Ok(next.run(req).await)
+    }
+}
@@ -1,3 +1,5 @@
+use super::service::NetworkStatus;
+use crate::metrics::METRICS;
 use futures::channel::oneshot::Sender as OneshotSender;
 use libp2p::kad::record::store::MemoryStore;
 use libp2p::kad::{record::Key, Kademlia, Record};
@@ -8,6 +10,8 @@ use libp2p::{
     NetworkBehaviour, PeerId,
 };
 use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
 
 #[derive(NetworkBehaviour)]
 pub struct MyBehaviour {
@@ -15,6 +19,8 @@ pub struct MyBehaviour {
     pub mdns: Mdns,
     #[behaviour(ignore)]
     pub awaiting_response: HashMap<Key, Vec<OneshotSender<Vec<u8>>>>,
+    #[behaviour(ignore)]
+    pub status: Arc<NetworkStatus>,
 }
 
 impl NetworkBehaviourEventProcess<MdnsEvent> for MyBehaviour {
@@ -23,6 +29,7 @@ impl NetworkBehaviourEventProcess<MdnsEvent> for MyBehaviour {
             for (peer_id, multiaddr) in list {
                 self.kademlia.add_address(&peer_id, multiaddr);
             }
+            self.update_peers();
         }
     }
 }
@@ -32,6 +39,7 @@ impl NetworkBehaviourEventProcess<KademliaEvent> for MyBehaviour {
         match message {
             KademliaEvent::QueryResult { result, .. } => match result {
                 QueryResult::GetRecord(Ok(ok)) => {
+                    METRICS.record_dht_query("get", true);
                     for PeerRecord {
                         record: Record { key, value, .. },
                         ..
@@ -46,26 +54,30 @@ impl NetworkBehaviourEventProcess<KademliaEvent> for MyBehaviour {
                     }
                 }
                 QueryResult::GetRecord(Err(err)) => {
+                    METRICS.record_dht_query("get", false);
                     log::warn!("Failed to get record: {:?}", err);
                 }
                 QueryResult::PutRecord(Ok(PutRecordOk { key })) => {
+                    METRICS.record_dht_query("put", true);
                     log::info!(
                         "successfully put key: {}",
                         String::from_utf8_lossy(key.as_ref())
                     );
                 }
                 QueryResult::PutRecord(Err(err)) => {
+                    METRICS.record_dht_query("put", false);
                     log::warn!("Failed to put record: {:?}", err);
                 }
                 _ => {}
             },
+            KademliaEvent::RoutingUpdated { .. } => self.update_peers(),
             _ => {}
         }
     }
 }
 
 impl MyBehaviour {
-    pub fn new(local_peer_id: PeerId) -> Self {
+    pub fn new(local_peer_id: PeerId, status: Arc<NetworkStatus>) -> Self {
         let store = MemoryStore::new(local_peer_id.clone());
         let kademlia = Kademlia::new(local_peer_id, store);
         let mdns = Mdns::new().unwrap();
@@ -73,6 +85,14 @@ impl MyBehaviour {
             kademlia,
             mdns,
             awaiting_response: Default::default(),
+            status,
         }
     }
+
+    /// Updates the peer count from the routing table.
+    fn update_peers(&mut self) {
+        let peers = self.kademlia.kbuckets().map(|b| b.num_entries()).sum();
+        self.status.peers.store(peers, Ordering::SeqCst);
+        METRICS.set_peers(peers);
+    }
 }
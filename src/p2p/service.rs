@@ -1,19 +1,47 @@
 use super::behaviour::MyBehaviour;
+use crate::logger;
 use async_std::sync::Receiver;
 use futures::channel::oneshot::Sender as OneshotSender;
 use futures::{select, StreamExt};
 use libp2p::kad::{record::Key, Quorum, Record};
 use libp2p::Swarm;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Instant;
 
 pub enum NetworkRequest {
     GetDHTKey {
         request: Key,
         response_channel: OneshotSender<Vec<u8>>,
+        /// Id of the HTTP request, for logging.
+        request_id: Option<String>,
     },
     PutDHTKey {
         key: Key,
         value: Vec<u8>,
+        /// Time after which peers drop the record.
+        expires: Option<Instant>,
+        request_id: Option<String>,
     },
+    /// Stops the service.
+    Shutdown,
+}
+
+/// Status of the p2p service, shared with the store for readiness checks.
+#[derive(Default)]
+pub struct NetworkStatus {
+    /// True while the service is running.
+    pub running: AtomicBool,
+    /// Number of peers in the routing table.
+    pub peers: AtomicUsize,
+}
+
+/// Marks the service as stopped when dropped, including if the service panics.
+struct RunningGuard<'a>(&'a NetworkStatus);
+
+impl Drop for RunningGuard<'_> {
+    fn drop(&mut self) {
+        self.0.running.store(false, Ordering::SeqCst);
+    }
 }
 
 pub struct P2pService {
@@ -23,6 +51,10 @@ pub struct P2pService {
 
 impl P2pService {
     pub async fn run(self) {
+        let status = self.swarm.status.clone();
+        status.running.store(true, Ordering::SeqCst);
+        let _running = RunningGuard(&status);
+
         let mut network_stream = self.network_receiver.fuse();
         let mut swarm = self.swarm.fuse();
 
@@ -32,25 +64,34 @@ impl P2pService {
                     Some(NetworkRequest::GetDHTKey {
                         request,
                         response_channel,
+                        request_id,
                     }) => {
+                        let previous = logger::set_request_id(request_id);
+                        log::debug!("Getting record {}", String::from_utf8_lossy(request.as_ref()));
+
                         // Send request for the record
                         swarm.get_mut().kademlia.get_record(&request, Quorum::One);
 
                         // Push awaiting channel at index of the key.
                         let channels = swarm.get_mut().awaiting_response.entry(request).or_default();
                         channels.push(response_channel);
+                        logger::set_request_id(previous);
                     }
-                    Some(NetworkRequest::PutDHTKey { key, value }) => {
+                    Some(NetworkRequest::PutDHTKey { key, value, expires, request_id }) => {
+                        let previous = logger::set_request_id(request_id);
+                        log::debug!("Putting record {}", String::from_utf8_lossy(key.as_ref()));
+
                         // Generate a record to put in the DHT
                         let record = Record {
                             key,
                             value,
                             publisher: None,
-                            expires: None,
+                            expires,
                         };
                         swarm.get_mut().kademlia.put_record(record, Quorum::One).unwrap();
+                        logger::set_request_id(previous);
                     }
-                    None => break,
+                    Some(NetworkRequest::Shutdown) | None => break,
                 },
                 swarm_event = swarm.next() => match swarm_event {
                     Some(event) => {
@@ -1,59 +1,102 @@
-use super::service::NetworkRequest;
-use crate::utils::{WasmModule, WasmModuleRef, WasmStore};
-use anyhow::Error;
+use super::service::{NetworkRequest, NetworkStatus};
+use crate::logger;
+use crate::metrics::METRICS;
+use crate::utils::expiry;
+use crate::utils::record::{decode_module, encode_module};
+use crate::utils::{ReadinessCheck, WasmModule, WasmModuleRef, WasmStore};
+use anyhow::{anyhow, Error};
 use async_std::future;
-use async_std::{sync::Sender, task};
+use async_std::sync::Sender;
 use futures::channel::oneshot;
 use libp2p::kad::record::Key;
-use serde_cbor::{from_slice, to_vec};
-use std::borrow::Cow;
-use std::time::Duration;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tide::utils::async_trait;
 
-/// Represents a sled db to load and store Wasm code.
-pub struct P2pStore(pub Sender<NetworkRequest>);
+/// Name of the sled tree caching the modules fetched from the DHT.
+pub const CACHE_TREE: &str = "dht_cache";
+
+/// Stores Wasm code in the DHT of the p2p network.
+pub struct P2pStore {
+    pub sender: Sender<NetworkRequest>,
+    pub status: Arc<NetworkStatus>,
+    /// Number of peers needed for the node to be ready.
+    pub min_peers: usize,
+}
+
+#[async_trait]
 impl WasmStore for P2pStore {
-    fn load_module(&self, name: &str) -> Result<WasmModule, Error> {
-        let bytes = task::block_on(async {
-            let (tx, rx) = oneshot::channel();
-            self.0
-                .send(NetworkRequest::GetDHTKey {
-                    request: Key::new(&name),
-                    response_channel: tx,
-                })
-                .await;
-            future::timeout(Duration::from_secs(3), rx).await
-        })??;
+    async fn load_module(&self, name: &str) -> Result<WasmModule, Error> {
+        let bytes = METRICS
+            .time_store_future("load_module", async {
+                let (tx, rx) = oneshot::channel();
+                self.sender
+                    .send(NetworkRequest::GetDHTKey {
+                        request: Key::new(&name),
+                        response_channel: tx,
+                        request_id: logger::request_id(),
+                    })
+                    .await;
+                future::timeout(Duration::from_secs(3), rx).await
+            })
+            .await??;
 
-        Ok(from_slice(bytes.as_ref())?)
+        Ok(decode_module(bytes.as_ref())?.0)
     }
-    fn contains_module(&self, name: &str) -> Result<bool, Error> {
-        task::block_on(async {
-            let (tx, rx) = oneshot::channel();
-            self.0
-                .send(NetworkRequest::GetDHTKey {
-                    request: Key::new(&name),
-                    response_channel: tx,
-                })
-                .await;
-            match future::timeout(Duration::from_secs(2), rx).await {
-                Err(_) => Ok(false),
-                Ok(Ok(_)) => Ok(true),
-                Ok(Err(e)) => Err(e.into()),
-            }
-        })
+    async fn contains_module(&self, name: &str) -> Result<bool, Error> {
+        METRICS
+            .time_store_future("contains_module", async {
+                let (tx, rx) = oneshot::channel();
+                self.sender
+                    .send(NetworkRequest::GetDHTKey {
+                        request: Key::new(&name),
+                        response_channel: tx,
+                        request_id: logger::request_id(),
+                    })
+                    .await;
+                match future::timeout(Duration::from_secs(2), rx).await {
+                    Err(_) => Ok(false),
+                    Ok(Ok(_)) => Ok(true),
+                    Ok(Err(e)) => Err(e.into()),
+                }
+            })
+            .await
     }
-    fn put_module(
-        &self,
-        name: &str,
-        code: &[u8],
-        host_modules: &[Cow<'_, str>],
-    ) -> Result<(), Error> {
-        let value = to_vec(&WasmModuleRef { code, host_modules })?;
+    async fn put_module(&self, name: &str, module: &WasmModuleRef<'_, '_>) -> Result<(), Error> {
+        let value = encode_module(module)?;
 
-        task::block_on(self.0.send(NetworkRequest::PutDHTKey {
-            key: Key::new(&name),
-            value,
-        }));
+        self.sender
+            .send(NetworkRequest::PutDHTKey {
+                key: Key::new(&name),
+                value,
+                expires: module.expires.map(|expires| {
+                    Instant::now() + Duration::from_millis(expires.saturating_sub(expiry::now()))
+                }),
+                request_id: logger::request_id(),
+            })
+            .await;
         Ok(())
     }
+    async fn readiness_checks(&self) -> Vec<ReadinessCheck> {
+        let running = if self.status.running.load(Ordering::SeqCst) {
+            Ok(())
+        } else {
+            Err(anyhow!("P2p service is not running"))
+        };
+        let peers = self.status.peers.load(Ordering::SeqCst);
+        let peers = if peers >= self.min_peers {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "Connected to {} peers, need at least {}",
+                peers,
+                self.min_peers
+            ))
+        };
+        vec![
+            ReadinessCheck::from_result("p2p_service", running),
+            ReadinessCheck::from_result("peers", peers),
+        ]
+    }
 }
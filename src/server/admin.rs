@@ -0,0 +1,90 @@
+use crate::auth::{ApiKeys, Authenticated};
+use crate::utils::expiry::{self, SweepReport};
+use crate::utils::limits::{StoreLimits, Usage, UsageCounters, UsageTotals};
+use crate::utils::WasmStore;
+use anyhow::Error;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tide::{Body, Response, StatusCode};
+
+/// Default seconds between sweeps of expired modules.
+pub const DEFAULT_SWEEP_INTERVAL: u64 = 60;
+
+/// Usage of the store, along with its limits.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+pub struct UsageReport {
+    pub limits: StoreLimits,
+    pub store: Usage,
+    /// Usage of each namespace with modules.
+    pub namespaces: BTreeMap<String, Usage>,
+    /// Usage of each API key which registered modules, by key id.
+    pub owners: BTreeMap<String, Usage>,
+}
+
+/// Returns the usage of the store from the usage counters. Usage is empty without counters.
+pub async fn usage<S>(req: tide::Request<Arc<S>>) -> tide::Result
+where
+    S: WasmStore,
+{
+    let totals = match req.ext::<Arc<UsageCounters>>() {
+        Some(counters) => counters.totals()?,
+        None => UsageTotals::default(),
+    };
+    let report = UsageReport {
+        limits: req
+            .ext::<Arc<StoreLimits>>()
+            .map(|l| l.as_ref().clone())
+            .unwrap_or_default(),
+        store: totals.store,
+        namespaces: totals.namespaces,
+        owners: totals.owners,
+    };
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&report)?)
+        .build())
+}
+
+/// Removes the expired modules no live module depends on, along with their owners, and subtracts
+/// them from the usage counters.
+pub async fn collect<S>(
+    store: &S,
+    keys: Option<&ApiKeys>,
+    counters: Option<&UsageCounters>,
+) -> Result<SweepReport, Error>
+where
+    S: WasmStore + ?Sized,
+{
+    let mut removed = Vec::new();
+    let report = expiry::sweep(store, expiry::now(), |name, module| {
+        removed.push((name.to_owned(), module.code.len() as u64))
+    })
+    .await?;
+    for (name, size) in removed {
+        let owner = match keys {
+            Some(keys) => keys.owner(&name)?,
+            None => None,
+        };
+        if let Some(counters) = counters {
+            counters.release(&name, size, owner.as_deref())?;
+        }
+        if let Some(keys) = keys {
+            keys.remove_owner(&name)?;
+        }
+    }
+    Ok(report)
+}
+
+/// Sweeps the expired modules, and returns what was collected.
+pub async fn gc<S>(req: tide::Request<Arc<S>>) -> tide::Result
+where
+    S: WasmStore,
+{
+    let keys = req.ext::<Authenticated>().map(|a| a.keys.as_ref());
+    let counters = req.ext::<Arc<UsageCounters>>().map(|c| c.as_ref());
+    let report = collect(req.state().as_ref(), keys, counters).await?;
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&report)?)
+        .build())
+}
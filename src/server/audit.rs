@@ -0,0 +1,14 @@
+use crate::audit::{AuditLog, AuditQuery};
+use std::sync::Arc;
+use tide::{Body, Response, StatusCode};
+
+/// Returns the audit entries matching the query parameters, newest first.
+pub async fn handle<S>(req: tide::Request<Arc<S>>) -> tide::Result {
+    let query: AuditQuery = req.query()?;
+    let log = req
+        .ext::<Arc<AuditLog>>()
+        .ok_or_else(|| tide::Error::from_str(StatusCode::NotFound, "Audit log is not enabled"))?;
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&log.query(&query)?)?)
+        .build())
+}
@@ -1,32 +1,101 @@
+use super::{execution_response, host_env, module_key, run_guest};
+use crate::metrics::METRICS;
+use crate::namespaces::Namespaces;
+use crate::utils::host::HostNamespace;
+use crate::utils::namespace::{namespace_of, resolve};
 use crate::utils::WasmStore;
-use crate::utils::{load_wasm_module_recursive, wasm};
+use crate::utils::{fetch_modules, instantiate_module, link_host_modules, wasm, wasm::Params};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use serde_json::Number;
 use std::borrow::Cow;
 use std::sync::Arc;
-use tide::{Body, Response, StatusCode};
+use std::time::Instant;
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Execution of a function of a registered module.
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+#[schemars(rename = "ExecuteRequest")]
 pub struct Request<'a> {
     pub module_name: Cow<'a, str>,
     pub function_name: Cow<'a, str>,
     #[serde(default)]
-    pub params: Vec<Number>,
+    pub params: Params,
+    /// Native host namespaces to make available to the module and its dependencies.
+    #[serde(default)]
+    pub host_functions: Vec<HostNamespace>,
+    /// Seed for the `random` host namespace.
+    #[serde(default)]
+    pub random_seed: Option<u64>,
+    /// Only commit writes to the `kv` host namespace if the call succeeds.
+    #[serde(default)]
+    pub kv_transactional: bool,
+    /// Return the timings of the execution with the result.
+    #[serde(default)]
+    pub trace: bool,
 }
 
 pub async fn handle<S>(mut req: tide::Request<Arc<S>>) -> tide::Result
 where
-    S: WasmStore,
+    S: WasmStore + Send + Sync + 'static,
 {
     let Request {
         module_name,
         function_name,
         params,
+        host_functions,
+        random_seed,
+        kv_transactional,
+        trace,
     } = req.body_json().await?;
-    let module = load_wasm_module_recursive(req.state().as_ref(), module_name.as_ref())?;
+    let store = req.state().as_ref();
+    let key = module_key(&req, &module_name)?;
+    let namespace = namespace_of(&key);
+
+    // The linked modules are fetched concurrently before the guest runs.
+    let started = Instant::now();
+    let mut module = store.load_module(&key).await?;
+    let host_keys = module
+        .host_modules
+        .iter()
+        .map(|m| resolve(m, namespace))
+        .collect();
+    let modules = fetch_modules(store, host_keys).await?;
+    let host = host_env(
+        req.state(),
+        namespace,
+        req.ext::<Arc<Namespaces>>().cloned(),
+        host_functions,
+        random_seed,
+        kv_transactional,
+        trace,
+    )
+    .await?;
+
+    run_guest(host.clone(), req.ext().cloned(), move || {
+        let namespace = namespace_of(&key);
+        let imports =
+            link_host_modules(&modules, &module.host_modules, &host, namespace, Some(&key))?;
+        if let Some(tracer) = host.tracer() {
+            tracer.record_load(started.elapsed());
+        }
+        let instance = instantiate_module(&module.code, &imports, &host, None)?;
+
+        // Parameter names registered with the module take precedence over the name section.
+        let param_names = match params {
+            Params::Named(_) => module.param_names.remove(function_name.as_ref()),
+            Params::Positional(_) => None,
+        };
 
-    let res = wasm::call_fn(&module, &function_name, params)?;
-    Ok(Response::builder(StatusCode::Ok)
-        .body(Body::from_json(&res)?)
-        .build())
+        let start = Instant::now();
+        let res = wasm::call_fn(&instance, &function_name, params, param_names.as_deref())
+            .map_err(|e| host.map_err(e));
+        if let Some(tracer) = host.tracer() {
+            tracer.record_call(start.elapsed());
+        }
+        METRICS.record_execution("registered", started.elapsed());
+        if res.is_ok() {
+            host.commit()?;
+        }
+        execution_response(res, &host)
+    })
+    .await
 }
@@ -0,0 +1,35 @@
+use crate::utils::{ReadinessCheck, WasmStore};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tide::{Body, Response, StatusCode};
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+pub struct Readiness {
+    pub ready: bool,
+    pub checks: Vec<ReadinessCheck>,
+}
+
+/// Returns OK while the process is up.
+pub async fn healthz<S>(_req: tide::Request<Arc<S>>) -> tide::Result {
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&serde_json::json!({ "status": "ok" }))?)
+        .build())
+}
+
+/// Runs the readiness checks of the store. Returns 503 if any check fails.
+pub async fn readyz<S>(req: tide::Request<Arc<S>>) -> tide::Result
+where
+    S: WasmStore,
+{
+    let checks = req.state().readiness_checks().await;
+    let ready = checks.iter().all(|c| c.ok);
+    let status = if ready {
+        StatusCode::Ok
+    } else {
+        StatusCode::ServiceUnavailable
+    };
+    Ok(Response::builder(status)
+        .body(Body::from_json(&Readiness { ready, checks })?)
+        .build())
+}
@@ -1,12 +1,19 @@
-use crate::utils::{load_wasm_module_recursive, wasm::execute_wasm, WasmStore};
+use super::{check_imports, execution_response, host_env, request_namespace, run_guest};
+use crate::metrics::METRICS;
+use crate::namespaces::Namespaces;
+use crate::utils::host::HostNamespace;
+use crate::utils::namespace::resolve;
+use crate::utils::{fetch_modules, link_host_modules, wasm::execute_wasm, WasmStore};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use serde_json::Number;
 use std::borrow::Cow;
 use std::sync::Arc;
-use tide::{Body, Response, StatusCode};
-use wasmer_runtime::ImportObject;
+use std::time::Instant;
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Execution of a function of a module given in the request.
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+#[schemars(rename = "RunRequest")]
 pub struct Request<'a> {
     pub wasm_hex: Cow<'a, str>,
     pub function_name: Cow<'a, str>,
@@ -14,32 +21,71 @@ pub struct Request<'a> {
     pub params: Vec<Number>,
     #[serde(default)]
     pub host_modules: Vec<Cow<'a, str>>,
+    /// Native host namespaces to make available to the guest.
+    #[serde(default)]
+    pub host_functions: Vec<HostNamespace>,
+    /// Seed for the `random` host namespace.
+    #[serde(default)]
+    pub random_seed: Option<u64>,
+    /// Only commit writes to the `kv` host namespace if the call succeeds.
+    #[serde(default)]
+    pub kv_transactional: bool,
+    /// Return the timings of the execution with the result.
+    #[serde(default)]
+    pub trace: bool,
 }
 
 pub async fn handle<S>(mut req: tide::Request<Arc<S>>) -> tide::Result
 where
-    S: WasmStore,
+    S: WasmStore + Send + Sync + 'static,
 {
     let Request {
         wasm_hex,
         function_name,
         params,
         host_modules,
+        host_functions,
+        random_seed,
+        kv_transactional,
+        trace,
     } = req.body_json().await?;
 
     let wasm_bytes = hex::decode(wasm_hex.as_ref())?;
+    let namespace = request_namespace(&req);
+    check_imports(&req, namespace, &host_modules)?;
 
-    // Import host functions
-    let mut imports = ImportObject::new();
-    for module in host_modules {
-        let import = load_wasm_module_recursive(req.state().as_ref(), module.as_ref())?;
-        imports.register(module, import);
-    }
+    // The linked modules are fetched concurrently before the guest runs.
+    let start = Instant::now();
+    let host_keys = host_modules.iter().map(|m| resolve(m, namespace)).collect();
+    let modules = fetch_modules(req.state().as_ref(), host_keys).await?;
+    let host = host_env(
+        req.state(),
+        namespace,
+        req.ext::<Arc<Namespaces>>().cloned(),
+        host_functions,
+        random_seed,
+        kv_transactional,
+        trace,
+    )
+    .await?;
+
+    let namespace = namespace.to_owned();
+    run_guest(host.clone(), req.ext().cloned(), move || {
+        // Import host functions
+        let imports = link_host_modules(&modules, &host_modules, &host, &namespace, None)?;
+        if let Some(tracer) = host.tracer() {
+            tracer.record_load(start.elapsed());
+        }
 
-    let res = execute_wasm(&wasm_bytes, &function_name, params, &imports)?;
-    Ok(Response::builder(StatusCode::Ok)
-        .body(Body::from_json(&res)?)
-        .build())
+        let res = execute_wasm(&wasm_bytes, &function_name, params.into(), &imports, &host)
+            .map_err(|e| host.map_err(e));
+        METRICS.record_execution("adhoc", start.elapsed());
+        if res.is_ok() {
+            host.commit()?;
+        }
+        execution_response(res, &host)
+    })
+    .await
 }
 
 #[cfg(test)]
@@ -52,13 +98,21 @@ mod tests {
             "wasm_hex": "0061736d0100000001060160017f017f030201000707010372756e00000a0601040020000b",
             "function_name": "run",
             "params": [2],
-            "host_modules": ["utils"]
+            "host_modules": ["utils"],
+            "host_functions": ["env", "random"],
+            "random_seed": 7,
+            "kv_transactional": true,
+            "trace": true
         }"#;
         let Request {
             wasm_hex,
             function_name,
             params,
             host_modules,
+            host_functions,
+            random_seed,
+            kv_transactional,
+            trace,
         } = serde_json::from_str(req_payload).unwrap();
         assert_eq!(
             wasm_hex,
@@ -67,5 +121,9 @@ mod tests {
         assert_eq!(function_name, "run");
         assert_eq!(params, [Number::from(2)]);
         assert_eq!(host_modules, ["utils"]);
+        assert_eq!(host_functions, [HostNamespace::Env, HostNamespace::Random]);
+        assert_eq!(random_seed, Some(7));
+        assert!(kv_transactional);
+        assert!(trace);
     }
 }
@@ -0,0 +1,266 @@
+use crate::auth::Authenticated;
+use crate::utils::limits::{StoreLimits, UsageCounters};
+use async_channel::{bounded, Receiver, Sender};
+use async_std::future::timeout;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tide::utils::async_trait;
+use tide::{Middleware, Next, Request, Response, StatusCode};
+
+/// Default number of requests a client can burst before being rate limited.
+pub const DEFAULT_BURST: u32 = 10;
+/// Default number of executions which can wait for a free execution slot.
+pub const DEFAULT_MAX_QUEUED: usize = 64;
+/// Maximum time an execution waits in the queue before being rejected.
+const QUEUE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Retry delay suggested when the execution queue is full, in seconds.
+const QUEUE_RETRY_AFTER: u64 = 1;
+/// Number of clients tracked before idle buckets are removed.
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+/// Request limits of the server.
+#[derive(Debug, Clone)]
+pub struct Limits {
+    /// Requests per second allowed for each API key, or client IP if authentication is disabled.
+    pub requests_per_second: Option<f64>,
+    /// Number of requests a client can make at once before being rate limited.
+    pub burst: u32,
+    /// Maximum number of guest executions running at once.
+    pub max_executions: Option<usize>,
+    /// Maximum number of executions waiting for a free slot, further executions are rejected.
+    pub max_queued: usize,
+    /// Limits on registered modules.
+    pub store: StoreLimits,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            requests_per_second: None,
+            burst: DEFAULT_BURST,
+            max_executions: None,
+            max_queued: DEFAULT_MAX_QUEUED,
+            store: StoreLimits::default(),
+        }
+    }
+}
+
+/// Middleware which makes the store limits available to the handlers.
+pub struct WithStoreLimits(pub Arc<StoreLimits>);
+
+#[async_trait]
+impl<State> Middleware<State> for WithStoreLimits
+where
+    State: Clone + Send + Sync + 'static,
+{
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        req.set_ext(self.0.clone());
+        Ok(next.run(req).await)
+    }
+}
+
+/// Middleware which makes the usage counters available to the handlers.
+pub struct WithUsageCounters(pub Arc<UsageCounters>);
+
+#[async_trait]
+impl<State> Middleware<State> for WithUsageCounters
+where
+    State: Clone + Send + Sync + 'static,
+{
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        req.set_ext(self.0.clone());
+        Ok(next.run(req).await)
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Middleware which rate limits each client with a token bucket. Requests over the limit get a
+/// 429 response with a `Retry-After` header.
+pub struct RateLimit {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimit {
+    pub fn new(requests_per_second: f64, burst: u32) -> Self {
+        Self {
+            rate: requests_per_second,
+            burst: f64::from(burst.max(1)),
+            buckets: Default::default(),
+        }
+    }
+
+    /// Takes a token for the client. Returns the seconds until a token is available if the
+    /// client is over the limit.
+    fn acquire(&self, client: &str) -> Result<(), u64> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED_CLIENTS {
+            // Clients with a full bucket are in the same state as untracked clients.
+            let (rate, burst) = (self.rate, self.burst);
+            buckets.retain(|_, b| {
+                b.tokens + now.duration_since(b.updated).as_secs_f64() * rate < burst
+            });
+        }
+
+        let bucket = buckets.entry(client.to_owned()).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - bucket.tokens) / self.rate).ceil().max(1.0) as u64)
+        }
+    }
+}
+
+/// Identifies the client of a request by its API key, or its IP if not authenticated.
+pub fn client_id<State>(req: &Request<State>) -> String {
+    if let Some(Authenticated { key, .. }) = req.ext() {
+        return format!("key:{}", key.id);
+    }
+    let addr = req.peer_addr().unwrap_or("unknown");
+    // Strip the port, so connections from the same host share the limit.
+    let ip = match addr.rfind(':') {
+        Some(i) if !addr.ends_with(']') => &addr[..i],
+        _ => addr,
+    };
+    format!("ip:{}", ip)
+}
+
+#[async_trait]
+impl<State> Middleware<State> for RateLimit
+where
+    State: Clone + Send + Sync + 'static,
+{
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        match self.acquire(&client_id(&req)) {
+            Ok(()) => Ok(next.run(req).await),
+            Err(retry_after) => Ok(Response::builder(StatusCode::TooManyRequests)
+                .header("Retry-After", retry_after.to_string())
+                .body("Rate limit exceeded")
+                .build()),
+        }
+    }
+}
+
+/// Middleware which caps the number of concurrent guest executions. Executions wait in a
+/// bounded queue for a free slot, and get a 503 response with a `Retry-After` header when the
+/// queue is full.
+#[derive(Clone)]
+pub struct ExecutionLimit {
+    slots: Receiver<()>,
+    release: Sender<()>,
+    queued: Arc<AtomicUsize>,
+    max_queued: usize,
+}
+
+impl ExecutionLimit {
+    pub fn new(max_executions: usize, max_queued: usize) -> Self {
+        let (release, slots) = bounded(max_executions.max(1));
+        for _ in 0..max_executions.max(1) {
+            release
+                .try_send(())
+                .expect("channel has capacity for all slots");
+        }
+        Self {
+            slots,
+            release,
+            queued: Default::default(),
+            max_queued,
+        }
+    }
+
+    /// Waits for a free execution slot. Returns `None` if the queue is full or the wait timed
+    /// out.
+    async fn acquire(&self) -> Option<ExecutionSlot> {
+        if self.slots.try_recv().is_ok() {
+            return Some(ExecutionSlot(self.release.clone()));
+        }
+        if self.queued.fetch_add(1, Ordering::SeqCst) >= self.max_queued {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        let slot = timeout(QUEUE_TIMEOUT, self.slots.recv()).await;
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+        match slot {
+            Ok(Ok(())) => Some(ExecutionSlot(self.release.clone())),
+            _ => None,
+        }
+    }
+}
+
+/// Execution slot, released when dropped. Set as a request extension by [`ExecutionLimit`], so
+/// the guest run can hold it until it returns, even if the client disconnects.
+pub struct ExecutionSlot(Sender<()>);
+
+impl Drop for ExecutionSlot {
+    fn drop(&mut self) {
+        let _ = self.0.try_send(());
+    }
+}
+
+#[async_trait]
+impl<State> Middleware<State> for ExecutionLimit
+where
+    State: Clone + Send + Sync + 'static,
+{
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        match self.acquire().await {
+            Some(slot) => {
+                req.set_ext(Arc::new(slot));
+                Ok(next.run(req).await)
+            }
+            None => Ok(Response::builder(StatusCode::ServiceUnavailable)
+                .header("Retry-After", QUEUE_RETRY_AFTER.to_string())
+                .body("Too many executions in progress")
+                .build()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_bucket() {
+        let limit = RateLimit::new(1.0, 2);
+        assert_eq!(limit.acquire("a"), Ok(()));
+        assert_eq!(limit.acquire("a"), Ok(()));
+        assert_eq!(limit.acquire("a"), Err(1));
+        // Clients have separate buckets
+        assert_eq!(limit.acquire("b"), Ok(()));
+    }
+
+    #[async_std::test]
+    async fn execution_queue() {
+        let limit = ExecutionLimit::new(1, 1);
+        let slot = limit.acquire().await.unwrap();
+
+        // One execution can wait in the queue, the next is rejected
+        let waiting = {
+            let limit = limit.clone();
+            async_std::task::spawn(async move { limit.acquire().await.is_some() })
+        };
+        async_std::task::sleep(Duration::from_millis(50)).await;
+        assert!(limit.acquire().await.is_none());
+
+        drop(slot);
+        assert!(waiting.await);
+        assert!(limit.acquire().await.is_some());
+    }
+}
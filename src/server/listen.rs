@@ -0,0 +1,83 @@
+use std::io;
+use tide::listener::ConcurrentListener;
+use tide_rustls::TlsListener;
+
+/// Certificate and private key files, in PEM format.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsFiles {
+    pub cert: String,
+    pub key: String,
+}
+
+/// Addresses the server listens on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Listen {
+    /// TCP addresses, as `host:port`.
+    pub addrs: Vec<String>,
+    /// If provided, all TCP listeners use TLS.
+    pub tls: Option<TlsFiles>,
+    /// Path of a Unix domain socket to listen on, alongside the TCP addresses.
+    pub unix_socket: Option<String>,
+}
+
+impl Default for Listen {
+    fn default() -> Self {
+        Self {
+            addrs: vec!["localhost:4000".to_owned()],
+            tls: None,
+            unix_socket: None,
+        }
+    }
+}
+
+impl Listen {
+    /// Creates a listener for all of the addresses.
+    pub fn listener<State>(&self) -> io::Result<ConcurrentListener<State>>
+    where
+        State: Clone + Send + Sync + 'static,
+    {
+        let mut listener = ConcurrentListener::new();
+        for addr in &self.addrs {
+            match &self.tls {
+                Some(TlsFiles { cert, key }) => listener.add(
+                    TlsListener::build()
+                        .addrs(addr.as_str())
+                        .cert(cert)
+                        .key(key)
+                        .finish()?,
+                )?,
+                None => listener.add(addr.as_str())?,
+            }
+        }
+        if let Some(path) = &self.unix_socket {
+            listener.add(unix_socket(path)?)?;
+        }
+        Ok(listener)
+    }
+}
+
+#[cfg(unix)]
+fn unix_socket(path: &str) -> io::Result<String> {
+    use std::os::unix::fs::FileTypeExt;
+
+    // Remove the socket left by a previous run, binding fails if the path exists.
+    match std::fs::metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path),
+            ))
+        }
+        Err(_) => {}
+    }
+    Ok(format!("http+unix://{}", path))
+}
+
+#[cfg(not(unix))]
+fn unix_socket(_path: &str) -> io::Result<String> {
+    Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "Unix domain sockets are only supported on unix platforms",
+    ))
+}
@@ -0,0 +1,16 @@
+use crate::metrics::METRICS;
+use crate::utils::WasmStore;
+use std::sync::Arc;
+use tide::{Response, StatusCode};
+
+/// Renders the metrics in the Prometheus text format.
+pub async fn handle<S>(req: tide::Request<Arc<S>>) -> tide::Result
+where
+    S: WasmStore,
+{
+    let stats = req.state().stats().await?;
+    Ok(Response::builder(StatusCode::Ok)
+        .content_type("text/plain; version=0.0.4")
+        .body(METRICS.render(stats))
+        .build())
+}
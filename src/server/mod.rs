@@ -1,31 +1,401 @@
+pub mod admin;
+pub mod audit;
 pub mod execute;
+pub mod health;
 pub mod index;
+pub mod limits;
+pub mod listen;
+pub mod metrics;
+pub mod modules;
+pub mod openapi;
 pub mod register;
+pub mod request_id;
+pub mod shutdown;
 
-use crate::utils::WasmStore;
+use crate::audit::{Audit, AuditLog};
+use crate::auth::{ApiKeys, Authenticate, Authenticated, LocalOnly};
+use crate::logger;
+use crate::metrics::RequestMetrics;
+use crate::namespaces::{Namespaces, WithNamespaces};
+use crate::utils::host::{HostEnv, HostNamespace, ModuleLoader};
+use crate::utils::limits::UsageCounters;
+use crate::utils::namespace::{self, namespace_of, resolve, DEFAULT_NAMESPACE};
+use crate::utils::trace::Trace;
+use crate::utils::{load_wasm_module_recursive, WasmStore};
+use async_std::future;
+use async_std::prelude::*;
+use async_std::task;
+use limits::{
+    ExecutionLimit, ExecutionSlot, Limits, RateLimit, WithStoreLimits, WithUsageCounters,
+};
+use listen::Listen;
+use request_id::RequestId;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use shutdown::{InFlight, DEFAULT_GRACE_PERIOD};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tide::utils::After;
-use tide::Response;
+use tide::{Body, Response, StatusCode};
+use wasmer_runtime::Value as WasmValue;
 
-/// Initialize database and start server.
-pub async fn start<S>(port: u16, store: Arc<S>) -> tide::Result<()>
+/// Execution response when host namespaces or tracing are enabled, which includes the guest
+/// logs and the trace.
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+pub struct ExecutionResponse {
+    #[schemars(with = "Vec<openapi::WasmValue>")]
+    pub result: Vec<WasmValue>,
+    pub logs: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trace: Option<Trace>,
+}
+
+/// Error response of a failed execution when host namespaces or tracing are enabled, with the guest
+/// logs and the trace up to the failure.
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+pub struct ExecutionFailure {
+    pub error: String,
+    pub logs: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trace: Option<Trace>,
+}
+
+/// Namespace the API key of the request is limited to, if any.
+fn key_namespace<S>(req: &tide::Request<S>) -> Option<&str> {
+    req.ext::<Authenticated>()
+        .and_then(|auth| auth.key.namespace.as_deref())
+}
+
+/// Namespace of the modules a request refers to without a namespace.
+fn request_namespace<S>(req: &tide::Request<S>) -> &str {
+    key_namespace(req).unwrap_or(DEFAULT_NAMESPACE)
+}
+
+/// Resolves a module reference of a request to its store key. References are resolved in the
+/// namespace of the API key, which can't refer to modules of other namespaces.
+pub(crate) fn module_key<S>(req: &tide::Request<S>, reference: &str) -> tide::Result<String> {
+    namespace::validate_reference(reference)
+        .map_err(|e| tide::Error::new(StatusCode::BadRequest, e))?;
+    let key = resolve(reference, request_namespace(req));
+    match key_namespace(req) {
+        Some(limit) if namespace_of(&key) != limit => Err(tide::Error::from_str(
+            StatusCode::Forbidden,
+            format!("API key is limited to namespace {}", limit),
+        )),
+        _ => Ok(key),
+    }
+}
+
+/// Checks that modules of `from` may import the module at the store key. Without namespace
+/// settings, only imports within a namespace are allowed.
+fn check_import(
+    namespaces: Option<&Namespaces>,
+    from: &str,
+    key: &str,
+) -> Result<(), anyhow::Error> {
+    match namespaces {
+        Some(namespaces) => namespaces.check_import(from, key),
+        None if namespace_of(key) == from => Ok(()),
+        None => Err(anyhow::anyhow!(
+            "Imports from other namespaces are not enabled"
+        )),
+    }
+}
+
+/// Checks that the host modules of a request are valid references, which modules of the
+/// namespace may import.
+fn check_imports<S, N>(req: &tide::Request<S>, from: &str, host_modules: &[N]) -> tide::Result<()>
+where
+    N: AsRef<str>,
+{
+    let namespaces = req.ext::<Arc<Namespaces>>().map(Arc::as_ref);
+    for reference in host_modules {
+        namespace::validate_reference(reference.as_ref())
+            .map_err(|e| tide::Error::new(StatusCode::BadRequest, e))?;
+        check_import(namespaces, from, &resolve(reference.as_ref(), from))
+            .map_err(|e| tide::Error::new(StatusCode::Forbidden, e))?;
+    }
+    Ok(())
+}
+
+/// Creates the host environment for a request, with the guest storage of the store if the `kv`
+/// namespace is enabled, and loading modules from the store for the `modules` namespace. Modules
+/// called through the `modules` namespace are resolved in the `namespace`. Guest calls are
+/// synchronous, so these modules are fetched by blocking the call, which the handlers run on the
+/// blocking thread pool.
+async fn host_env<S>(
+    store: &Arc<S>,
+    namespace: &str,
+    namespaces: Option<Arc<Namespaces>>,
+    host_functions: Vec<HostNamespace>,
+    random_seed: Option<u64>,
+    kv_transactional: bool,
+    trace: bool,
+) -> Result<HostEnv, anyhow::Error>
+where
+    S: WasmStore + Send + Sync + 'static,
+{
+    let kv = if host_functions.contains(&HostNamespace::Kv) {
+        store.guest_kv(kv_transactional).await?
+    } else {
+        None
+    };
+    let modules = host_functions.contains(&HostNamespace::Modules);
+
+    let mut host = HostEnv::new(host_functions, random_seed);
+    if let Some(kv) = kv {
+        host = host.with_kv(kv);
+    }
+    if modules {
+        let store = store.clone();
+        let namespace = namespace.to_owned();
+        let loader: ModuleLoader = Arc::new(move |name: &str, host: &HostEnv| {
+            namespace::validate_reference(name)?;
+            let key = resolve(name, &namespace);
+            check_import(namespaces.as_deref(), &namespace, &key)?;
+            task::block_on(load_wasm_module_recursive(store.as_ref(), &key, host))
+        });
+        host = host.with_modules(loader);
+    }
+    if trace {
+        host = host.with_tracer();
+    }
+    Ok(host)
+}
+
+/// Builds the response for an execution. The result values are returned directly, unless host
+/// namespaces or tracing are enabled for the request. Failed executions return the error, along
+/// with the logs and the trace if they are enabled.
+fn execution_response(
+    result: Result<Vec<WasmValue>, anyhow::Error>,
+    host: &HostEnv,
+) -> tide::Result {
+    let trace = host.tracer().map(|t| t.finish());
+    if host.is_empty() && trace.is_none() {
+        return Ok(Response::builder(StatusCode::Ok)
+            .body(Body::from_json(&result?)?)
+            .build());
+    }
+    match result {
+        Ok(result) => Ok(Response::builder(StatusCode::Ok)
+            .body(Body::from_json(&ExecutionResponse {
+                result,
+                logs: host.logs(),
+                trace,
+            })?)
+            .build()),
+        Err(e) => {
+            let status = StatusCode::InternalServerError;
+            let mut res = Response::builder(status)
+                .body(Body::from_json(&ExecutionFailure {
+                    error: e.to_string(),
+                    logs: host.logs(),
+                    trace,
+                })?)
+                .build();
+            // The error is set for the audit log, the body is kept.
+            res.set_error(tide::Error::new(status, e));
+            Ok(res)
+        }
+    }
+}
+
+/// Runs a guest execution on the blocking thread pool, as guest calls are synchronous and may
+/// block on loading modules. Once the deadline of the `host` environment passes, the execution
+/// fails without waiting for the guest, which can't be interrupted and is left running. The
+/// execution `slot` is held until the guest returns.
+async fn run_guest<F>(host: HostEnv, slot: Option<Arc<ExecutionSlot>>, run: F) -> tide::Result
+where
+    F: FnOnce() -> tide::Result + Send + 'static,
+{
+    let mut guest = logger::spawn_blocking(move || {
+        let _slot = slot;
+        run()
+    });
+    let deadline = match host.deadline() {
+        Some(deadline) => deadline,
+        None => return guest.await,
+    };
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    if let Ok(res) = future::timeout(remaining, &mut guest).await {
+        return res;
+    }
+    match host.time_out() {
+        Some(e) => execution_response(Err(e), &host),
+        // The guest returned and committed its writes as the deadline passed.
+        None => guest.await,
+    }
+}
+
+/// Options of the server.
+pub struct Options {
+    /// If provided, requests need an API key with the scope for the route.
+    pub auth: Option<Arc<ApiKeys>>,
+    pub limits: Limits,
+    /// If provided, registrations, deletions and executions are recorded.
+    pub audit: Option<Arc<AuditLog>>,
+    /// Grants and quotas of namespaces. If not provided, modules can only import modules of
+    /// their own namespace and there are no quotas.
+    pub namespaces: Option<Arc<Namespaces>>,
+    /// Running usage the store, owner and namespace quotas are checked against. If not provided,
+    /// these quotas aren't enforced and the usage report is empty.
+    pub usage: Option<Arc<UsageCounters>>,
+    /// Time to wait for in-flight requests when shutting down.
+    pub grace_period: Duration,
+    /// If provided, expired modules are swept at this interval while the server runs.
+    pub sweep_interval: Option<Duration>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            auth: None,
+            limits: Limits::default(),
+            audit: None,
+            namespaces: None,
+            usage: None,
+            grace_period: Duration::from_secs(DEFAULT_GRACE_PERIOD),
+            sweep_interval: None,
+        }
+    }
+}
+
+/// Creates the server with all routes.
+pub fn app<S>(store: Arc<S>, options: Options) -> tide::Server<Arc<S>>
 where
     S: WasmStore + Send + Sync + 'static,
 {
     let mut app = tide::with_state(store);
 
+    app.with(RequestId);
+    app.with(RequestMetrics);
     app.with(After(|mut res: Response| async {
         // ! You may want to remove this error message, only helpful for debugging
         if let Some(s) = res.error().map(|e| e.to_string()) {
-            res.set_body(s);
+            if res.is_empty().unwrap_or(true) {
+                res.set_body(s);
+            }
         }
         Ok(res)
     }));
+    match options.auth {
+        Some(keys) => app.with(Authenticate(keys)),
+        None => app.with(LocalOnly),
+    };
+    // Rate limited after authentication, so clients are identified by their key.
+    if let Some(rate) = options.limits.requests_per_second {
+        app.with(RateLimit::new(rate, options.limits.burst));
+    }
+
+    if let Some(log) = options.audit {
+        app.with(Audit(log));
+    }
+    if let Some(namespaces) = options.namespaces {
+        app.with(WithNamespaces(namespaces));
+    }
+    app.with(WithStoreLimits(Arc::new(options.limits.store.clone())));
+    if let Some(usage) = options.usage {
+        app.with(WithUsageCounters(usage));
+    }
 
-    app.at("/").post(index::handle);
+    match options.limits.max_executions {
+        Some(max) => {
+            let limit = ExecutionLimit::new(max, options.limits.max_queued);
+            app.at("/").with(limit.clone()).post(index::handle);
+            app.at("/execute").with(limit).post(execute::handle);
+        }
+        None => {
+            app.at("/").post(index::handle);
+            app.at("/execute").post(execute::handle);
+        }
+    }
     app.at("/register").post(register::handle);
-    app.at("/execute").post(execute::handle);
-    app.listen(format!("localhost:{}", port)).await?;
+    app.at("/modules").get(modules::list);
+    app.at("/modules/:name")
+        .get(modules::inspect)
+        .delete(modules::delete);
+    app.at("/modules/:namespace/:name")
+        .get(modules::inspect)
+        .delete(modules::delete);
+    app.at("/metrics").get(metrics::handle);
+    app.at("/audit").get(audit::handle);
+    app.at("/admin/usage").get(admin::usage);
+    app.at("/admin/gc").post(admin::gc);
+    app.at("/healthz").get(health::healthz);
+    app.at("/readyz").get(health::readyz);
+    app.at("/openapi.json").get(openapi::handle);
+    app
+}
+
+/// Removes the expired modules at every interval.
+async fn sweep_expired<S>(
+    store: Arc<S>,
+    keys: Option<Arc<ApiKeys>>,
+    usage: Option<Arc<UsageCounters>>,
+    interval: Duration,
+) where
+    S: WasmStore,
+{
+    loop {
+        task::sleep(interval).await;
+        match admin::collect(store.as_ref(), keys.as_deref(), usage.as_deref()).await {
+            Ok(report) if !report.collected.is_empty() => log::info!(
+                "Collected {} expired modules: {}",
+                report.collected.len(),
+                report.collected.join(", ")
+            ),
+            Ok(_) => {}
+            Err(e) => log::warn!("Could not sweep expired modules: {}", e),
+        }
+    }
+}
+
+/// Initialize database and start server on the listen addresses.
+///
+/// When `shutdown` resolves, the server stops accepting connections and waits up to the grace
+/// period for in-flight requests.
+pub async fn start<S, F>(
+    listen: &Listen,
+    store: Arc<S>,
+    options: Options,
+    shutdown: F,
+) -> tide::Result<()>
+where
+    S: WasmStore + Send + Sync + 'static,
+    F: Future<Output = ()>,
+{
+    let grace_period = options.grace_period;
+    let sweeper = options.sweep_interval.map(|interval| {
+        let keys = options.auth.clone();
+        let usage = options.usage.clone();
+        task::spawn(sweep_expired(store.clone(), keys, usage, interval))
+    });
+    let in_flight = InFlight::default();
+    let mut app = app(store, options);
+    app.with(in_flight.clone());
+
+    // Dropping the listener future stops accepting connections, open connections are handled
+    // by their own tasks.
+    let listener = listen.listener()?;
+    let serve = async { app.listen(listener).await.map(|_| false) };
+    let stop = async {
+        shutdown.await;
+        Ok(true)
+    };
+    let stopped = serve.race(stop).await?;
+    if stopped {
+        log::info!(
+            "Shutting down, waiting for {} requests in flight",
+            in_flight.count()
+        );
+        let remaining = in_flight.drain(grace_period).await;
+        if remaining > 0 {
+            log::warn!("Grace period elapsed with {} requests in flight", remaining);
+        }
+    }
+    if let Some(sweeper) = sweeper {
+        sweeper.cancel().await;
+    }
 
     Ok(())
 }
@@ -34,16 +404,18 @@ where
 mod tests {
     use super::*;
     use crate::local_db::LocalDB;
+    use crate::utils::limits::StoreLimits;
+    use crate::utils::record::{decode_module, encode_module};
     use crate::utils::*;
-    use async_std::prelude::*;
-    use async_std::task;
-    use serde_cbor::{from_slice, to_vec};
-    use std::time::Duration;
-    use wasmer_runtime::Value as WasmValue;
+    use async_std::{future, task};
+    use sha2::Digest;
+    use std::sync::atomic::{AtomicUsize, Ordering};
 
     #[async_std::test]
     async fn full_usage_path() {
-        let db = Arc::new(LocalDB(sled::Config::new().temporary(true).open().unwrap()));
+        let db = Arc::new(Blocking::new(LocalDB(
+            sled::Config::new().temporary(true).open().unwrap(),
+        )));
 
         let utils_code = include_bytes!("../../utils.wasm");
         let hex_utils = hex::encode(utils_code.as_ref());
@@ -51,6 +423,9 @@ mod tests {
         let linking_code = include_bytes!("../../linking.wasm");
         let hex_linking = hex::encode(linking_code.as_ref());
 
+        let named_code = include_bytes!("../../named.wasm");
+        let hex_named = hex::encode(named_code.as_ref());
+
         const UTILS: &str = "utils";
 
         let port = portpicker::pick_unused_port().unwrap();
@@ -74,6 +449,10 @@ mod tests {
                     function_name: "double".into(),
                     params: vec![2i32.into()],
                     host_modules: Vec::new(),
+                    host_functions: Vec::new(),
+                    random_seed: None,
+                    kv_transactional: false,
+                    trace: false,
                 })?)
                 .await?;
             assert_eq!(res.status(), http_types::StatusCode::Ok);
@@ -87,6 +466,12 @@ mod tests {
                     module_name: UTILS.into(),
                     wasm_hex: hex_utils.as_str().into(),
                     host_modules: Vec::new(),
+                    param_names: [("double".to_owned(), vec!["value".to_owned()])]
+                        .iter()
+                        .cloned()
+                        .collect(),
+                    metadata: ModuleMetadata::default(),
+                    ttl: None,
                 })?)
                 .await?;
             assert_eq!(res.status(), http_types::StatusCode::Ok);
@@ -97,13 +482,67 @@ mod tests {
                 .body(http_types::Body::from_json(&execute::Request {
                     module_name: UTILS.into(),
                     function_name: "double".into(),
-                    params: vec![2i32.into()],
+                    params: vec![2i32.into()].into(),
+                    host_functions: Vec::new(),
+                    random_seed: None,
+                    kv_transactional: false,
+                    trace: false,
                 })?)
                 .await?;
             assert_eq!(res.status(), http_types::StatusCode::Ok);
             let value: [WasmValue; 1] = res.body_json().await.unwrap();
             assert_eq!(value, [WasmValue::I32(4)]);
 
+            // Execute registered function with param names registered with the module
+            let uri = format!("http://localhost:{}/execute", port);
+            let mut res = surf::post(uri)
+                .body(http_types::Body::from_json(&serde_json::json!({
+                    "module_name": UTILS,
+                    "function_name": "double",
+                    "params": { "value": 3 },
+                }))?)
+                .await?;
+            assert_eq!(res.status(), http_types::StatusCode::Ok);
+            let value: [WasmValue; 1] = res.body_json().await.unwrap();
+            assert_eq!(value, [WasmValue::I32(6)]);
+
+            // Register and execute module with param names from the name section
+            let uri = format!("http://localhost:{}/register", port);
+            let res = surf::post(uri)
+                .body(http_types::Body::from_json(&register::Request {
+                    module_name: "named".into(),
+                    wasm_hex: hex_named.as_str().into(),
+                    host_modules: Vec::new(),
+                    param_names: Default::default(),
+                    metadata: ModuleMetadata::default(),
+                    ttl: None,
+                })?)
+                .await?;
+            assert_eq!(res.status(), http_types::StatusCode::Ok);
+
+            let uri = format!("http://localhost:{}/execute", port);
+            let mut res = surf::post(uri)
+                .body(http_types::Body::from_json(&serde_json::json!({
+                    "module_name": "named",
+                    "function_name": "sub",
+                    "params": { "rhs": 3, "lhs": 10 },
+                }))?)
+                .await?;
+            assert_eq!(res.status(), http_types::StatusCode::Ok);
+            let value: [WasmValue; 1] = res.body_json().await.unwrap();
+            assert_eq!(value, [WasmValue::I32(7)]);
+
+            // Unknown param names are rejected
+            let uri = format!("http://localhost:{}/execute", port);
+            let res = surf::post(uri)
+                .body(http_types::Body::from_json(&serde_json::json!({
+                    "module_name": "named",
+                    "function_name": "sub",
+                    "params": { "lhs": 10, "other": 3 },
+                }))?)
+                .await?;
+            assert_eq!(res.status(), http_types::StatusCode::InternalServerError);
+
             // Send execute request with code linking to registered function
             let uri = format!("http://localhost:{}", port);
             let mut res = surf::post(uri)
@@ -112,12 +551,583 @@ mod tests {
                     function_name: "double_twice".into(),
                     params: vec![2i32.into()],
                     host_modules: vec!["utils".into()],
+                    host_functions: Vec::new(),
+                    random_seed: None,
+                    kv_transactional: false,
+                    trace: false,
                 })?)
                 .await?;
             assert_eq!(res.status(), http_types::StatusCode::Ok);
             let value: [WasmValue; 1] = res.body_json().await.unwrap();
             assert_eq!(value, [WasmValue::I32(8)]);
 
+            // Trace counts the calls into the linked module
+            let uri = format!("http://localhost:{}", port);
+            let mut res = surf::post(uri)
+                .body(http_types::Body::from_json(&serde_json::json!({
+                    "wasm_hex": hex_linking,
+                    "function_name": "double_twice",
+                    "params": [2],
+                    "host_modules": ["utils"],
+                    "trace": true,
+                }))?)
+                .await?;
+            assert_eq!(res.status(), http_types::StatusCode::Ok);
+            let ExecutionResponse { result, trace, .. } = res.body_json().await.unwrap();
+            assert_eq!(result, [WasmValue::I32(8)]);
+            let trace = trace.unwrap();
+            assert_eq!(trace.host_module_calls, 2);
+            assert_eq!(trace.modules["utils"].calls, 2);
+
+            // Trace and logs are returned when the call traps
+            let uri = format!("http://localhost:{}", port);
+            let mut res = surf::post(uri)
+                .body(http_types::Body::from_json(&serde_json::json!({
+                    "wasm_hex": hex::encode(include_bytes!("../../host.wasm").as_ref()),
+                    "function_name": "fail",
+                    "params": [],
+                    "host_functions": ["env", "random"],
+                    "trace": true,
+                }))?)
+                .await?;
+            assert_eq!(res.status(), http_types::StatusCode::InternalServerError);
+            let ExecutionFailure { error, trace, .. } = res.body_json().await.unwrap();
+            assert_eq!(error, "Guest panicked: oh no");
+            let trace = trace.unwrap();
+            assert!(trace.compile_us > 0);
+            assert!(trace.total_us >= trace.call_us);
+
+            Ok(())
+        });
+
+        server.race(client).await.unwrap();
+    }
+
+    #[async_std::test]
+    async fn api_keys() {
+        use crate::auth::Scope;
+        use http_types::{Method, Request, StatusCode, Url};
+
+        let sled = sled::Config::new().temporary(true).open().unwrap();
+        let keys = Arc::new(ApiKeys::new(&sled).unwrap());
+        let app = app(
+            Arc::new(Blocking::new(LocalDB(sled))),
+            Options {
+                auth: Some(keys.clone()),
+                ..Default::default()
+            },
+        );
+
+        let (owner, owner_key) = keys.create("owner", vec![Scope::Register], None).unwrap();
+        let (other, _) = keys
+            .create("other", vec![Scope::Register, Scope::ExecuteAdhoc], None)
+            .unwrap();
+        let (admin, _) = keys.create("admin", vec![Scope::Admin], None).unwrap();
+
+        let request = |method, path: &str, key: Option<&str>, body: serde_json::Value| {
+            let url = Url::parse("http://localhost").unwrap().join(path).unwrap();
+            let mut req = Request::new(method, url);
+            if let Some(key) = key {
+                req.insert_header("Authorization", format!("Bearer {}", key));
+            }
+            req.set_body(http_types::Body::from_json(&body).unwrap());
+            req
+        };
+        let register = |name: &str, host_modules: &[&str]| {
+            let code: &[u8] = if host_modules.is_empty() {
+                include_bytes!("../../utils.wasm")
+            } else {
+                include_bytes!("../../linking.wasm")
+            };
+            serde_json::json!({
+                "module_name": name,
+                "wasm_hex": hex::encode(code),
+                "host_modules": host_modules,
+            })
+        };
+        let null = serde_json::Value::Null;
+
+        // Health checks don't need a key
+        let mut res: http_types::Response = app
+            .respond(request(Method::Get, "/readyz", None, null.clone()))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let readiness: health::Readiness = res.body_json().await.unwrap();
+        assert!(readiness.ready);
+        assert_eq!(readiness.checks[0].name, "database");
+
+        // Missing, invalid and insufficient keys are rejected
+        let res: http_types::Response = app
+            .respond(request(
+                Method::Post,
+                "/register",
+                None,
+                register("utils", &[]),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+        let res: http_types::Response = app
+            .respond(request(
+                Method::Post,
+                "/register",
+                Some("wea_invalid"),
+                register("utils", &[]),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+        let res: http_types::Response = app
+            .respond(request(
+                Method::Post,
+                "/execute",
+                Some(&owner),
+                null.clone(),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Forbidden);
+
+        // Registering records the key as the owner
+        let res: http_types::Response = app
+            .respond(request(
+                Method::Post,
+                "/register",
+                Some(&owner),
+                register("utils", &[]),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(keys.owner("utils").unwrap(), Some(owner_key.id));
+        let res: http_types::Response = app
+            .respond(request(
+                Method::Post,
+                "/register",
+                Some(&other),
+                register("linking", &["utils"]),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+
+        // Only the owner or an admin can delete, and not while other modules depend on it
+        let res: http_types::Response = app
+            .respond(request(
+                Method::Delete,
+                "/modules/utils",
+                Some(&other),
+                null.clone(),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Forbidden);
+        let res: http_types::Response = app
+            .respond(request(
+                Method::Delete,
+                "/modules/utils",
+                Some(&owner),
+                null.clone(),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::InternalServerError);
+        let res: http_types::Response = app
+            .respond(request(
+                Method::Delete,
+                "/modules/linking",
+                Some(&admin),
+                null.clone(),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let res: http_types::Response = app
+            .respond(request(
+                Method::Delete,
+                "/modules/utils",
+                Some(&owner),
+                null.clone(),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(keys.owner("utils").unwrap(), None);
+
+        // Revoked keys are rejected
+        let owner_id = keys.authenticate(&owner).unwrap().unwrap().id;
+        assert!(keys.revoke(&owner_id).unwrap());
+        let res: http_types::Response = app
+            .respond(request(
+                Method::Post,
+                "/register",
+                Some(&owner),
+                register("utils", &[]),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+    }
+
+    #[async_std::test]
+    async fn local_only() {
+        use http_types::{Method, Request, StatusCode, Url};
+
+        let db = Blocking::new(LocalDB(sled::Config::new().temporary(true).open().unwrap()));
+        let app = app(Arc::new(db), Options::default());
+        let send = |method, path: &str, peer_addr: Option<&str>| {
+            let url = Url::parse("http://localhost").unwrap().join(path).unwrap();
+            let mut req = Request::new(method, url);
+            req.set_peer_addr(peer_addr);
+            let app = app.clone();
+            async move {
+                let res: http_types::Response = app.respond(req).await.unwrap();
+                res.status()
+            }
+        };
+
+        // Without authentication, only local clients can delete modules and run sweeps
+        let remote = Some("203.0.113.7:4000");
+        let status = send(Method::Delete, "/modules/utils", remote).await;
+        assert_eq!(status, StatusCode::Forbidden);
+        let status = send(Method::Post, "/admin/gc", remote).await;
+        assert_eq!(status, StatusCode::Forbidden);
+        let status = send(Method::Get, "/modules/utils", remote).await;
+        assert_ne!(status, StatusCode::Forbidden);
+        for local in [None, Some("127.0.0.1:4000"), Some("[::1]:4000")].iter() {
+            let status = send(Method::Delete, "/modules/utils", *local).await;
+            assert_ne!(status, StatusCode::Forbidden);
+            let status = send(Method::Post, "/admin/gc", *local).await;
+            assert_eq!(status, StatusCode::Ok);
+        }
+    }
+
+    #[async_std::test]
+    async fn namespaces() {
+        use crate::auth::Scope;
+        use crate::namespaces::NamespaceConfig;
+        use http_types::{Method, Request, StatusCode, Url};
+
+        let sled = sled::Config::new().temporary(true).open().unwrap();
+        let keys = Arc::new(ApiKeys::new(&sled).unwrap());
+        let namespaces = Arc::new(Namespaces::new(&sled).unwrap());
+        let usage = Arc::new(UsageCounters::new(&sled).unwrap());
+        let app = app(
+            Arc::new(Blocking::new(LocalDB(sled))),
+            Options {
+                auth: Some(keys.clone()),
+                namespaces: Some(namespaces.clone()),
+                usage: Some(usage),
+                ..Default::default()
+            },
+        );
+
+        let scopes = vec![
+            Scope::Register,
+            Scope::ExecuteAdhoc,
+            Scope::ExecuteRegistered,
+        ];
+        let (team_a, _) = keys.create("a", scopes.clone(), Some("team-a")).unwrap();
+        let (team_b, _) = keys.create("b", scopes, Some("team-b")).unwrap();
+        let (admin, _) = keys.create("admin", vec![Scope::Admin], None).unwrap();
+
+        let send = |method, path: &str, key: &str, body: serde_json::Value| {
+            let url = Url::parse("http://localhost").unwrap().join(path).unwrap();
+            let mut req = Request::new(method, url);
+            req.insert_header("Authorization", format!("Bearer {}", key));
+            req.set_body(http_types::Body::from_json(&body).unwrap());
+            let app = app.clone();
+            async move {
+                let res: http_types::Response = app.respond(req).await.unwrap();
+                res
+            }
+        };
+        let register = |name: &str, host_modules: &[&str]| {
+            serde_json::json!({
+                "module_name": name,
+                "wasm_hex": hex::encode(include_bytes!("../../utils.wasm").as_ref()),
+                "host_modules": host_modules,
+            })
+        };
+        let null = serde_json::Value::Null;
+
+        // Both namespaces can register a module with the same name
+        let res = send(Method::Post, "/register", &team_a, register("utils", &[])).await;
+        assert_eq!(res.status(), StatusCode::Ok);
+        let res = send(Method::Post, "/register", &team_b, register("utils", &[])).await;
+        assert_eq!(res.status(), StatusCode::Ok);
+        let mut res = send(Method::Get, "/modules", &team_b, null.clone()).await;
+        let modules: Vec<modules::ModuleSummary> = res.body_json().await.unwrap();
+        assert_eq!(modules.len(), 1);
+        assert_eq!(modules[0].name, "team-b/utils");
+        let res = send(Method::Get, "/modules/team-b/utils", &admin, null.clone()).await;
+        assert_eq!(res.status(), StatusCode::Ok);
+
+        // Keys can't refer to modules of other namespaces
+        let res = send(Method::Get, "/modules/team-a/utils", &team_b, null.clone()).await;
+        assert_eq!(res.status(), StatusCode::Forbidden);
+
+        // Modules run with host modules of their own namespace
+        let linking = serde_json::json!({
+            "wasm_hex": hex::encode(include_bytes!("../../linking.wasm").as_ref()),
+            "function_name": "double_twice",
+            "params": [2],
+            "host_modules": ["utils"],
+        });
+        let mut res = send(Method::Post, "/", &team_a, linking).await;
+        assert_eq!(res.status(), StatusCode::Ok);
+        let value: [WasmValue; 1] = res.body_json().await.unwrap();
+        assert_eq!(value, [WasmValue::I32(8)]);
+
+        // Imports from other namespaces have to be granted
+        let import = register("import", &["team-a/utils"]);
+        let res = send(Method::Post, "/register", &team_b, import.clone()).await;
+        assert_eq!(res.status(), StatusCode::Forbidden);
+        namespaces.grant("team-a", "team-b").unwrap();
+        let res = send(Method::Post, "/register", &team_b, import).await;
+        assert_eq!(res.status(), StatusCode::Ok);
+
+        // Registrations over the quota are rejected
+        namespaces
+            .set(
+                "team-a",
+                &NamespaceConfig {
+                    max_modules: Some(1),
+                    ..namespaces.get("team-a").unwrap()
+                },
+            )
+            .unwrap();
+        let res = send(Method::Post, "/register", &team_a, register("other", &[])).await;
+        assert_eq!(res.status(), StatusCode::InsufficientStorage);
+    }
+
+    #[async_std::test]
+    async fn store_limits() {
+        use admin::UsageReport;
+        use http_types::{Method, Request, StatusCode, Url};
+
+        let code = include_bytes!("../../utils.wasm");
+        let sled = sled::Config::new().temporary(true).open().unwrap();
+        let usage = Arc::new(UsageCounters::new(&sled).unwrap());
+        let app = app(
+            Arc::new(Blocking::new(LocalDB(sled))),
+            Options {
+                usage: Some(usage),
+                limits: Limits {
+                    store: StoreLimits {
+                        max_module_size: Some(code.len() as u64),
+                        max_host_modules: Some(1),
+                        max_store_size: Some(code.len() as u64 * 2),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        );
+        let send = |method, path: &str, body: serde_json::Value| {
+            let url = Url::parse("http://localhost").unwrap().join(path).unwrap();
+            let mut req = Request::new(method, url);
+            req.set_body(http_types::Body::from_json(&body).unwrap());
+            let app = app.clone();
+            async move {
+                let res: http_types::Response = app.respond(req).await.unwrap();
+                res
+            }
+        };
+        let register = |name: &str, code: &[u8], host_modules: &[&str]| {
+            serde_json::json!({
+                "module_name": name,
+                "wasm_hex": hex::encode(code),
+                "host_modules": host_modules,
+            })
+        };
+
+        let mut large = code.to_vec();
+        large.push(0);
+        let res = send(Method::Post, "/register", register("large", &large, &[])).await;
+        assert_eq!(res.status(), StatusCode::PayloadTooLarge);
+        let res = send(
+            Method::Post,
+            "/register",
+            register("deps", code, &["a", "b"]),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::PayloadTooLarge);
+
+        for name in &["a", "b"] {
+            let res = send(Method::Post, "/register", register(name, code, &[])).await;
+            assert_eq!(res.status(), StatusCode::Ok);
+        }
+        let res = send(Method::Post, "/register", register("c", code, &[])).await;
+        assert_eq!(res.status(), StatusCode::InsufficientStorage);
+
+        // Deleted modules no longer count towards the quota
+        let res = send(Method::Delete, "/modules/b", serde_json::Value::Null).await;
+        assert_eq!(res.status(), StatusCode::Ok);
+        let res = send(Method::Post, "/register", register("c", code, &[])).await;
+        assert_eq!(res.status(), StatusCode::Ok);
+
+        let mut res = send(Method::Get, "/admin/usage", serde_json::Value::Null).await;
+        let report: UsageReport = res.body_json().await.unwrap();
+        assert_eq!(report.store.modules, 2);
+        assert_eq!(report.store.bytes, code.len() as u64 * 2);
+        assert_eq!(report.namespaces["default"].modules, 2);
+        assert_eq!(report.limits.max_host_modules, Some(1));
+    }
+
+    #[async_std::test]
+    async fn audit_log() {
+        use crate::audit::AuditEntry;
+        use http_types::{Method, Request, Url};
+
+        let sled = sled::Config::new().temporary(true).open().unwrap();
+        let log = Arc::new(AuditLog::new(&sled).unwrap());
+        let app = app(
+            Arc::new(Blocking::new(LocalDB(sled))),
+            Options {
+                audit: Some(log),
+                ..Default::default()
+            },
+        );
+
+        let request = |method, path: &str, body: serde_json::Value| {
+            let url = Url::parse("http://localhost").unwrap().join(path).unwrap();
+            let mut req = Request::new(method, url);
+            req.set_body(http_types::Body::from_json(&body).unwrap());
+            req
+        };
+        let code = include_bytes!("../../utils.wasm");
+        let requests = vec![
+            serde_json::json!({ "module_name": "utils", "wasm_hex": hex::encode(code.as_ref()) }),
+            serde_json::json!({ "module_name": "utils", "function_name": "double", "params": [2] }),
+            serde_json::json!({ "module_name": "utils", "function_name": "missing" }),
+        ];
+        for (path, body) in ["/register", "/execute", "/execute"].iter().zip(requests) {
+            let _: http_types::Response = app
+                .respond(request(Method::Post, path, body))
+                .await
+                .unwrap();
+        }
+
+        let mut res: http_types::Response = app
+            .respond(request(Method::Get, "/audit", serde_json::Value::Null))
+            .await
+            .unwrap();
+        let entries: Vec<AuditEntry> = res.body_json().await.unwrap();
+        assert_eq!(entries.len(), 3);
+        // Newest first
+        assert_eq!(entries[0].function.as_deref(), Some("missing"));
+        assert_eq!(entries[2].route, "/register");
+        let code_hash = hex::encode(sha2::Sha256::digest(code.as_ref()));
+        assert!(entries
+            .iter()
+            .all(|e| e.module_hash.as_ref() == Some(&code_hash)));
+
+        let mut res: http_types::Response = app
+            .respond(request(
+                Method::Get,
+                "/audit?route=/execute&success=false",
+                serde_json::Value::Null,
+            ))
+            .await
+            .unwrap();
+        let entries: Vec<AuditEntry> = res.body_json().await.unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].status, 500);
+        assert!(entries[0].error.is_some());
+        assert!(entries[0].params_digest.is_none());
+        assert_eq!(entries[0].module.as_deref(), Some("utils"));
+    }
+
+    #[async_std::test]
+    async fn audit_namespaces() {
+        use crate::audit::{AuditEntry, AuditQuery};
+        use crate::auth::Scope;
+        use http_types::{Method, Request, Url};
+
+        let sled = sled::Config::new().temporary(true).open().unwrap();
+        let keys = Arc::new(ApiKeys::new(&sled).unwrap());
+        let log = Arc::new(AuditLog::new(&sled).unwrap());
+        let app = app(
+            Arc::new(Blocking::new(LocalDB(sled))),
+            Options {
+                auth: Some(keys.clone()),
+                audit: Some(log.clone()),
+                ..Default::default()
+            },
+        );
+        let scopes = vec![Scope::Register, Scope::ExecuteRegistered];
+        let (team_a, _) = keys.create("a", scopes.clone(), Some("team-a")).unwrap();
+        let (team_b, _) = keys.create("b", scopes, Some("team-b")).unwrap();
+
+        let send = |path: &str, key: &str, body: serde_json::Value| {
+            let url = Url::parse("http://localhost").unwrap().join(path).unwrap();
+            let mut req = Request::new(Method::Post, url);
+            req.insert_header("Authorization", format!("Bearer {}", key));
+            req.set_body(http_types::Body::from_json(&body).unwrap());
+            let app = app.clone();
+            async move {
+                let res: http_types::Response = app.respond(req).await.unwrap();
+                res
+            }
+        };
+        // Both namespaces have a module named "utils", with different code
+        let utils = include_bytes!("../../utils.wasm");
+        let named = include_bytes!("../../named.wasm");
+        for (key, code) in [(&team_a, utils.as_ref()), (&team_b, named.as_ref())].iter() {
+            let body = serde_json::json!({ "module_name": "utils", "wasm_hex": hex::encode(code) });
+            send("/register", key, body).await;
+        }
+        let body = serde_json::json!({ "module_name": "utils", "function_name": "double" });
+        send("/execute", &team_b, body).await;
+
+        let entries: Vec<AuditEntry> = log
+            .query(&AuditQuery {
+                route: Some("/execute".into()),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].module.as_deref(), Some("team-b/utils"));
+        let named_hash = hex::encode(sha2::Sha256::digest(named.as_ref()));
+        assert_eq!(entries[0].module_hash, Some(named_hash));
+    }
+
+    #[cfg(unix)]
+    #[async_std::test]
+    async fn listeners() {
+        use async_std::os::unix::net::UnixStream;
+
+        let db = Arc::new(Blocking::new(LocalDB(
+            sled::Config::new().temporary(true).open().unwrap(),
+        )));
+        let port = portpicker::pick_unused_port().unwrap();
+        let socket = std::env::temp_dir().join(format!("wasm-exec-api-{}.sock", port));
+        let listen = Listen {
+            addrs: vec![format!("localhost:{}", port)],
+            tls: None,
+            unix_socket: Some(socket.to_str().unwrap().to_owned()),
+        };
+        let server =
+            task::spawn(
+                async move { start(&listen, db, Options::default(), future::pending()).await },
+            );
+
+        let client = task::spawn(async move {
+            task::sleep(Duration::from_millis(100)).await;
+            let res = surf::get(format!("http://localhost:{}/healthz", port)).await?;
+            assert_eq!(res.status(), http_types::StatusCode::Ok);
+
+            let mut stream = UnixStream::connect(&socket).await?;
+            stream
+                .write_all(b"GET /healthz HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+                .await?;
+            // The connection is kept alive, so only the start of the response is read.
+            let mut res = [0u8; 15];
+            stream.read_exact(&mut res).await?;
+            assert_eq!(&res, b"HTTP/1.1 200 OK");
             Ok(())
         });
 
@@ -126,36 +1136,404 @@ mod tests {
 
     #[test]
     fn wasm_module_symmetric_serialize() {
+        let param_names = [("run".to_owned(), vec!["a".to_owned()])]
+            .iter()
+            .cloned()
+            .collect();
         let wasm_ref = WasmModuleRef {
             code: b"test code",
             host_modules: &["one".into(), "two".into()],
+            param_names: &param_names,
+            metadata: &ModuleMetadata::default(),
+            expires: None,
         };
-        let serialized = to_vec(&wasm_ref).unwrap();
-        let wasm_mod_deser: WasmModule = from_slice(&serialized).unwrap();
+        let serialized = encode_module(&wasm_ref).unwrap();
+        let (wasm_mod_deser, _) = decode_module(&serialized).unwrap();
         assert_eq!(wasm_mod_deser.code, wasm_ref.code);
         assert_eq!(wasm_mod_deser.host_modules, wasm_ref.host_modules);
+        assert_eq!(&wasm_mod_deser.param_names, wasm_ref.param_names);
+
+        // Modules stored before param names were added can still be loaded
+        let serialized = serde_cbor::to_vec(&(b"test code".as_ref(), ["one"])).unwrap();
+        let (wasm_mod_deser, _) = decode_module(&serialized).unwrap();
+        assert!(wasm_mod_deser.param_names.is_empty());
     }
 
     #[async_std::test]
     async fn store_load() {
         let config = sled::Config::new().temporary(true);
-        let db = LocalDB(config.open().unwrap());
+        let db = Blocking::new(LocalDB(config.open().unwrap()));
         let code = include_bytes!("../../utils.wasm");
+        let host = HostEnv::default();
+        let param_names = Default::default();
+        let utils = WasmModuleRef {
+            code,
+            host_modules: &[],
+            param_names: &param_names,
+            metadata: &ModuleMetadata::default(),
+            expires: None,
+        };
+        let link = WasmModuleRef {
+            code,
+            host_modules: &["utils".into()],
+            param_names: &param_names,
+            metadata: &ModuleMetadata::default(),
+            expires: None,
+        };
 
-        assert!(load_wasm_module_recursive(&db, "utils").is_err());
+        assert!(load_wasm_module_recursive(&db, "utils", &host)
+            .await
+            .is_err());
 
         // Trying to load with dependency module that doesn't exist
-        assert!(store_wasm_module(&db, "test", code, &["utils".into()]).is_err());
+        assert!(
+            store_wasm_module(&db, "test", &link, &StoreLimits::default(), None)
+                .await
+                .is_err()
+        );
 
         // Store and load utils
-        store_wasm_module(&db, "utils", code, &[]).unwrap();
-        assert!(load_wasm_module_recursive(&db, "utils").is_ok());
+        store_wasm_module(&db, "utils", &utils, &StoreLimits::default(), None)
+            .await
+            .unwrap();
+        assert!(load_wasm_module_recursive(&db, "utils", &host)
+            .await
+            .is_ok());
 
         // Shouldn't be able to overwrite existing module
-        assert!(store_wasm_module(&db, "utils", code, &[]).is_err());
+        assert!(
+            store_wasm_module(&db, "utils", &utils, &StoreLimits::default(), None)
+                .await
+                .is_err()
+        );
 
         // Should be able to store link with host module of now stored "utils"
-        store_wasm_module(&db, "link", code, &["utils".into()]).unwrap();
-        assert!(load_wasm_module_recursive(&db, "link").is_ok());
+        store_wasm_module(&db, "link", &link, &StoreLimits::default(), None)
+            .await
+            .unwrap();
+        assert!(load_wasm_module_recursive(&db, "link", &host).await.is_ok());
+
+        // Records written past the checks, like hand-written sidecars, can form cycles
+        let metadata = ModuleMetadata::default();
+        let cyclic = |host_modules| WasmModuleRef {
+            code,
+            host_modules,
+            param_names: &param_names,
+            metadata: &metadata,
+            expires: None,
+        };
+        let (on_self, on_later, on_waiting) = (
+            [std::borrow::Cow::from("self")],
+            [std::borrow::Cow::from("later")],
+            [std::borrow::Cow::from("waiting")],
+        );
+        db.put_module("self", &cyclic(&on_self)).await.unwrap();
+        match load_wasm_module_recursive(&db, "self", &host).await {
+            Err(e) => assert_eq!(e.to_string(), "Dependency cycle through module self"),
+            Ok(_) => panic!("Linked a module depending on itself"),
+        }
+        db.put_module("waiting", &cyclic(&on_later)).await.unwrap();
+        let err = store_wasm_module(
+            &db,
+            "later",
+            &cyclic(&on_waiting),
+            &StoreLimits::default(),
+            None,
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("dependency cycle"));
+        assert!(!db.contains_module("later").await.unwrap());
+    }
+
+    /// Store which counts the loads in flight.
+    struct SlowStore {
+        base: Blocking<LocalDB>,
+        loading: AtomicUsize,
+        max_loading: AtomicUsize,
+    }
+
+    #[tide::utils::async_trait]
+    impl WasmStore for SlowStore {
+        async fn load_module(&self, name: &str) -> Result<WasmModule, anyhow::Error> {
+            let loading = self.loading.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_loading.fetch_max(loading, Ordering::SeqCst);
+            task::sleep(Duration::from_millis(50)).await;
+            self.loading.fetch_sub(1, Ordering::SeqCst);
+            self.base.load_module(name).await
+        }
+        async fn contains_module(&self, name: &str) -> Result<bool, anyhow::Error> {
+            self.base.contains_module(name).await
+        }
+        async fn put_module(
+            &self,
+            name: &str,
+            module: &WasmModuleRef<'_, '_>,
+        ) -> Result<(), anyhow::Error> {
+            self.base.put_module(name, module).await
+        }
+    }
+
+    #[async_std::test]
+    async fn concurrent_fetches() {
+        let db = SlowStore {
+            base: Blocking::new(LocalDB(sled::Config::new().temporary(true).open().unwrap())),
+            loading: AtomicUsize::new(0),
+            max_loading: AtomicUsize::new(0),
+        };
+        let param_names = Default::default();
+        let metadata = ModuleMetadata::default();
+        for (name, host_modules) in &[
+            ("utils", vec![]),
+            ("left", vec!["utils".into()]),
+            ("right", vec!["utils".into()]),
+            ("top", vec!["left".into(), "right".into()]),
+        ] {
+            let module = WasmModuleRef {
+                code: include_bytes!("../../utils.wasm"),
+                host_modules,
+                param_names: &param_names,
+                metadata: &metadata,
+                expires: None,
+            };
+            store_wasm_module(&db, name, &module, &StoreLimits::default(), None)
+                .await
+                .unwrap();
+        }
+
+        // The host modules of a level are loaded together, and shared ones only once
+        let modules = fetch_modules(&db, vec!["top".to_owned()]).await.unwrap();
+        let mut names: Vec<_> = modules.keys().collect();
+        names.sort();
+        assert_eq!(names, ["left", "right", "top", "utils"]);
+        assert_eq!(db.max_loading.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn host_functions() {
+        use crate::utils::host::HostNamespace;
+        use crate::utils::wasm::execute_wasm;
+        use wasmer_runtime::ImportObject;
+
+        let code = include_bytes!("../../host.wasm");
+        let host_env = |seed| {
+            let host = HostEnv::new(vec![HostNamespace::Env, HostNamespace::Random], seed);
+            let mut imports = ImportObject::new();
+            host.register(&mut imports, None).unwrap();
+            (host, imports)
+        };
+
+        // Guest logs are collected from the env namespace
+        let (host, imports) = host_env(None);
+        let res = execute_wasm(code, "greet", Vec::new().into(), &imports, &host).unwrap();
+        assert_eq!(res, [WasmValue::I32(1)]);
+        assert_eq!(host.logs(), ["hello"]);
+
+        // Panic message replaces the trap error
+        let (host, imports) = host_env(None);
+        let err = execute_wasm(code, "fail", Vec::new().into(), &imports, &host).unwrap_err();
+        assert_eq!(host.map_err(err).to_string(), "Guest panicked: oh no");
+
+        // Random values are reproducible with the same seed
+        let random = |seed| {
+            let (host, imports) = host_env(Some(seed));
+            execute_wasm(code, "random", Vec::new().into(), &imports, &host).unwrap()
+        };
+        assert_eq!(random(4), random(4));
+        assert_ne!(random(4), random(5));
+
+        // Guest can't be instantiated without the namespaces enabled
+        let imports = ImportObject::new();
+        let host = HostEnv::default();
+        assert!(execute_wasm(code, "greet", Vec::new().into(), &imports, &host).is_err());
+    }
+
+    #[test]
+    fn guest_kv() {
+        use crate::utils::host::HostNamespace;
+        use crate::utils::wasm::call_fn;
+
+        let db = Blocking::new(LocalDB(sled::Config::new().temporary(true).open().unwrap()));
+        let code = include_bytes!("../../kv.wasm");
+        let param_names = Default::default();
+        let module = WasmModuleRef {
+            code,
+            host_modules: &[],
+            param_names: &param_names,
+            metadata: &ModuleMetadata::default(),
+            expires: None,
+        };
+        let limits = StoreLimits::default();
+        let store = |name| task::block_on(store_wasm_module(&db, name, &module, &limits, None));
+        store("counter").unwrap();
+        store("other").unwrap();
+
+        let call = |name, function, transactional| {
+            let kv = task::block_on(db.guest_kv(transactional)).unwrap().unwrap();
+            let host = HostEnv::new(vec![HostNamespace::Kv], None).with_kv(kv);
+            let instance = task::block_on(load_wasm_module_recursive(&db, name, &host)).unwrap();
+            let res = call_fn(&instance, function, Vec::new().into(), None)?;
+            host.commit()?;
+            Ok::<_, anyhow::Error>(res)
+        };
+
+        // Values persist between calls and are scoped by module
+        assert_eq!(
+            call("counter", "increment", false).unwrap(),
+            [WasmValue::I32(1)]
+        );
+        assert_eq!(
+            call("counter", "increment", false).unwrap(),
+            [WasmValue::I32(2)]
+        );
+        assert_eq!(
+            call("other", "increment", false).unwrap(),
+            [WasmValue::I32(1)]
+        );
+
+        // Writes are kept when the guest traps after, unless transactional
+        assert!(call("counter", "increment_trap", false).is_err());
+        assert!(call("counter", "increment_trap", true).is_err());
+        assert_eq!(
+            call("counter", "increment", true).unwrap(),
+            [WasmValue::I32(4)]
+        );
+
+        // Values over the size limit are rejected
+        assert!(call("counter", "set_large", false).is_err());
+
+        assert_eq!(
+            call("counter", "reset", false).unwrap(),
+            [WasmValue::I32(1)]
+        );
+        assert_eq!(
+            call("counter", "reset", false).unwrap(),
+            [WasmValue::I32(0)]
+        );
+        assert_eq!(
+            call("counter", "increment", false).unwrap(),
+            [WasmValue::I32(1)]
+        );
+    }
+
+    #[test]
+    fn module_calls() {
+        use crate::utils::host::HostNamespace;
+        use crate::utils::wasm::call_fn;
+
+        let db = Arc::new(Blocking::new(LocalDB(
+            sled::Config::new().temporary(true).open().unwrap(),
+        )));
+        let param_names = Default::default();
+        let utils = WasmModuleRef {
+            code: include_bytes!("../../utils.wasm"),
+            host_modules: &[],
+            param_names: &param_names,
+            metadata: &ModuleMetadata::default(),
+            expires: None,
+        };
+        let caller = WasmModuleRef {
+            code: include_bytes!("../../caller.wasm"),
+            host_modules: &[],
+            param_names: &param_names,
+            metadata: &ModuleMetadata::default(),
+            expires: None,
+        };
+        let store = |name, module| {
+            let limits = StoreLimits::default();
+            task::block_on(store_wasm_module(db.as_ref(), name, module, &limits, None))
+        };
+        store("caller", &caller).unwrap();
+
+        let call = |function, params: Vec<i32>| {
+            let modules = vec![HostNamespace::Modules];
+            let host = host_env(&db, DEFAULT_NAMESPACE, None, modules, None, false, false);
+            let host = task::block_on(host)?;
+            let instance =
+                task::block_on(load_wasm_module_recursive(db.as_ref(), "caller", &host))?;
+            let params = params.into_iter().map(Into::into).collect::<Vec<_>>();
+            call_fn(&instance, function, params.into(), None)
+        };
+
+        // Called module must be registered
+        assert!(call("call_double", vec![21]).is_err());
+
+        store("utils", &utils).unwrap();
+        assert_eq!(call("call_double", vec![21]).unwrap(), [WasmValue::I64(42)]);
+
+        // Recursive calls are limited by depth
+        let err = call("recurse", vec![]).unwrap_err();
+        assert!(err.to_string().contains("maximum module call depth"));
+    }
+
+    #[async_std::test]
+    async fn execution_time_budget() {
+        use crate::utils::host::CALL_TIME_BUDGET;
+        use http_types::{Method, Request, StatusCode, Url};
+
+        let db = Blocking::new(LocalDB(sled::Config::new().temporary(true).open().unwrap()));
+        let app = app(Arc::new(db), Options::default());
+        let spin = wat::parse_str(r#"(module (func (export "spin") (loop (br 0))))"#).unwrap();
+        let url = Url::parse("http://localhost/").unwrap();
+        let mut req = Request::new(Method::Post, url);
+        req.set_body(
+            http_types::Body::from_json(&serde_json::json!({
+                "wasm_hex": hex::encode(spin),
+                "function_name": "spin",
+                "host_functions": ["modules"],
+            }))
+            .unwrap(),
+        );
+
+        // The guest never returns, the request fails once the budget is spent.
+        let start = std::time::Instant::now();
+        let mut res: http_types::Response = app.respond(req).await.unwrap();
+        assert!(start.elapsed() < CALL_TIME_BUDGET + Duration::from_secs(1));
+        assert_eq!(res.status(), StatusCode::InternalServerError);
+        let failure: ExecutionFailure = res.body_json().await.unwrap();
+        assert!(failure.error.contains("time budget"));
+    }
+
+    #[async_std::test]
+    async fn execution_slot_held_by_guest() {
+        use http_types::{Method, Request, StatusCode, Url};
+
+        let db = Blocking::new(LocalDB(sled::Config::new().temporary(true).open().unwrap()));
+        let options = Options {
+            limits: Limits {
+                max_executions: Some(1),
+                max_queued: 0,
+                ..Limits::default()
+            },
+            ..Options::default()
+        };
+        let app = app(Arc::new(db), options);
+        let countdown = wat::parse_str(
+            r#"(module (func (export "countdown") (param i64)
+                (loop $l
+                    (local.set 0 (i64.sub (local.get 0) (i64.const 1)))
+                    (br_if $l (i64.ne (local.get 0) (i64.const 0))))))"#,
+        )
+        .unwrap();
+        let execute = |n: u64| {
+            let url = Url::parse("http://localhost/").unwrap();
+            let mut req = Request::new(Method::Post, url);
+            let body = serde_json::json!({
+                "wasm_hex": hex::encode(&countdown),
+                "function_name": "countdown",
+                "params": [n],
+            });
+            req.set_body(http_types::Body::from_json(&body).unwrap());
+            let app = app.clone();
+            async move {
+                let res: http_types::Response = app.respond(req).await.unwrap();
+                res.status()
+            }
+        };
+
+        // The client gives up, but the guest keeps its slot until it returns
+        let gone = future::timeout(Duration::from_millis(100), execute(1_000_000_000)).await;
+        assert!(gone.is_err());
+        assert_eq!(execute(1).await, StatusCode::ServiceUnavailable);
     }
 }
@@ -0,0 +1,151 @@
+use super::{key_namespace, module_key};
+use crate::auth::Authenticated;
+use crate::utils::limits::UsageCounters;
+use crate::utils::namespace::{namespace_of, SEPARATOR};
+use crate::utils::wasm::exported_functions;
+use crate::utils::{delete_wasm_module, ModuleMetadata, WasmStore};
+use futures::future::try_join_all;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tide::{Body, Response, StatusCode};
+
+/// Details of a registered module.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+pub struct ModuleInfo {
+    /// Name of the module, with its namespace unless it's in the default namespace.
+    pub name: String,
+    /// Size of the wasm code in bytes.
+    pub size_bytes: usize,
+    /// Hex SHA-256 hash of the wasm code.
+    pub hash: String,
+    pub host_modules: Vec<String>,
+    /// Names of the exported functions.
+    pub functions: Vec<String>,
+    /// Parameter names registered for exported functions.
+    pub param_names: HashMap<String, Vec<String>>,
+    pub metadata: ModuleMetadata,
+    /// Unix timestamp in milliseconds after which the module expires.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<u64>,
+}
+
+/// Entry of a module in the listing.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+pub struct ModuleSummary {
+    pub name: String,
+    pub metadata: ModuleMetadata,
+}
+
+/// Filters of the module listing.
+#[derive(Serialize, Deserialize, JsonSchema, Default, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ModuleQuery {
+    /// Only modules with this tag.
+    pub tag: Option<String>,
+    /// Only modules of this namespace. Keys limited to a namespace only list its modules.
+    pub namespace: Option<String>,
+}
+
+/// Module reference of the route, from the `namespace` and `name` params.
+fn route_module<S>(req: &tide::Request<S>) -> tide::Result<String> {
+    let name = req.param("name")?;
+    let reference = match req.param("namespace") {
+        Ok(namespace) => format!("{}{}{}", namespace, SEPARATOR, name),
+        Err(_) => name.to_owned(),
+    };
+    module_key(req, &reference)
+}
+
+/// Returns the registered modules matching the query, sorted by name.
+pub async fn list<S>(req: tide::Request<Arc<S>>) -> tide::Result
+where
+    S: WasmStore,
+{
+    let query: ModuleQuery = req.query()?;
+    let namespace = match (key_namespace(&req), query.namespace.as_deref()) {
+        (Some(limit), Some(namespace)) if limit != namespace => {
+            return Err(tide::Error::from_str(
+                StatusCode::Forbidden,
+                format!("API key is limited to namespace {}", limit),
+            ))
+        }
+        (limit, namespace) => limit.or(namespace),
+    };
+    let mut names = req.state().module_names().await?;
+    names.retain(|name| namespace.map_or(true, |ns| namespace_of(name) == ns));
+    names.sort();
+    let loaded = try_join_all(names.iter().map(|name| req.state().load_module(name))).await?;
+    let mut modules = Vec::with_capacity(names.len());
+    for (name, module) in names.into_iter().zip(loaded) {
+        let metadata = module.metadata;
+        if query
+            .tag
+            .as_ref()
+            .map_or(true, |t| metadata.tags.contains(t))
+        {
+            modules.push(ModuleSummary { name, metadata });
+        }
+    }
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&modules)?)
+        .build())
+}
+
+/// Returns the details of a registered module.
+pub async fn inspect<S>(req: tide::Request<Arc<S>>) -> tide::Result
+where
+    S: WasmStore,
+{
+    let name = route_module(&req)?;
+    if !req.state().contains_module(&name).await? {
+        return Err(tide::Error::from_str(
+            StatusCode::NotFound,
+            format!("Module {} does not exist", name),
+        ));
+    }
+    let module = req.state().load_module(&name).await?;
+    let info = ModuleInfo {
+        name,
+        size_bytes: module.code.len(),
+        hash: hex::encode(Sha256::digest(&module.code)),
+        functions: exported_functions(&module.code)?,
+        host_modules: module.host_modules,
+        param_names: module.param_names,
+        metadata: module.metadata,
+        expires: module.expires,
+    };
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&info)?)
+        .build())
+}
+
+pub async fn delete<S>(req: tide::Request<Arc<S>>) -> tide::Result<String>
+where
+    S: WasmStore,
+{
+    let module_name = &route_module(&req)?;
+
+    // Only the owner of the module can delete it, when authentication is enabled.
+    if let Some(Authenticated { keys, key }) = req.ext() {
+        keys.check_owner(module_name, key)
+            .map_err(|e| tide::Error::new(StatusCode::Forbidden, e))?;
+    }
+
+    let owner = match req.ext::<Authenticated>() {
+        Some(Authenticated { keys, .. }) => keys.owner(module_name)?,
+        None => None,
+    };
+    let module = delete_wasm_module(req.state().as_ref(), module_name).await?;
+
+    if let Some(counters) = req.ext::<Arc<UsageCounters>>() {
+        counters.release(module_name, module.code.len() as u64, owner.as_deref())?;
+    }
+    if let Some(Authenticated { keys, .. }) = req.ext() {
+        keys.remove_owner(module_name)?;
+    }
+
+    Ok(format!("Successfully deleted module: {}", module_name))
+}
@@ -0,0 +1,347 @@
+use super::admin::UsageReport;
+use super::health::Readiness;
+use super::modules::{ModuleInfo, ModuleQuery, ModuleSummary};
+use super::{execute, index, register, ExecutionFailure, ExecutionResponse};
+use crate::audit::{AuditEntry, AuditQuery};
+use crate::utils::expiry::SweepReport;
+use once_cell::sync::Lazy;
+use schemars::gen::SchemaSettings;
+use schemars::schema::Schema;
+use schemars::JsonSchema;
+use schemars::Map;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tide::{Body, Response, StatusCode};
+
+/// OpenAPI document of the API, generated from the request and response types.
+static SPEC: Lazy<Value> = Lazy::new(spec);
+
+/// Schema of a wasm value, serialized externally tagged by type.
+#[derive(JsonSchema)]
+#[allow(dead_code)]
+pub enum WasmValue {
+    I32(i32),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+    V128(u128),
+}
+
+fn json_content(schema: &Schema) -> Value {
+    json!({ "application/json": { "schema": schema } })
+}
+
+/// Query parameters of the properties of a schema, which is removed from the schemas.
+fn query_parameters(schema: &Schema, schemas: &mut Map<String, Schema>) -> Vec<Value> {
+    let name = match schema {
+        Schema::Object(obj) => obj.reference.as_deref().unwrap_or_default(),
+        Schema::Bool(_) => "",
+    };
+    match schemas.remove(name.trim_start_matches("#/components/schemas/")) {
+        Some(Schema::Object(obj)) => obj
+            .object
+            .map(|obj| {
+                obj.properties
+                    .iter()
+                    .map(|(name, schema)| {
+                        json!({ "name": name, "in": "query", "required": false, "schema": schema })
+                    })
+                    .collect()
+            })
+            .unwrap_or_default(),
+        _ => Vec::new(),
+    }
+}
+
+fn text_response(description: &str) -> Value {
+    json!({
+        "description": description,
+        "content": { "text/plain": { "schema": { "type": "string" } } },
+    })
+}
+
+/// Builds the OpenAPI 3 document of all routes.
+pub fn spec() -> Value {
+    let mut gen = SchemaSettings::openapi3().into_generator();
+    let run = gen.subschema_for::<index::Request>();
+    let register = gen.subschema_for::<register::Request>();
+    let execute = gen.subschema_for::<execute::Request>();
+    let values = gen.subschema_for::<Vec<WasmValue>>();
+    let execution = gen.subschema_for::<ExecutionResponse>();
+    let failure = gen.subschema_for::<ExecutionFailure>();
+    let audit = gen.subschema_for::<Vec<AuditEntry>>();
+    let readiness = gen.subschema_for::<Readiness>();
+    let modules = gen.subschema_for::<Vec<ModuleSummary>>();
+    let module = gen.subschema_for::<ModuleInfo>();
+    let audit_query = gen.subschema_for::<AuditQuery>();
+    let module_query = gen.subschema_for::<ModuleQuery>();
+    let usage = gen.subschema_for::<UsageReport>();
+    let sweep = gen.subschema_for::<SweepReport>();
+
+    // Visitors are only applied to root schemas by the generator.
+    let mut schemas = gen.take_definitions();
+    for visitor in gen.visitors_mut() {
+        for schema in schemas.values_mut() {
+            visitor.visit_schema(schema);
+        }
+    }
+
+    let audit_params = query_parameters(&audit_query, &mut schemas);
+    let module_params = query_parameters(&module_query, &mut schemas);
+
+    let execution_result = json!({
+        "description": "Result values, or with the guest logs and trace if host namespaces or tracing are enabled",
+        "content": {
+            "application/json": {
+                "schema": { "oneOf": [values, execution] },
+            },
+        },
+    });
+    let error = text_response("Error message");
+    let execution_error = json!({
+        "description": "Error message, or with the guest logs and trace if host namespaces or tracing are enabled",
+        "content": {
+            "text/plain": { "schema": { "type": "string" } },
+            "application/json": { "schema": failure },
+        },
+    });
+    let public: Vec<Value> = Vec::new();
+    let path_param = |name: &str| json!({ "name": name, "in": "path", "required": true, "schema": { "type": "string" } });
+    let module_routes = |params: Vec<Value>, suffix: &str| {
+        json!({
+            "parameters": params,
+            "get": {
+                "operationId": format!("inspectModule{}", suffix),
+                "summary": "Details of a registered module",
+                "responses": {
+                    "200": { "description": "Module details", "content": json_content(&module) },
+                    "default": error,
+                },
+            },
+            "delete": {
+                "operationId": format!("deleteModule{}", suffix),
+                "summary": "Deletes a registered module",
+                "responses": { "200": text_response("Module deleted"), "default": error },
+            },
+        })
+    };
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "wasm-exec-api",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": {
+            "/": {
+                "post": {
+                    "operationId": "run",
+                    "summary": "Executes a function of a module given in the request",
+                    "requestBody": { "required": true, "content": json_content(&run) },
+                    "responses": { "200": execution_result, "default": execution_error },
+                },
+            },
+            "/register": {
+                "post": {
+                    "operationId": "register",
+                    "summary": "Registers a module",
+                    "requestBody": { "required": true, "content": json_content(&register) },
+                    "responses": { "200": text_response("Module stored"), "default": error },
+                },
+            },
+            "/execute": {
+                "post": {
+                    "operationId": "execute",
+                    "summary": "Executes a function of a registered module",
+                    "requestBody": { "required": true, "content": json_content(&execute) },
+                    "responses": { "200": execution_result, "default": execution_error },
+                },
+            },
+            "/modules": {
+                "get": {
+                    "operationId": "listModules",
+                    "summary": "Registered modules matching the query, sorted by name",
+                    "parameters": module_params,
+                    "responses": {
+                        "200": { "description": "Modules", "content": json_content(&modules) },
+                        "default": error,
+                    },
+                },
+            },
+            "/modules/{name}": module_routes(vec![path_param("name")], ""),
+            "/modules/{namespace}/{name}": module_routes(
+                vec![path_param("namespace"), path_param("name")],
+                "InNamespace",
+            ),
+            "/metrics": {
+                "get": {
+                    "operationId": "metrics",
+                    "summary": "Metrics in the Prometheus text format",
+                    "responses": { "200": text_response("Metrics"), "default": error },
+                },
+            },
+            "/audit": {
+                "get": {
+                    "operationId": "audit",
+                    "summary": "Audit entries matching the query, newest first",
+                    "parameters": audit_params,
+                    "responses": {
+                        "200": { "description": "Audit entries", "content": json_content(&audit) },
+                        "default": error,
+                    },
+                },
+            },
+            "/admin/usage": {
+                "get": {
+                    "operationId": "usage",
+                    "summary": "Usage of the store by namespace and API key, with its limits",
+                    "responses": {
+                        "200": { "description": "Usage report", "content": json_content(&usage) },
+                        "default": error,
+                    },
+                },
+            },
+            "/admin/gc": {
+                "post": {
+                    "operationId": "gc",
+                    "summary": "Removes the expired modules no live module depends on",
+                    "responses": {
+                        "200": { "description": "Sweep report", "content": json_content(&sweep) },
+                        "default": error,
+                    },
+                },
+            },
+            "/healthz": {
+                "get": {
+                    "operationId": "healthz",
+                    "summary": "Returns OK while the process is up",
+                    "security": public,
+                    "responses": {
+                        "200": {
+                            "description": "Process is up",
+                            "content": {
+                                "application/json": {
+                                    "schema": {
+                                        "type": "object",
+                                        "properties": { "status": { "type": "string" } },
+                                    },
+                                },
+                            },
+                        },
+                    },
+                },
+            },
+            "/readyz": {
+                "get": {
+                    "operationId": "readyz",
+                    "summary": "Readiness checks of the store",
+                    "security": public,
+                    "responses": {
+                        "200": { "description": "All checks passed", "content": json_content(&readiness) },
+                        "503": { "description": "A check failed", "content": json_content(&readiness) },
+                    },
+                },
+            },
+            "/openapi.json": {
+                "get": {
+                    "operationId": "openapi",
+                    "summary": "This document",
+                    "security": public,
+                    "responses": {
+                        "200": {
+                            "description": "OpenAPI document",
+                            "content": { "application/json": { "schema": { "type": "object" } } },
+                        },
+                    },
+                },
+            },
+        },
+        "components": {
+            "schemas": schemas,
+            "securitySchemes": {
+                "bearer": { "type": "http", "scheme": "bearer" },
+                "apiKey": { "type": "apiKey", "in": "header", "name": "X-Api-Key" },
+            },
+        },
+        // Only checked when the server is started with `--auth`.
+        "security": [{ "bearer": [] }, { "apiKey": [] }],
+    })
+}
+
+/// Returns the OpenAPI document.
+pub async fn handle<S>(_req: tide::Request<Arc<S>>) -> tide::Result {
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&*SPEC)?)
+        .build())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn refs<'a>(value: &'a Value, out: &mut Vec<&'a str>) {
+        match value {
+            Value::Object(map) => {
+                if let Some(Value::String(r)) = map.get("$ref") {
+                    out.push(r);
+                }
+                map.values().for_each(|v| refs(v, out));
+            }
+            Value::Array(values) => values.iter().for_each(|v| refs(v, out)),
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn spec_references() {
+        let spec = spec();
+        let paths = spec["paths"].as_object().unwrap();
+        for path in &[
+            "/",
+            "/register",
+            "/execute",
+            "/modules",
+            "/modules/{name}",
+            "/modules/{namespace}/{name}",
+            "/metrics",
+            "/audit",
+            "/admin/usage",
+            "/admin/gc",
+            "/healthz",
+            "/readyz",
+            "/openapi.json",
+        ] {
+            assert!(paths.contains_key(*path), "missing {}", path);
+        }
+
+        let schemas = spec["components"]["schemas"].as_object().unwrap();
+        for name in &[
+            "RunRequest",
+            "RegisterRequest",
+            "ExecuteRequest",
+            "HostNamespace",
+        ] {
+            assert!(schemas.contains_key(*name), "missing {}", name);
+        }
+        assert_eq!(
+            schemas["RunRequest"]["required"],
+            json!(["function_name", "wasm_hex"])
+        );
+
+        let mut all = Vec::new();
+        refs(&spec, &mut all);
+        for r in all {
+            let name = r.strip_prefix("#/components/schemas/").unwrap();
+            assert!(schemas.contains_key(name), "unresolved {}", r);
+        }
+
+        let params = spec["paths"]["/audit"]["get"]["parameters"]
+            .as_array()
+            .unwrap();
+        assert!(params.iter().any(|p| p["name"] == "module"));
+        let params = spec["paths"]["/modules"]["get"]["parameters"]
+            .as_array()
+            .unwrap();
+        assert!(params.iter().any(|p| p["name"] == "tag"));
+    }
+}
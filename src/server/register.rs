@@ -1,14 +1,44 @@
+use super::{check_imports, module_key};
+use crate::auth::Authenticated;
+use crate::logger;
+use crate::namespaces::{NamespaceConfig, Namespaces};
+use crate::utils::limits::{Charge, LimitError, StoreLimits, UsageCounters};
+use crate::utils::namespace::namespace_of;
 use crate::utils::*;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
+use std::collections::HashMap;
 use std::sync::Arc;
+use tide::StatusCode;
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Registration of a new module. Fails if a module with the same name already exists.
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+#[schemars(rename = "RegisterRequest")]
 pub struct Request<'a> {
     pub module_name: Cow<'a, str>,
     pub wasm_hex: Cow<'a, str>,
     #[serde(default)]
     pub host_modules: Vec<Cow<'a, str>>,
+    /// Parameter names of exported functions, for modules without a name section.
+    #[serde(default)]
+    pub param_names: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    pub metadata: ModuleMetadata,
+    /// Seconds after which the module expires, and is removed once no module which hasn't expired
+    /// depends on it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u64>,
+}
+
+/// Responds to modules over a limit with 413, and to exceeded quotas with 507.
+fn limit_error(e: anyhow::Error) -> tide::Error {
+    let status = match e.downcast_ref::<LimitError>() {
+        Some(LimitError::TooLarge(_)) => StatusCode::PayloadTooLarge,
+        Some(LimitError::QuotaExceeded(_)) => StatusCode::InsufficientStorage,
+        None => StatusCode::InternalServerError,
+    };
+    tide::Error::new(status, e)
 }
 
 pub async fn handle<S>(mut req: tide::Request<Arc<S>>) -> tide::Result<String>
@@ -19,16 +49,58 @@ where
         module_name,
         wasm_hex,
         host_modules,
+        param_names,
+        metadata,
+        ttl,
     } = req.body_json().await?;
 
     let wasm_bytes = hex::decode(wasm_hex.as_ref())?;
+    let module = module_key(&req, &module_name)?;
+    let namespace = namespace_of(&module);
+    check_imports(&req, namespace, &host_modules)?;
+    let limits = req.ext::<Arc<StoreLimits>>().cloned().unwrap_or_default();
+    let charge = match req.ext::<Arc<UsageCounters>>() {
+        Some(counters) => Some(Charge {
+            counters,
+            owner: req.ext::<Authenticated>().map(|a| a.key.id.as_str()),
+            namespace: match req.ext::<Arc<Namespaces>>() {
+                Some(namespaces) => namespaces.get(namespace)?,
+                None => NamespaceConfig::default(),
+            },
+        }),
+        None => None,
+    };
 
     store_wasm_module(
         req.state().as_ref(),
-        module_name.as_ref(),
-        &wasm_bytes,
-        &host_modules,
-    )?;
+        &module,
+        &WasmModuleRef {
+            code: &wasm_bytes,
+            host_modules: &host_modules,
+            param_names: &param_names,
+            metadata: &metadata,
+            expires: ttl.map(expiry::expires_at),
+        },
+        &limits,
+        charge.as_ref(),
+    )
+    .await
+    .map_err(limit_error)?;
+
+    // Modules registered with a key are removed again if the owner can't be recorded, as they
+    // couldn't be deleted by the key.
+    if let Some(Authenticated { keys, key }) = req.ext() {
+        let (keys, name, owner) = (keys.clone(), module.clone(), key.id.clone());
+        if let Err(e) = logger::spawn_blocking(move || keys.set_owner(&name, &owner)).await {
+            if let Err(e) = req.state().remove_module(&module).await {
+                log::error!("Failed to remove module {} without owner: {}", module, e);
+            } else if let Some(charge) = &charge {
+                let size = wasm_bytes.len() as u64;
+                charge.counters.release(&module, size, charge.owner)?;
+            }
+            return Err(e.into());
+        }
+    }
 
-    Ok(format!("Successfully stored module: {}", module_name))
+    Ok(format!("Successfully stored module: {}", module))
 }
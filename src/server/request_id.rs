@@ -0,0 +1,73 @@
+use crate::logger::with_request_id;
+use rand::RngCore;
+use tide::utils::async_trait;
+use tide::{Middleware, Next, Request};
+
+/// Header of the request id.
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+/// Maximum length of a request id given by the client.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Middleware which propagates the `X-Request-Id` of the request, or assigns a new one. The id is
+/// attached to the log records emitted while handling the request, and returned in the response.
+pub struct RequestId;
+
+#[async_trait]
+impl<State> Middleware<State> for RequestId
+where
+    State: Clone + Send + Sync + 'static,
+{
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let id = req
+            .header(REQUEST_ID_HEADER)
+            .map(|v| v.as_str())
+            .filter(|id| is_valid(id))
+            .map(str::to_owned)
+            .unwrap_or_else(generate);
+
+        let mut res = with_request_id(id.clone(), next.run(req)).await;
+        res.insert_header(REQUEST_ID_HEADER, id);
+        Ok(res)
+    }
+}
+
+/// Ids from clients are only used if short and printable, so they can't break log lines.
+fn is_valid(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+fn generate() -> String {
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logger::request_id;
+    use http_types::{Request, Response, Url};
+
+    #[async_std::test]
+    async fn propagate_request_id() {
+        let mut app = tide::new();
+        app.with(RequestId);
+        app.at("/")
+            .get(|_| async { Ok(request_id().unwrap_or_default()) });
+        let url = Url::parse("http://localhost/").unwrap();
+
+        let mut req = Request::get(url.clone());
+        req.insert_header(REQUEST_ID_HEADER, "abc-123");
+        let mut res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res[REQUEST_ID_HEADER], "abc-123");
+        assert_eq!(res.body_string().await.unwrap(), "abc-123");
+
+        // Invalid ids are replaced.
+        let mut req = Request::get(url);
+        req.insert_header(REQUEST_ID_HEADER, "a b");
+        let mut res: Response = app.respond(req).await.unwrap();
+        let id = res[REQUEST_ID_HEADER].as_str().to_owned();
+        assert_eq!(id.len(), 32);
+        assert_eq!(res.body_string().await.unwrap(), id);
+    }
+}
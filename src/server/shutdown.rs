@@ -0,0 +1,93 @@
+use async_std::task;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tide::utils::async_trait;
+use tide::{Middleware, Next, Request};
+
+/// Default time to wait for in-flight requests when shutting down, in seconds.
+pub const DEFAULT_GRACE_PERIOD: u64 = 30;
+/// Interval to check if in-flight requests have finished.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Middleware which counts the requests being handled, including executions waiting in the
+/// queue, so they can be drained on shutdown.
+#[derive(Clone, Default)]
+pub struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    /// Number of requests being handled.
+    pub fn count(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Waits until all requests are handled, or the grace period elapses. Returns the number of
+    /// requests still in flight.
+    pub async fn drain(&self, grace_period: Duration) -> usize {
+        let start = Instant::now();
+        while self.count() > 0 && start.elapsed() < grace_period {
+            task::sleep(DRAIN_POLL_INTERVAL).await;
+        }
+        self.count()
+    }
+}
+
+struct InFlightGuard<'a>(&'a AtomicUsize);
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[async_trait]
+impl<State> Middleware<State> for InFlight
+where
+    State: Clone + Send + Sync + 'static,
+{
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        let _guard = InFlightGuard(&self.0);
+        Ok(next.run(req).await)
+    }
+}
+
+/// Resolves when the process receives SIGINT or SIGTERM.
+pub async fn signal() {
+    let (sender, receiver) = async_channel::bounded(1);
+    if let Err(e) = ctrlc::set_handler(move || {
+        let _ = sender.try_send(());
+    }) {
+        log::error!("Could not set the shutdown signal handler: {}", e);
+        return async_std::future::pending().await;
+    }
+    let _ = receiver.recv().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[async_std::test]
+    async fn drain_in_flight() {
+        let in_flight = InFlight::default();
+        let mut app = tide::new();
+        app.with(in_flight.clone());
+        app.at("/").get(|_| async {
+            task::sleep(Duration::from_millis(200)).await;
+            Ok("")
+        });
+
+        let req = http_types::Request::get(http_types::Url::parse("http://localhost/").unwrap());
+        let res = task::spawn(async move {
+            let res: http_types::Response = app.respond(req).await.unwrap();
+            res.status()
+        });
+        task::sleep(Duration::from_millis(50)).await;
+        assert_eq!(in_flight.count(), 1);
+        assert_eq!(in_flight.drain(Duration::from_millis(10)).await, 1);
+
+        assert_eq!(in_flight.drain(Duration::from_secs(5)).await, 0);
+        assert_eq!(res.await, http_types::StatusCode::Ok);
+    }
+}
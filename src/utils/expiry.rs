@@ -0,0 +1,217 @@
+use super::namespace::{namespace_of, resolve};
+use super::{WasmModule, WasmStore};
+use anyhow::Error;
+use futures::future::try_join_all;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Current Unix timestamp in milliseconds.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Expiry timestamp of a module registered now with a TTL in seconds.
+pub fn expires_at(ttl: u64) -> u64 {
+    now().saturating_add(ttl.saturating_mul(1000))
+}
+
+/// Expired modules found by a sweep.
+#[derive(Serialize, Deserialize, JsonSchema, Default, Debug, Clone, PartialEq)]
+pub struct SweepReport {
+    /// Expired modules which were removed.
+    pub collected: Vec<String>,
+    /// Expired modules kept because a live module depends on them.
+    pub kept: Vec<String>,
+    /// Expired modules which could not be removed, like modules of a read-only overlay.
+    #[serde(default)]
+    pub failed: Vec<String>,
+}
+
+/// Adds the modules reachable from `name` through `deps` to `needed`.
+fn mark_needed(name: &str, deps: &HashMap<String, Vec<String>>, needed: &mut HashSet<String>) {
+    let mut stack = vec![name];
+    while let Some(name) = stack.pop() {
+        for dep in deps.get(name).into_iter().flatten() {
+            if needed.insert(dep.clone()) {
+                stack.push(dep);
+            }
+        }
+    }
+}
+
+/// Host modules of the module, resolved in its namespace.
+fn resolved_deps(name: &str, module: &WasmModule) -> Vec<String> {
+    let namespace = namespace_of(name);
+    module
+        .host_modules
+        .iter()
+        .map(|m| resolve(m, namespace))
+        .collect()
+}
+
+/// Removes the modules which expired before `now`, unless a module which hasn't expired depends on
+/// them directly or through other modules. Modules which are only depended on by collected modules
+/// are collected too. The modules are loaded concurrently, and `removed` is called with each
+/// collected module. Before each removal, modules registered since the sweep started are checked
+/// for dependencies on it. Modules which fail to be removed are reported and don't stop the sweep.
+pub async fn sweep<S, F>(store: &S, now: u64, mut removed: F) -> Result<SweepReport, Error>
+where
+    S: WasmStore + ?Sized,
+    F: FnMut(&str, &WasmModule),
+{
+    let names = store.module_names().await?;
+    let modules = try_join_all(names.iter().map(|name| store.load_module(name))).await?;
+    let mut expired = HashMap::new();
+    let mut deps = HashMap::new();
+    for (name, module) in names.into_iter().zip(modules) {
+        deps.insert(name.clone(), resolved_deps(&name, &module));
+        if module.expires.map_or(false, |expires| expires <= now) {
+            expired.insert(name, module);
+        }
+    }
+
+    // Everything reachable from a live module is needed.
+    let mut needed = HashSet::new();
+    for name in deps.keys().filter(|n| !expired.contains_key(*n)) {
+        mark_needed(name, &deps, &mut needed);
+    }
+
+    let mut report = SweepReport::default();
+    for (name, module) in expired {
+        if !needed.contains(&name) {
+            // Modules registered since the snapshot are live, and may depend on the module.
+            for new in store.module_names().await? {
+                if deps.contains_key(&new) {
+                    continue;
+                }
+                // Modules removed in the meantime don't need anything.
+                if let Ok(loaded) = store.load_module(&new).await {
+                    deps.insert(new.clone(), resolved_deps(&new, &loaded));
+                    mark_needed(&new, &deps, &mut needed);
+                }
+            }
+        }
+        if needed.contains(&name) {
+            report.kept.push(name);
+            continue;
+        }
+        match store.remove_module(&name).await {
+            Ok(()) => {
+                removed(&name, &module);
+                report.collected.push(name);
+            }
+            Err(e) => {
+                log::warn!("Could not remove expired module {}: {}", name, e);
+                report.failed.push(name);
+            }
+        }
+    }
+    report.collected.sort();
+    report.kept.sort();
+    report.failed.sort();
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::local_db::LocalDB;
+    use crate::utils::{Blocking, ModuleMetadata, SyncWasmStore, WasmModuleRef};
+    use anyhow::anyhow;
+    use std::borrow::Cow;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tide::utils::async_trait;
+
+    fn put(db: &LocalDB, name: &str, host_modules: &[Cow<str>], expires: Option<u64>) {
+        let module = WasmModuleRef {
+            code: b"code",
+            host_modules,
+            param_names: &HashMap::new(),
+            metadata: &ModuleMetadata::default(),
+            expires,
+        };
+        db.put_module(name, &module).unwrap();
+    }
+
+    #[async_std::test]
+    async fn sweep_expired() {
+        let db = LocalDB(sled::Config::new().temporary(true).open().unwrap());
+        put(&db, "expired", &[], Some(10));
+        put(&db, "expired_dep", &[], Some(10));
+        put(&db, "expired_user", &["expired_dep".into()], Some(10));
+        put(&db, "kept", &[], Some(10));
+        put(&db, "live", &["kept".into()], None);
+        put(&db, "later", &[], Some(30));
+        let store = Blocking::new(db);
+
+        let mut removed = Vec::new();
+        let report = sweep(&store, 20, |name, _| removed.push(name.to_owned()))
+            .await
+            .unwrap();
+        removed.sort();
+        assert_eq!(removed, report.collected);
+        assert_eq!(report.collected, ["expired", "expired_dep", "expired_user"]);
+        assert_eq!(report.kept, ["kept"]);
+        let mut names = store.module_names().await.unwrap();
+        names.sort();
+        assert_eq!(names, ["kept", "later", "live"]);
+    }
+
+    /// Store which registers a module depending on `expired_dep` when listed the second time,
+    /// and can't remove `stuck`.
+    struct Racing {
+        store: Blocking<LocalDB>,
+        listed: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl WasmStore for Racing {
+        async fn load_module(&self, name: &str) -> Result<WasmModule, Error> {
+            self.store.load_module(name).await
+        }
+        async fn contains_module(&self, name: &str) -> Result<bool, Error> {
+            self.store.contains_module(name).await
+        }
+        async fn put_module(
+            &self,
+            name: &str,
+            module: &WasmModuleRef<'_, '_>,
+        ) -> Result<(), Error> {
+            self.store.put_module(name, module).await
+        }
+        async fn module_names(&self) -> Result<Vec<String>, Error> {
+            if self.listed.fetch_add(1, Ordering::SeqCst) == 1 {
+                put(&self.store.0, "late", &["expired_dep".into()], None);
+            }
+            self.store.module_names().await
+        }
+        async fn remove_module(&self, name: &str) -> Result<(), Error> {
+            if name == "stuck" {
+                return Err(anyhow!("Module {} is read-only", name));
+            }
+            self.store.remove_module(name).await
+        }
+    }
+
+    #[async_std::test]
+    async fn sweep_rechecks_and_continues() {
+        let db = LocalDB(sled::Config::new().temporary(true).open().unwrap());
+        put(&db, "expired", &[], Some(10));
+        put(&db, "expired_dep", &[], Some(10));
+        put(&db, "stuck", &[], Some(10));
+        let store = Racing {
+            store: Blocking::new(db),
+            listed: AtomicUsize::new(0),
+        };
+
+        let report = sweep(&store, 20, |_, _| {}).await.unwrap();
+        assert_eq!(report.collected, ["expired"]);
+        assert_eq!(report.kept, ["expired_dep"]);
+        assert_eq!(report.failed, ["stuck"]);
+    }
+}
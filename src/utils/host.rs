@@ -0,0 +1,516 @@
+use super::kv::GuestKv;
+use super::trace::Tracer;
+use anyhow::{anyhow, Error};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use wasmer_runtime::{func, types::Type, Ctx, DynFunc, ImportObject, Instance, Value as WasmValue};
+use wasmer_runtime_core::{import::Namespace, memory::MemoryView};
+
+/// Native host namespaces that guests can import when enabled on a request.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HostNamespace {
+    /// `log`, `abort` and `panic` functions which take a utf8 string from guest memory.
+    Env,
+    /// `now_ms` wall clock time and `monotonic_ns` time since the request started.
+    Clock,
+    /// `next_u64` and `next_f64` from a random generator, seeded by the request.
+    Random,
+    /// `get`, `set` and `delete` of values persisted for the registered module.
+    Kv,
+    /// `call` a function of a registered module, chosen at runtime by name.
+    Modules,
+}
+
+impl HostNamespace {
+    /// Name the namespace is imported by.
+    pub fn name(self) -> &'static str {
+        match self {
+            HostNamespace::Env => "env",
+            HostNamespace::Clock => "clock",
+            HostNamespace::Random => "random",
+            HostNamespace::Kv => "kv",
+            HostNamespace::Modules => "modules",
+        }
+    }
+}
+
+impl std::str::FromStr for HostNamespace {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "env" => Ok(HostNamespace::Env),
+            "clock" => Ok(HostNamespace::Clock),
+            "random" => Ok(HostNamespace::Random),
+            "kv" => Ok(HostNamespace::Kv),
+            "modules" => Ok(HostNamespace::Modules),
+            _ => Err(anyhow!("Unknown host namespace {}", s)),
+        }
+    }
+}
+
+/// Maximum depth of nested calls through the `modules` namespace.
+pub const MAX_CALL_DEPTH: usize = 8;
+/// Maximum number of calls through the `modules` namespace for a single request.
+pub const MAX_MODULE_CALLS: usize = 256;
+/// Time after the request started, after which calls through the `modules` namespace fail. The
+/// execution of a request with the namespace fails once it's spent, see [`HostEnv::deadline`].
+pub const CALL_TIME_BUDGET: Duration = Duration::from_secs(5);
+
+/// Loads a registered module by name, with its dependencies linked to the host environment.
+pub type ModuleLoader = Arc<dyn Fn(&str, &HostEnv) -> Result<Instance, Error> + Send + Sync>;
+
+#[derive(Default)]
+struct HostState {
+    logs: Vec<String>,
+    panic_message: Option<String>,
+}
+
+/// Host functions enabled for a single request. The state is shared between all instances
+/// linked during the request, so logs and random values are in call order.
+#[derive(Clone)]
+pub struct HostEnv {
+    namespaces: Vec<HostNamespace>,
+    state: Arc<Mutex<HostState>>,
+    rng: Arc<Mutex<SplitMix64>>,
+    started: Instant,
+    kv: Option<Arc<GuestKv>>,
+    modules: Option<ModuleLoader>,
+    call_depth: Arc<AtomicUsize>,
+    call_count: Arc<AtomicUsize>,
+    settled: Arc<AtomicBool>,
+    tracer: Option<Arc<Tracer>>,
+}
+
+impl Default for HostEnv {
+    fn default() -> Self {
+        Self::new(Vec::new(), None)
+    }
+}
+
+impl HostEnv {
+    /// Creates a host environment with the namespaces enabled. The random generator is seeded
+    /// with the current time if no seed is provided.
+    pub fn new(namespaces: Vec<HostNamespace>, random_seed: Option<u64>) -> Self {
+        let seed = random_seed.unwrap_or_else(|| unix_time().as_nanos() as u64);
+        Self {
+            namespaces,
+            state: Default::default(),
+            rng: Arc::new(Mutex::new(SplitMix64(seed))),
+            started: Instant::now(),
+            kv: None,
+            modules: None,
+            call_depth: Default::default(),
+            call_count: Default::default(),
+            settled: Default::default(),
+            tracer: None,
+        }
+    }
+
+    /// Sets the storage used by the `kv` namespace.
+    pub fn with_kv(mut self, kv: GuestKv) -> Self {
+        self.kv = Some(Arc::new(kv));
+        self
+    }
+
+    /// Sets the loader used by the `modules` namespace to load registered modules.
+    pub fn with_modules(mut self, loader: ModuleLoader) -> Self {
+        self.modules = Some(loader);
+        self
+    }
+
+    /// Enables tracing of the execution.
+    pub fn with_tracer(mut self) -> Self {
+        self.tracer = Some(Default::default());
+        self
+    }
+
+    /// Tracer of the execution, if tracing is enabled.
+    pub fn tracer(&self) -> Option<&Arc<Tracer>> {
+        self.tracer.as_ref()
+    }
+
+    /// Returns true if no host namespaces are enabled.
+    pub fn is_empty(&self) -> bool {
+        self.namespaces.is_empty()
+    }
+
+    /// Registers the enabled host namespaces in the import object. Errors if a namespace with
+    /// the same name is already registered.
+    ///
+    /// The `kv` namespace is scoped to the registered module name, so it is only registered
+    /// when a `scope` is provided.
+    pub fn register(&self, imports: &mut ImportObject, scope: Option<&str>) -> Result<(), Error> {
+        for &namespace in &self.namespaces {
+            if imports.contains_namespace(namespace.name()) {
+                return Err(anyhow!(
+                    "Host namespace {} conflicts with a host module of the same name",
+                    namespace.name()
+                ));
+            }
+            let ns = match namespace {
+                HostNamespace::Env => self.env_namespace(),
+                HostNamespace::Clock => self.clock_namespace(),
+                HostNamespace::Random => self.random_namespace(),
+                HostNamespace::Kv => match scope {
+                    Some(scope) => self.kv_namespace(scope)?,
+                    None => continue,
+                },
+                HostNamespace::Modules => self.modules_namespace()?,
+            };
+            imports.register(namespace.name(), ns);
+        }
+        Ok(())
+    }
+
+    /// Messages logged by guests during the request.
+    pub fn logs(&self) -> Vec<String> {
+        self.state.lock().unwrap().logs.clone()
+    }
+
+    /// Time after which the execution fails, if the `modules` namespace is enabled. Guests can't
+    /// be interrupted, so the guest keeps running, but its result is dropped, see
+    /// [`HostEnv::time_out`].
+    pub fn deadline(&self) -> Option<Instant> {
+        if self.namespaces.contains(&HostNamespace::Modules) {
+            Some(self.started + CALL_TIME_BUDGET)
+        } else {
+            None
+        }
+    }
+
+    /// Marks the execution as timed out, so that the `kv` writes of the guest are not committed
+    /// when it returns. Returns the error of the execution, or `None` if the writes were already
+    /// committed, in which case the result of the guest should be used.
+    pub fn time_out(&self) -> Option<Error> {
+        if self.settled.swap(true, Ordering::SeqCst) {
+            return None;
+        }
+        Some(time_budget_error())
+    }
+
+    /// Commits the writes to the `kv` namespace, if transactional. This should only be called
+    /// after the guest call returned successfully. Fails if the execution timed out.
+    pub fn commit(&self) -> Result<(), Error> {
+        if self.settled.swap(true, Ordering::SeqCst) {
+            return Err(time_budget_error());
+        }
+        match &self.kv {
+            Some(kv) => kv.commit(),
+            None => Ok(()),
+        }
+    }
+
+    /// Replaces an execution error with the guest's panic message, if one was set.
+    pub fn map_err(&self, err: Error) -> Error {
+        match self.state.lock().unwrap().panic_message.take() {
+            Some(msg) => anyhow!("Guest panicked: {}", msg),
+            None => err,
+        }
+    }
+
+    fn env_namespace(&self) -> Namespace {
+        let mut ns = Namespace::new();
+        let state = self.state.clone();
+        ns.insert(
+            "log",
+            func!(
+                move |ctx: &mut Ctx, ptr: u32, len: u32| -> Result<(), String> {
+                    let msg = read_str(ctx, ptr, len)?;
+                    log::info!("Guest log: {}", msg);
+                    state.lock().unwrap().logs.push(msg);
+                    Ok(())
+                }
+            ),
+        );
+        ns.insert(
+            "abort",
+            func!(|ctx: &mut Ctx, ptr: u32, len: u32| -> Result<(), String> {
+                Err(format!("Guest aborted: {}", read_str(ctx, ptr, len)?))
+            }),
+        );
+        let state = self.state.clone();
+        ns.insert(
+            "panic",
+            func!(
+                move |ctx: &mut Ctx, ptr: u32, len: u32| -> Result<(), String> {
+                    let msg = read_str(ctx, ptr, len)?;
+                    state.lock().unwrap().panic_message = Some(msg);
+                    Ok(())
+                }
+            ),
+        );
+        ns
+    }
+
+    fn clock_namespace(&self) -> Namespace {
+        let mut ns = Namespace::new();
+        ns.insert("now_ms", func!(|| unix_time().as_millis() as i64));
+        let started = self.started;
+        ns.insert(
+            "monotonic_ns",
+            func!(move || started.elapsed().as_nanos() as i64),
+        );
+        ns
+    }
+
+    fn random_namespace(&self) -> Namespace {
+        let mut ns = Namespace::new();
+        let rng = self.rng.clone();
+        ns.insert("next_u64", func!(move || rng.lock().unwrap().next() as i64));
+        let rng = self.rng.clone();
+        ns.insert(
+            "next_f64",
+            // Uses the upper 53 bits to generate a float in the range [0, 1).
+            func!(move || (rng.lock().unwrap().next() >> 11) as f64 / (1u64 << 53) as f64),
+        );
+        ns
+    }
+
+    fn kv_namespace(&self, scope: &str) -> Result<Namespace, Error> {
+        let kv = self
+            .kv
+            .clone()
+            .ok_or_else(|| anyhow!("Host namespace kv is not supported by the store backend"))?;
+        let mut ns = Namespace::new();
+        let (get_kv, get_scope) = (kv.clone(), scope.to_owned());
+        ns.insert(
+            "get",
+            // Copies up to `val_cap` bytes of the value into guest memory, and returns the full
+            // length of the value, or -1 if the key doesn't exist.
+            func!(move |ctx: &mut Ctx,
+                        key_ptr: u32,
+                        key_len: u32,
+                        val_ptr: u32,
+                        val_cap: u32|
+                  -> Result<i32, String> {
+                let key = read_bytes(ctx, key_ptr, key_len)?;
+                match get_kv.get(&get_scope, &key).map_err(|e| e.to_string())? {
+                    Some(value) => {
+                        let len = value.len().min(val_cap as usize);
+                        write_bytes(ctx, val_ptr, &value[..len])?;
+                        Ok(value.len() as i32)
+                    }
+                    None => Ok(-1),
+                }
+            }),
+        );
+        let (set_kv, set_scope) = (kv.clone(), scope.to_owned());
+        ns.insert(
+            "set",
+            func!(move |ctx: &mut Ctx,
+                        key_ptr: u32,
+                        key_len: u32,
+                        val_ptr: u32,
+                        val_len: u32|
+                  -> Result<(), String> {
+                let key = read_bytes(ctx, key_ptr, key_len)?;
+                let value = read_bytes(ctx, val_ptr, val_len)?;
+                set_kv
+                    .set(&set_scope, &key, &value)
+                    .map_err(|e| e.to_string())
+            }),
+        );
+        let scope = scope.to_owned();
+        ns.insert(
+            "delete",
+            // Returns 1 if the key existed, 0 otherwise.
+            func!(
+                move |ctx: &mut Ctx, key_ptr: u32, key_len: u32| -> Result<i32, String> {
+                    let key = read_bytes(ctx, key_ptr, key_len)?;
+                    let existed = kv.delete(&scope, &key).map_err(|e| e.to_string())?;
+                    Ok(existed as i32)
+                }
+            ),
+        );
+        Ok(ns)
+    }
+
+    fn modules_namespace(&self) -> Result<Namespace, Error> {
+        let loader = self.modules.clone().ok_or_else(|| {
+            anyhow!("Host namespace modules is not supported by the store backend")
+        })?;
+        let host = self.clone();
+        let mut ns = Namespace::new();
+        ns.insert(
+            "call",
+            // Args are 8 byte little endian values, converted to the types of the function
+            // params. Returns the first result of the function, or 0 if it has none.
+            func!(move |ctx: &mut Ctx,
+                        name_ptr: u32,
+                        name_len: u32,
+                        fn_ptr: u32,
+                        fn_len: u32,
+                        args_ptr: u32,
+                        args_len: u32|
+                  -> Result<i64, String> {
+                let name = read_str(ctx, name_ptr, name_len)?;
+                let fn_name = read_str(ctx, fn_ptr, fn_len)?;
+                let args = read_bytes(ctx, args_ptr, args_len)?;
+                host.call_module(&loader, &name, &fn_name, &args)
+                    .map_err(|e| e.to_string())
+            }),
+        );
+        Ok(ns)
+    }
+
+    /// Calls a function of a registered module, within the call budget of the request. There is
+    /// no fuel budget, as metering isn't available with the cranelift backend.
+    fn call_module(
+        &self,
+        loader: &ModuleLoader,
+        name: &str,
+        fn_name: &str,
+        args: &[u8],
+    ) -> Result<i64, Error> {
+        let _depth = DepthGuard::enter(&self.call_depth)?;
+        if self.call_count.fetch_add(1, Ordering::SeqCst) >= MAX_MODULE_CALLS {
+            return Err(anyhow!(
+                "Exceeded the limit of {} module calls",
+                MAX_MODULE_CALLS
+            ));
+        }
+        self.check_time_budget()?;
+
+        let instance = loader(name, self)?;
+        let function: DynFunc = instance.exports.get(fn_name)?;
+        let types = function.signature().params();
+        if args.len() != types.len() * 8 {
+            return Err(anyhow!(
+                "Invalid args length for {}.{}, got {} bytes and needed {}",
+                name,
+                fn_name,
+                args.len(),
+                types.len() * 8
+            ));
+        }
+        let params = args
+            .chunks(8)
+            .zip(types)
+            .map(|(bytes, t)| {
+                let mut buf = [0u8; 8];
+                buf.copy_from_slice(bytes);
+                let bits = u64::from_le_bytes(buf);
+                match t {
+                    Type::I32 => Ok(WasmValue::I32(bits as i32)),
+                    Type::I64 => Ok(WasmValue::I64(bits as i64)),
+                    Type::F32 => Ok(WasmValue::F32(f64::from_bits(bits) as f32)),
+                    Type::F64 => Ok(WasmValue::F64(f64::from_bits(bits))),
+                    Type::V128 => Err(anyhow!("V128 params are not supported for module calls")),
+                }
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        let results = function.call(&params).map_err(|e| anyhow!("{}", e))?;
+        self.check_time_budget()?;
+
+        match results.first() {
+            None => Ok(0),
+            Some(WasmValue::I32(v)) => Ok(i64::from(*v)),
+            Some(WasmValue::I64(v)) => Ok(*v),
+            Some(WasmValue::F32(v)) => Ok(f64::from(*v).to_bits() as i64),
+            Some(WasmValue::F64(v)) => Ok(v.to_bits() as i64),
+            Some(WasmValue::V128(_)) => {
+                Err(anyhow!("V128 results are not supported for module calls"))
+            }
+        }
+    }
+
+    fn check_time_budget(&self) -> Result<(), Error> {
+        if self.started.elapsed() > CALL_TIME_BUDGET {
+            return Err(time_budget_error());
+        }
+        Ok(())
+    }
+}
+
+fn time_budget_error() -> Error {
+    anyhow!(
+        "Exceeded the time budget of {:?} for module calls",
+        CALL_TIME_BUDGET
+    )
+}
+
+/// Tracks the depth of nested module calls, decremented when dropped.
+struct DepthGuard<'a>(&'a AtomicUsize);
+
+impl<'a> DepthGuard<'a> {
+    fn enter(depth: &'a AtomicUsize) -> Result<Self, Error> {
+        if depth.fetch_add(1, Ordering::SeqCst) >= MAX_CALL_DEPTH {
+            depth.fetch_sub(1, Ordering::SeqCst);
+            return Err(anyhow!(
+                "Exceeded the maximum module call depth of {}",
+                MAX_CALL_DEPTH
+            ));
+        }
+        Ok(Self(depth))
+    }
+}
+
+impl Drop for DepthGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Returns the guest's memory view, if it has a memory.
+fn memory_view(ctx: &Ctx) -> Result<MemoryView<'_, u8>, String> {
+    // Safety: the module pointer is valid for the lifetime of the context.
+    let info = unsafe { &(*ctx.module).info };
+    if info.memories.is_empty() && info.imported_memories.is_empty() {
+        return Err("Guest has no memory".to_owned());
+    }
+    Ok(ctx.memory(0).view::<u8>())
+}
+
+/// Reads bytes from the guest's memory.
+fn read_bytes(ctx: &Ctx, ptr: u32, len: u32) -> Result<Vec<u8>, String> {
+    let view = memory_view(ctx)?;
+    let start = ptr as usize;
+    let bytes = start
+        .checked_add(len as usize)
+        .and_then(|end| view.get(start..end))
+        .ok_or_else(|| format!("Memory access out of bounds: {} + {}", ptr, len))?;
+    Ok(bytes.iter().map(|b| b.get()).collect())
+}
+
+/// Writes bytes to the guest's memory.
+fn write_bytes(ctx: &Ctx, ptr: u32, bytes: &[u8]) -> Result<(), String> {
+    let view = memory_view(ctx)?;
+    let start = ptr as usize;
+    let cells = start
+        .checked_add(bytes.len())
+        .and_then(|end| view.get(start..end))
+        .ok_or_else(|| format!("Memory access out of bounds: {} + {}", ptr, bytes.len()))?;
+    for (cell, &b) in cells.iter().zip(bytes) {
+        cell.set(b);
+    }
+    Ok(())
+}
+
+/// Reads a utf8 string from the guest's memory.
+fn read_str(ctx: &Ctx, ptr: u32, len: u32) -> Result<String, String> {
+    String::from_utf8(read_bytes(ctx, ptr, len)?).map_err(|e| e.to_string())
+}
+
+fn unix_time() -> std::time::Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+/// Small deterministic random generator, to give reproducible values for a seed.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
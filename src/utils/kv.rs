@@ -0,0 +1,142 @@
+use anyhow::{anyhow, Error};
+use sled::{Batch, Tree};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// Maximum size of a key stored by a guest.
+pub const MAX_KEY_SIZE: usize = 1024;
+/// Maximum size of a value stored by a guest.
+pub const MAX_VALUE_SIZE: usize = 16 * 1024;
+/// Maximum total bytes of keys and values stored for a single module.
+pub const MAX_MODULE_BYTES: usize = 1024 * 1024;
+
+/// Separates the module name from the key. This byte is never in a utf8 string, so keys can't
+/// collide between modules.
+const SCOPE_SEPARATOR: u8 = 0xff;
+
+/// Key-value storage for guests, where keys are scoped by the module name.
+///
+/// If transactional, writes are kept in memory until committed, which should only be done after
+/// the guest call returns successfully.
+pub struct GuestKv {
+    tree: Tree,
+    transactional: bool,
+    pending: Mutex<BTreeMap<Vec<u8>, Option<Vec<u8>>>>,
+}
+
+impl GuestKv {
+    // Only the sled store provides guest storage.
+    #[cfg_attr(feature = "p2p", allow(dead_code))]
+    pub fn new(tree: Tree, transactional: bool) -> Self {
+        Self {
+            tree,
+            transactional,
+            pending: Default::default(),
+        }
+    }
+
+    /// Gets the value for the key in the module's scope.
+    pub fn get(&self, scope: &str, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let key = scoped_key(scope, key);
+        if let Some(pending) = self.pending.lock().unwrap().get(&key) {
+            return Ok(pending.clone());
+        }
+        Ok(self.tree.get(key)?.map(|v| v.to_vec()))
+    }
+
+    /// Sets the value for the key in the module's scope, if within the size quotas.
+    pub fn set(&self, scope: &str, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        if key.len() > MAX_KEY_SIZE {
+            return Err(anyhow!(
+                "Key size {} exceeds the limit of {} bytes",
+                key.len(),
+                MAX_KEY_SIZE
+            ));
+        }
+        if value.len() > MAX_VALUE_SIZE {
+            return Err(anyhow!(
+                "Value size {} exceeds the limit of {} bytes",
+                value.len(),
+                MAX_VALUE_SIZE
+            ));
+        }
+
+        let key = scoped_key(scope, key);
+        let mut pending = self.pending.lock().unwrap();
+        let mut entries = self.scope_entries(scope, &pending)?;
+        entries.insert(key.clone(), key.len() + value.len());
+        let used: usize = entries.values().sum();
+        if used > MAX_MODULE_BYTES {
+            return Err(anyhow!(
+                "Storage quota of {} bytes exceeded for module {}",
+                MAX_MODULE_BYTES,
+                scope
+            ));
+        }
+
+        if self.transactional {
+            pending.insert(key, Some(value.to_vec()));
+        } else {
+            self.tree.insert(key, value)?;
+        }
+        Ok(())
+    }
+
+    /// Deletes the key in the module's scope. Returns true if the key existed.
+    pub fn delete(&self, scope: &str, key: &[u8]) -> Result<bool, Error> {
+        let existed = self.get(scope, key)?.is_some();
+        let key = scoped_key(scope, key);
+        if self.transactional {
+            self.pending.lock().unwrap().insert(key, None);
+        } else {
+            self.tree.remove(key)?;
+        }
+        Ok(existed)
+    }
+
+    /// Atomically applies the pending writes of a transactional session.
+    pub fn commit(&self) -> Result<(), Error> {
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        let mut batch = Batch::default();
+        for (key, value) in pending {
+            match value {
+                Some(value) => batch.insert(key, value),
+                None => batch.remove(key),
+            }
+        }
+        self.tree.apply_batch(batch)?;
+        Ok(())
+    }
+
+    /// Sizes of the entries in the module's scope, including the pending writes.
+    fn scope_entries(
+        &self,
+        scope: &str,
+        pending: &BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    ) -> Result<BTreeMap<Vec<u8>, usize>, Error> {
+        let prefix = scoped_key(scope, &[]);
+        let mut entries = BTreeMap::new();
+        for entry in self.tree.scan_prefix(&prefix) {
+            let (k, v) = entry?;
+            entries.insert(k.to_vec(), k.len() + v.len());
+        }
+        for (k, v) in pending.range(prefix.clone()..) {
+            if !k.starts_with(&prefix) {
+                break;
+            }
+            match v {
+                Some(v) => entries.insert(k.clone(), k.len() + v.len()),
+                None => entries.remove(k),
+            };
+        }
+        Ok(entries)
+    }
+}
+
+fn scoped_key(scope: &str, key: &[u8]) -> Vec<u8> {
+    let mut scoped = Vec::with_capacity(scope.len() + 1 + key.len());
+    scoped.extend_from_slice(scope.as_bytes());
+    scoped.push(SCOPE_SEPARATOR);
+    scoped.extend_from_slice(key);
+    scoped
+}
@@ -0,0 +1,433 @@
+use super::expiry;
+use super::kv::GuestKv;
+use super::record::{decode_module, encode_module};
+use super::{ReadinessCheck, StoreStats, WasmModule, WasmModuleRef, WasmStore};
+use crate::logger;
+use crate::metrics::METRICS;
+use anyhow::{anyhow, Error};
+use futures::future::join;
+use sled::Tree;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::Mutex;
+use tide::utils::async_trait;
+
+/// Copies of modules kept in front of a store by [`Cached`]. Stored modules never change, so
+/// cached copies stay valid until the module is removed through the store or expires. Operations
+/// are async so that caches doing disk I/O don't block the executor.
+#[async_trait]
+pub trait ModuleCache: Send + Sync {
+    /// Name of the cache in metrics.
+    fn label(&self) -> &'static str;
+
+    async fn get(&self, name: &str) -> Result<Option<WasmModule>, Error>;
+
+    /// Adds the module, evicting others if the cache is full.
+    async fn insert(&self, name: &str, module: &WasmModule) -> Result<(), Error>;
+
+    async fn remove(&self, name: &str) -> Result<(), Error>;
+}
+
+/// Least recently used modules, in memory.
+pub struct MemoryCache {
+    capacity: usize,
+    lru: Mutex<Lru>,
+}
+
+#[derive(Default)]
+struct Lru {
+    /// Modules with the tick of their last use.
+    modules: HashMap<String, (u64, WasmModule)>,
+    /// Names by the tick of their last use, from the least to the most recently used.
+    order: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+impl Lru {
+    fn get(&mut self, name: &str) -> Option<WasmModule> {
+        let tick = self.next_tick();
+        let (used, module) = self.modules.get_mut(name)?;
+        self.order.remove(used);
+        self.order.insert(tick, name.to_owned());
+        *used = tick;
+        Some(module.clone())
+    }
+
+    fn insert(&mut self, name: &str, module: WasmModule) {
+        let tick = self.next_tick();
+        if let Some((used, _)) = self.modules.insert(name.to_owned(), (tick, module)) {
+            self.order.remove(&used);
+        }
+        self.order.insert(tick, name.to_owned());
+    }
+
+    fn remove(&mut self, name: &str) {
+        if let Some((used, _)) = self.modules.remove(name) {
+            self.order.remove(&used);
+        }
+    }
+
+    fn evict_oldest(&mut self) {
+        let oldest = self.order.keys().next().copied();
+        if let Some(name) = oldest.and_then(|tick| self.order.remove(&tick)) {
+            self.modules.remove(&name);
+        }
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+impl MemoryCache {
+    /// Creates a cache of up to `capacity` modules.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            lru: Mutex::new(Lru::default()),
+        }
+    }
+}
+
+#[async_trait]
+impl ModuleCache for MemoryCache {
+    fn label(&self) -> &'static str {
+        "memory"
+    }
+    async fn get(&self, name: &str) -> Result<Option<WasmModule>, Error> {
+        Ok(self.lru.lock().unwrap().get(name))
+    }
+    async fn insert(&self, name: &str, module: &WasmModule) -> Result<(), Error> {
+        if self.capacity == 0 {
+            return Ok(());
+        }
+        let mut lru = self.lru.lock().unwrap();
+        lru.insert(name, module.clone());
+        while lru.modules.len() > self.capacity {
+            lru.evict_oldest();
+        }
+        Ok(())
+    }
+    async fn remove(&self, name: &str) -> Result<(), Error> {
+        self.lru.lock().unwrap().remove(name);
+        Ok(())
+    }
+}
+
+/// Module records in a sled tree, kept until removed or expired. Operations run on the blocking
+/// thread pool.
+pub struct SledCache(pub Tree);
+
+#[async_trait]
+impl ModuleCache for SledCache {
+    fn label(&self) -> &'static str {
+        "sled"
+    }
+    async fn get(&self, name: &str) -> Result<Option<WasmModule>, Error> {
+        let (tree, name) = (self.0.clone(), name.to_owned());
+        logger::spawn_blocking(move || match tree.get(name)? {
+            Some(bytes) => Ok(Some(decode_module(&bytes)?.0)),
+            None => Ok(None),
+        })
+        .await
+    }
+    async fn insert(&self, name: &str, module: &WasmModule) -> Result<(), Error> {
+        let (tree, name, record) = (self.0.clone(), name.to_owned(), encode_module(module)?);
+        logger::spawn_blocking(move || {
+            tree.insert(name, record)?;
+            Ok(())
+        })
+        .await
+    }
+    async fn remove(&self, name: &str) -> Result<(), Error> {
+        let (tree, name) = (self.0.clone(), name.to_owned());
+        logger::spawn_blocking(move || {
+            tree.remove(name)?;
+            Ok(())
+        })
+        .await
+    }
+}
+
+/// Store which serves loads from a cache, and fills it from the base store on misses. Failures of
+/// the cache only make loads go to the base store, and expired copies are removed and count as
+/// misses. Writes and removals go to the base store first, which decides whether a module exists.
+pub struct Cached<S, C> {
+    base: S,
+    cache: C,
+}
+
+impl<S, C> Cached<S, C> {
+    pub fn new(base: S, cache: C) -> Self {
+        Self { base, cache }
+    }
+}
+
+impl<S, C> Cached<S, C>
+where
+    C: ModuleCache,
+{
+    /// Returns the cached copy of the module, unless it has expired.
+    async fn cached(&self, name: &str) -> Option<WasmModule> {
+        match self.cache.get(name).await {
+            Ok(Some(module)) if module.expires.map_or(false, |e| e <= expiry::now()) => {
+                if let Err(e) = self.cache.remove(name).await {
+                    log::debug!(
+                        "Could not remove expired module {} from the cache: {}",
+                        name,
+                        e
+                    );
+                }
+                None
+            }
+            Ok(module) => module,
+            Err(e) => {
+                log::debug!("Could not read module {} from the cache: {}", name, e);
+                None
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<S, C> WasmStore for Cached<S, C>
+where
+    S: WasmStore,
+    C: ModuleCache,
+{
+    async fn load_module(&self, name: &str) -> Result<WasmModule, Error> {
+        if let Some(module) = self.cached(name).await {
+            METRICS.record_cache_lookup(self.cache.label(), true);
+            return Ok(module);
+        }
+        METRICS.record_cache_lookup(self.cache.label(), false);
+        let module = self.base.load_module(name).await?;
+        if let Err(e) = self.cache.insert(name, &module).await {
+            log::debug!("Could not cache module {}: {}", name, e);
+        }
+        Ok(module)
+    }
+    async fn contains_module(&self, name: &str) -> Result<bool, Error> {
+        if self.cached(name).await.is_some() {
+            return Ok(true);
+        }
+        self.base.contains_module(name).await
+    }
+    async fn put_module(&self, name: &str, module: &WasmModuleRef<'_, '_>) -> Result<(), Error> {
+        self.base.put_module(name, module).await?;
+        if let Err(e) = self.cache.insert(name, &module.to_module()).await {
+            log::debug!("Could not cache module {}: {}", name, e);
+        }
+        Ok(())
+    }
+    async fn module_names(&self) -> Result<Vec<String>, Error> {
+        self.base.module_names().await
+    }
+    async fn remove_module(&self, name: &str) -> Result<(), Error> {
+        self.base.remove_module(name).await?;
+        self.cache.remove(name).await
+    }
+    async fn guest_kv(&self, transactional: bool) -> Result<Option<GuestKv>, Error> {
+        self.base.guest_kv(transactional).await
+    }
+    async fn stats(&self) -> Result<Option<StoreStats>, Error> {
+        self.base.stats().await
+    }
+    async fn readiness_checks(&self) -> Vec<ReadinessCheck> {
+        self.base.readiness_checks().await
+    }
+}
+
+/// Store whose modules are those of a read-only overlay, then those of the writable base store.
+/// Modules of the overlay hide modules of the base with the same name, and can't be registered
+/// again or removed.
+pub struct Overlay<R, W> {
+    overlay: R,
+    base: W,
+}
+
+impl<R, W> Overlay<R, W> {
+    pub fn new(overlay: R, base: W) -> Self {
+        Self { overlay, base }
+    }
+}
+
+impl<R, W> Overlay<R, W>
+where
+    R: WasmStore,
+{
+    async fn check_writable(&self, name: &str) -> Result<(), Error> {
+        if self.overlay.contains_module(name).await? {
+            return Err(anyhow!("Module {} is in the read-only overlay", name));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<R, W> WasmStore for Overlay<R, W>
+where
+    R: WasmStore,
+    W: WasmStore,
+{
+    async fn load_module(&self, name: &str) -> Result<WasmModule, Error> {
+        if self.overlay.contains_module(name).await? {
+            self.overlay.load_module(name).await
+        } else {
+            self.base.load_module(name).await
+        }
+    }
+    async fn contains_module(&self, name: &str) -> Result<bool, Error> {
+        Ok(self.overlay.contains_module(name).await? || self.base.contains_module(name).await?)
+    }
+    async fn put_module(&self, name: &str, module: &WasmModuleRef<'_, '_>) -> Result<(), Error> {
+        self.check_writable(name).await?;
+        self.base.put_module(name, module).await
+    }
+    async fn module_names(&self) -> Result<Vec<String>, Error> {
+        let (overlay, base) = join(self.overlay.module_names(), self.base.module_names()).await;
+        let mut names: BTreeSet<String> = overlay?.into_iter().collect();
+        names.extend(base?);
+        Ok(names.into_iter().collect())
+    }
+    async fn remove_module(&self, name: &str) -> Result<(), Error> {
+        self.check_writable(name).await?;
+        self.base.remove_module(name).await
+    }
+    async fn guest_kv(&self, transactional: bool) -> Result<Option<GuestKv>, Error> {
+        self.base.guest_kv(transactional).await
+    }
+    async fn stats(&self) -> Result<Option<StoreStats>, Error> {
+        self.base.stats().await
+    }
+    async fn readiness_checks(&self) -> Vec<ReadinessCheck> {
+        let (mut checks, base) = join(
+            self.overlay.readiness_checks(),
+            self.base.readiness_checks(),
+        )
+        .await;
+        checks.extend(base);
+        checks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::local_db::LocalDB;
+    use crate::utils::{Blocking, ModuleMetadata};
+    use std::borrow::Cow;
+
+    fn db() -> sled::Db {
+        sled::Config::new().temporary(true).open().unwrap()
+    }
+
+    fn store() -> Blocking<LocalDB> {
+        Blocking::new(LocalDB(db()))
+    }
+
+    async fn put<S: WasmStore>(store: &S, name: &str, code: &[u8]) -> Result<(), Error> {
+        let host_modules: &[Cow<str>] = &[];
+        store
+            .put_module(
+                name,
+                &WasmModuleRef {
+                    code,
+                    host_modules,
+                    param_names: &HashMap::new(),
+                    metadata: &ModuleMetadata::default(),
+                    expires: None,
+                },
+            )
+            .await
+    }
+
+    #[async_std::test]
+    async fn memory_cache_evicts_least_recently_used() {
+        let cache = MemoryCache::new(2);
+        let module = |code: &[u8]| WasmModule {
+            code: code.to_vec(),
+            host_modules: Vec::new(),
+            param_names: HashMap::new(),
+            metadata: ModuleMetadata::default(),
+            expires: None,
+        };
+        cache.insert("a", &module(b"a")).await.unwrap();
+        cache.insert("b", &module(b"b")).await.unwrap();
+        assert!(cache.get("a").await.unwrap().is_some());
+        cache.insert("c", &module(b"c")).await.unwrap();
+        assert!(cache.get("a").await.unwrap().is_some());
+        assert!(cache.get("b").await.unwrap().is_none());
+        assert!(cache.get("c").await.unwrap().is_some());
+
+        // Replacing a module uses it, and removed modules leave room
+        cache.insert("a", &module(b"new")).await.unwrap();
+        cache.remove("c").await.unwrap();
+        cache.insert("b", &module(b"b")).await.unwrap();
+        assert_eq!(cache.get("a").await.unwrap().unwrap().code, b"new");
+        cache.insert("d", &module(b"d")).await.unwrap();
+        assert!(cache.get("b").await.unwrap().is_none());
+        assert!(cache.get("a").await.unwrap().is_some());
+        let lru = cache.lru.lock().unwrap();
+        assert_eq!(lru.order.len(), lru.modules.len());
+    }
+
+    #[async_std::test]
+    async fn cached_store() {
+        let base = store();
+        put(&base, "utils", b"code").await.unwrap();
+        let cache = SledCache(db().open_tree("cache").unwrap());
+        let cached = Cached::new(base, cache);
+
+        // The cache fills on loads from the base store
+        assert_eq!(cached.load_module("utils").await.unwrap().code, b"code");
+        assert!(cached.cache.get("utils").await.unwrap().is_some());
+        assert_eq!(cached.load_module("utils").await.unwrap().code, b"code");
+
+        // Writes are unique, and cached
+        assert!(put(&cached, "utils", b"other").await.is_err());
+        put(&cached, "new", b"new").await.unwrap();
+        assert!(cached.cache.get("new").await.unwrap().is_some());
+        assert!(cached.base.contains_module("new").await.unwrap());
+
+        cached.remove_module("utils").await.unwrap();
+        assert!(!cached.contains_module("utils").await.unwrap());
+        assert!(cached.load_module("utils").await.is_err());
+
+        // Expired copies are misses, and dropped from the cache, like records the DHT dropped
+        let expired = WasmModule {
+            code: b"expired".to_vec(),
+            host_modules: Vec::new(),
+            param_names: HashMap::new(),
+            metadata: ModuleMetadata::default(),
+            expires: Some(1),
+        };
+        cached.cache.insert("gone", &expired).await.unwrap();
+        assert!(!cached.contains_module("gone").await.unwrap());
+        assert!(cached.cache.get("gone").await.unwrap().is_none());
+        cached.cache.insert("gone", &expired).await.unwrap();
+        assert!(cached.load_module("gone").await.is_err());
+        assert!(cached.cache.get("gone").await.unwrap().is_none());
+    }
+
+    #[async_std::test]
+    async fn overlay_store() {
+        let overlay = store();
+        put(&overlay, "shared", b"overlay").await.unwrap();
+        let base = store();
+        put(&base, "shared", b"base").await.unwrap();
+        put(&base, "own", b"own").await.unwrap();
+        let store = Overlay::new(overlay, base);
+
+        assert_eq!(store.load_module("shared").await.unwrap().code, b"overlay");
+        assert_eq!(store.load_module("own").await.unwrap().code, b"own");
+        assert_eq!(store.module_names().await.unwrap(), ["own", "shared"]);
+
+        // Modules of the overlay can't be written or removed
+        assert!(put(&store, "shared", b"new").await.is_err());
+        assert!(store.remove_module("shared").await.is_err());
+        put(&store, "new", b"new").await.unwrap();
+        assert!(store.base.contains_module("new").await.unwrap());
+        assert!(put(&store, "new", b"again").await.is_err());
+    }
+}
@@ -0,0 +1,459 @@
+use super::namespace::namespace_of;
+use super::{Unsupported, WasmModuleRef, WasmStore};
+use crate::namespaces::NamespaceConfig;
+use anyhow::Error;
+use futures::future::try_join_all;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_cbor::{from_slice, to_vec};
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionError,
+    TransactionalTree,
+};
+use sled::{Batch, Db, Tree};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+/// Name of the sled tree of the usage counters.
+const USAGE_TREE: &str = "usage";
+/// Key of the usage of the whole store.
+const STORE_COUNTER: &str = "store";
+/// Prefix of the keys of the usage of each namespace.
+const NAMESPACE_PREFIX: &str = "namespace:";
+/// Prefix of the keys of the usage of each API key, by key id.
+const OWNER_PREFIX: &str = "owner:";
+
+/// Limits on the modules stored with [`store_wasm_module`](super::store_wasm_module). Sizes are
+/// of the wasm code in bytes.
+#[derive(Serialize, Deserialize, JsonSchema, Default, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct StoreLimits {
+    pub max_module_size: Option<u64>,
+    /// Maximum number of host modules of a module.
+    pub max_host_modules: Option<usize>,
+    /// Maximum total size of all modules.
+    pub max_store_size: Option<u64>,
+    /// Maximum total size of the modules registered by each API key.
+    pub max_owner_size: Option<u64>,
+}
+
+/// Module rejected by a limit of the store.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LimitError {
+    /// The module itself is over a limit.
+    TooLarge(String),
+    /// Storing the module would exceed a quota.
+    QuotaExceeded(String),
+}
+
+impl fmt::Display for LimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitError::TooLarge(msg) | LimitError::QuotaExceeded(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl std::error::Error for LimitError {}
+
+/// Number of modules and total size of their code.
+#[derive(Serialize, Deserialize, JsonSchema, Default, Debug, Clone, Copy, PartialEq)]
+pub struct Usage {
+    pub modules: usize,
+    pub bytes: u64,
+}
+
+impl Usage {
+    pub fn add(&mut self, size: usize) {
+        self.modules += 1;
+        self.bytes += size as u64;
+    }
+
+    pub fn remove(&mut self, size: usize) {
+        self.modules = self.modules.saturating_sub(1);
+        self.bytes = self.bytes.saturating_sub(size as u64);
+    }
+}
+
+impl StoreLimits {
+    /// Returns a [`LimitError`] if the module is over a limit. Quotas are checked when the module
+    /// is charged to the [`UsageCounters`].
+    pub fn check(&self, module: &WasmModuleRef<'_, '_>) -> Result<(), Error> {
+        let size = module.code.len() as u64;
+        if let Some(max) = self.max_module_size {
+            if size > max {
+                return Err(LimitError::TooLarge(format!(
+                    "Module is {} bytes, the maximum is {}",
+                    size, max
+                ))
+                .into());
+            }
+        }
+        if let Some(max) = self.max_host_modules {
+            if module.host_modules.len() > max {
+                return Err(LimitError::TooLarge(format!(
+                    "Module has {} host modules, the maximum is {}",
+                    module.host_modules.len(),
+                    max
+                ))
+                .into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Usage of the store, of each namespace with modules and of each API key owning modules.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct UsageTotals {
+    pub store: Usage,
+    pub namespaces: BTreeMap<String, Usage>,
+    /// Usage by key id.
+    pub owners: BTreeMap<String, Usage>,
+}
+
+/// Counters a stored module is charged to, with the quotas of its namespace.
+pub struct Charge<'a> {
+    pub counters: &'a UsageCounters,
+    /// Id of the key registering the module.
+    pub owner: Option<&'a str>,
+    pub namespace: NamespaceConfig,
+}
+
+/// Running usage of the store, of each namespace and of each API key, kept in a sled tree. The
+/// counters are updated when modules are stored and removed, so quotas are checked without
+/// loading the modules.
+pub struct UsageCounters {
+    tree: Tree,
+}
+
+fn abort<E: Into<Error>>(e: E) -> ConflictableTransactionError<Error> {
+    ConflictableTransactionError::Abort(e.into())
+}
+
+fn read(tx: &TransactionalTree, key: &str) -> ConflictableTransactionResult<Usage, Error> {
+    match tx.get(key)? {
+        Some(bytes) => from_slice(&bytes).map_err(abort),
+        None => Ok(Usage::default()),
+    }
+}
+
+/// Writes the counter, removing it once empty. The store counter is kept, as it marks the
+/// counters as initialized.
+fn write(
+    tx: &TransactionalTree,
+    key: &str,
+    usage: &Usage,
+) -> ConflictableTransactionResult<(), Error> {
+    if usage.modules == 0 && key != STORE_COUNTER {
+        tx.remove(key)?;
+    } else {
+        tx.insert(key, to_vec(usage).map_err(abort)?)?;
+    }
+    Ok(())
+}
+
+fn transaction_error(e: TransactionError<Error>) -> Error {
+    match e {
+        TransactionError::Abort(e) => e,
+        TransactionError::Storage(e) => e.into(),
+    }
+}
+
+fn namespace_counter(namespace: &str) -> String {
+    format!("{}{}", NAMESPACE_PREFIX, namespace)
+}
+
+fn owner_counter(owner: &str) -> String {
+    format!("{}{}", OWNER_PREFIX, owner)
+}
+
+impl UsageCounters {
+    pub fn new(db: &Db) -> Result<Self, Error> {
+        Ok(Self {
+            tree: db.open_tree(USAGE_TREE)?,
+        })
+    }
+
+    /// Counts the modules of the store unless the counters were initialized before, like for a
+    /// database used before usage was counted. `owners` are the owning key ids by module name.
+    /// Stores which can't be listed, like the DHT, start from zero. On other errors the counters
+    /// are left uninitialized, to be counted on the next call.
+    pub async fn init<S>(&self, store: &S, owners: &HashMap<String, String>) -> Result<(), Error>
+    where
+        S: WasmStore + ?Sized,
+    {
+        if self.tree.contains_key(STORE_COUNTER)? {
+            return Ok(());
+        }
+        let names = match store.module_names().await {
+            Ok(names) => names,
+            Err(e) if e.is::<Unsupported>() => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        let modules = try_join_all(names.iter().map(|name| store.load_module(name))).await?;
+        let mut totals = UsageTotals::default();
+        for (name, module) in names.iter().zip(modules) {
+            let size = module.code.len();
+            totals.store.add(size);
+            totals
+                .namespaces
+                .entry(namespace_of(name).to_owned())
+                .or_default()
+                .add(size);
+            if let Some(owner) = owners.get(name) {
+                totals.owners.entry(owner.clone()).or_default().add(size);
+            }
+        }
+
+        let mut batch = Batch::default();
+        for (namespace, usage) in &totals.namespaces {
+            batch.insert(namespace_counter(namespace).as_str(), to_vec(usage)?);
+        }
+        for (owner, usage) in &totals.owners {
+            batch.insert(owner_counter(owner).as_str(), to_vec(usage)?);
+        }
+        batch.insert(STORE_COUNTER, to_vec(&totals.store)?);
+        self.tree.apply_batch(batch)?;
+        Ok(())
+    }
+
+    /// Adds a module of `size` bytes to the counters of the store, its namespace and its owner.
+    /// The quotas are checked in the same transaction, so concurrent registrations can't exceed
+    /// them. Returns a [`LimitError`] if a quota would be exceeded.
+    pub fn charge(
+        &self,
+        name: &str,
+        size: u64,
+        limits: &StoreLimits,
+        charge: &Charge<'_>,
+    ) -> Result<(), Error> {
+        let namespace = namespace_of(name);
+        let namespace_key = namespace_counter(namespace);
+        let owner_key = charge.owner.map(owner_counter);
+        self.tree
+            .transaction(|tx| {
+                let mut store = read(tx, STORE_COUNTER)?;
+                if let Some(max) = limits.max_store_size {
+                    if store.bytes + size > max {
+                        return Err(abort(LimitError::QuotaExceeded(format!(
+                            "Store quota of {} bytes exceeded, {} are used",
+                            max, store.bytes
+                        ))));
+                    }
+                }
+                let mut used = read(tx, &namespace_key)?;
+                if let Some(max) = charge.namespace.max_modules {
+                    if used.modules >= max {
+                        return Err(abort(LimitError::QuotaExceeded(format!(
+                            "Namespace {} has reached its quota of {} modules",
+                            namespace, max
+                        ))));
+                    }
+                }
+                if let Some(max) = charge.namespace.max_bytes {
+                    if used.bytes + size > max {
+                        return Err(abort(LimitError::QuotaExceeded(format!(
+                            "Namespace {} would exceed its quota of {} bytes, {} are used",
+                            namespace, max, used.bytes
+                        ))));
+                    }
+                }
+                if let Some(key) = &owner_key {
+                    let mut owned = read(tx, key)?;
+                    if let Some(max) = limits.max_owner_size {
+                        if owned.bytes + size > max {
+                            return Err(abort(LimitError::QuotaExceeded(format!(
+                                "Owner quota of {} bytes exceeded, {} are used",
+                                max, owned.bytes
+                            ))));
+                        }
+                    }
+                    owned.add(size as usize);
+                    write(tx, key, &owned)?;
+                }
+                store.add(size as usize);
+                write(tx, STORE_COUNTER, &store)?;
+                used.add(size as usize);
+                write(tx, &namespace_key, &used)?;
+                Ok(())
+            })
+            .map_err(transaction_error)
+    }
+
+    /// Subtracts a removed module of `size` bytes from the counters of the store, its namespace
+    /// and its owner.
+    pub fn release(&self, name: &str, size: u64, owner: Option<&str>) -> Result<(), Error> {
+        let mut keys = vec![
+            STORE_COUNTER.to_owned(),
+            namespace_counter(namespace_of(name)),
+        ];
+        keys.extend(owner.map(owner_counter));
+        self.tree
+            .transaction(|tx| {
+                for key in &keys {
+                    let mut usage = read(tx, key)?;
+                    usage.remove(size as usize);
+                    write(tx, key, &usage)?;
+                }
+                Ok(())
+            })
+            .map_err(transaction_error)
+    }
+
+    /// Reads all counters.
+    pub fn totals(&self) -> Result<UsageTotals, Error> {
+        let mut totals = UsageTotals::default();
+        for entry in self.tree.iter() {
+            let (key, value) = entry?;
+            let key = String::from_utf8(key.to_vec())?;
+            let usage: Usage = from_slice(&value)?;
+            if key == STORE_COUNTER {
+                totals.store = usage;
+            } else if let Some(namespace) = key.strip_prefix(NAMESPACE_PREFIX) {
+                totals.namespaces.insert(namespace.to_owned(), usage);
+            } else if let Some(owner) = key.strip_prefix(OWNER_PREFIX) {
+                totals.owners.insert(owner.to_owned(), usage);
+            }
+        }
+        Ok(totals)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::local_db::LocalDB;
+    use crate::utils::{Blocking, ModuleMetadata, SyncWasmStore, WasmModule};
+
+    #[async_std::test]
+    async fn usage_counters() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let store = LocalDB(db.clone());
+        let module = WasmModuleRef {
+            code: b"code",
+            host_modules: &[],
+            param_names: &HashMap::new(),
+            metadata: &ModuleMetadata::default(),
+            expires: None,
+        };
+        store.put_module("a", &module).unwrap();
+        store.put_module("team/b", &module).unwrap();
+        let store = Blocking::new(store);
+
+        // Existing modules are counted once.
+        let counters = UsageCounters::new(&db).unwrap();
+        let mut owners = HashMap::new();
+        owners.insert("a".to_owned(), "key".to_owned());
+        counters.init(&store, &owners).await.unwrap();
+        counters.init(&store, &owners).await.unwrap();
+        let totals = counters.totals().unwrap();
+        assert_eq!(
+            totals.store,
+            Usage {
+                modules: 2,
+                bytes: 8
+            }
+        );
+        assert_eq!(
+            totals.namespaces["team"],
+            Usage {
+                modules: 1,
+                bytes: 4
+            }
+        );
+        assert_eq!(
+            totals.owners["key"],
+            Usage {
+                modules: 1,
+                bytes: 4
+            }
+        );
+
+        let limits = StoreLimits {
+            max_owner_size: Some(6),
+            ..StoreLimits::default()
+        };
+        let charge = Charge {
+            counters: &counters,
+            owner: Some("key"),
+            namespace: NamespaceConfig::default(),
+        };
+        let err = counters.charge("c", 4, &limits, &charge).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<LimitError>(),
+            Some(LimitError::QuotaExceeded(_))
+        ));
+        counters.release("a", 4, Some("key")).unwrap();
+        counters.charge("c", 4, &limits, &charge).unwrap();
+
+        counters.release("team/b", 4, None).unwrap();
+        let totals = counters.totals().unwrap();
+        assert_eq!(
+            totals.store,
+            Usage {
+                modules: 1,
+                bytes: 4
+            }
+        );
+        assert!(!totals.namespaces.contains_key("team"));
+        assert_eq!(
+            totals.owners["key"],
+            Usage {
+                modules: 1,
+                bytes: 4
+            }
+        );
+    }
+
+    /// Store without modules, which fails to list them unless listing isn't supported.
+    struct Unlisted {
+        supported: bool,
+    }
+
+    #[tide::utils::async_trait]
+    impl WasmStore for Unlisted {
+        async fn load_module(&self, name: &str) -> Result<WasmModule, Error> {
+            Err(anyhow::anyhow!("No module {}", name))
+        }
+        async fn contains_module(&self, _name: &str) -> Result<bool, Error> {
+            Ok(false)
+        }
+        async fn put_module(
+            &self,
+            _name: &str,
+            _module: &WasmModuleRef<'_, '_>,
+        ) -> Result<(), Error> {
+            Ok(())
+        }
+        async fn module_names(&self) -> Result<Vec<String>, Error> {
+            match self.supported {
+                true => Err(anyhow::anyhow!("Disk error")),
+                false => Err(Unsupported("Listing modules").into()),
+            }
+        }
+    }
+
+    #[async_std::test]
+    async fn init_errors() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let counters = UsageCounters::new(&db).unwrap();
+        let owners = HashMap::new();
+
+        // Failing to list modules leaves the counters uninitialized.
+        let err = counters
+            .init(&Unlisted { supported: true }, &owners)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Disk error");
+        assert!(counters.tree.is_empty());
+
+        // Stores which can't be listed start from zero.
+        counters
+            .init(&Unlisted { supported: false }, &owners)
+            .await
+            .unwrap();
+        assert_eq!(counters.totals().unwrap().store, Usage::default());
+        assert!(counters.tree.contains_key(STORE_COUNTER).unwrap());
+    }
+}
@@ -1,87 +1,585 @@
 extern crate serde;
 
+pub mod expiry;
+pub mod host;
+pub mod kv;
+pub mod layered;
+pub mod limits;
+pub mod namespace;
+pub mod record;
+pub mod trace;
 pub mod wasm;
 
+use crate::logger;
 use anyhow::{anyhow, Error};
-use serde_tuple::{Deserialize_tuple, Serialize_tuple};
+use futures::future::try_join_all;
+use host::HostEnv;
+use kv::GuestKv;
+use limits::{Charge, StoreLimits};
+use namespace::{namespace_of, resolve};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
-use wasmer_runtime::{instantiate, ImportObject, Instance};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::Arc;
+use std::time::Instant;
+use tide::utils::async_trait;
+use wasmer_runtime::{compile, ImportObject, Instance};
 
-/// Data layout for a wasm module.
-#[derive(Serialize_tuple, Deserialize_tuple)]
+/// Data layout for a wasm module. Stored in a versioned envelope, see [`record`].
+#[derive(Serialize, Deserialize, Clone)]
 pub struct WasmModule {
     /// Wasm code bytes.
+    #[serde(with = "serde_bytes")]
     pub code: Vec<u8>,
     /// Vector of dependency module names.
     pub host_modules: Vec<String>,
+    /// Parameter names of exported functions, used to call functions with named params.
+    #[serde(default)]
+    pub param_names: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    pub metadata: ModuleMetadata,
+    /// Unix timestamp in milliseconds after which the module can be removed, see [`expiry`].
+    #[serde(default)]
+    pub expires: Option<u64>,
 }
 
-#[derive(Serialize_tuple)]
+#[derive(Serialize)]
 pub struct WasmModuleRef<'a, 'm> {
+    #[serde(with = "serde_bytes")]
     pub code: &'a [u8],
     pub host_modules: &'a [Cow<'m, str>],
+    pub param_names: &'a HashMap<String, Vec<String>>,
+    pub metadata: &'a ModuleMetadata,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires: Option<u64>,
 }
 
-/// Interface to allow wasm modules to be loaded and stored with different backends.
-pub trait WasmStore {
+impl WasmModuleRef<'_, '_> {
+    /// Copies the module.
+    pub fn to_module(&self) -> WasmModule {
+        WasmModule {
+            code: self.code.to_vec(),
+            host_modules: self.host_modules.iter().map(|m| m.to_string()).collect(),
+            param_names: self.param_names.clone(),
+            metadata: self.metadata.clone(),
+            expires: self.expires,
+        }
+    }
+}
+
+/// Optional descriptive metadata of a module, given on registration.
+#[derive(Serialize, Deserialize, JsonSchema, Default, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ModuleMetadata {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    /// Tags the modules can be searched by.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub license: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_url: Option<String>,
+    /// Descriptions of exported functions.
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub functions: HashMap<String, String>,
+}
+
+impl ModuleMetadata {
+    /// Checks that tags are not empty and don't contain whitespace.
+    pub fn validate(&self) -> Result<(), Error> {
+        match self
+            .tags
+            .iter()
+            .find(|t| t.is_empty() || t.contains(char::is_whitespace))
+        {
+            Some(tag) => Err(anyhow!("Invalid tag {:?}", tag)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Size of a store, reported in metrics.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StoreStats {
+    pub modules: usize,
+    pub size_bytes: u64,
+}
+
+/// Result of a readiness check of a store.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+pub struct ReadinessCheck {
+    pub name: String,
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl ReadinessCheck {
+    /// Creates a check from the result of the check, with the error as the detail.
+    pub fn from_result(name: &str, res: Result<(), Error>) -> Self {
+        Self {
+            name: name.to_owned(),
+            ok: res.is_ok(),
+            detail: res.err().map(|e| e.to_string()),
+        }
+    }
+}
+
+/// Error of an operation the store backend doesn't support, like listing the modules of the DHT.
+#[derive(Debug)]
+pub struct Unsupported(pub &'static str);
+
+impl fmt::Display for Unsupported {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} is not supported by the store backend", self.0)
+    }
+}
+
+impl std::error::Error for Unsupported {}
+
+/// Interface to allow wasm modules to be loaded and stored with different backends. Operations
+/// are async so that backends doing network or disk I/O don't block the executor, see
+/// [`SyncWasmStore`] for backends with blocking I/O.
+#[async_trait]
+pub trait WasmStore: Send + Sync {
     /// Loads Wasm module from store.
+    async fn load_module(&self, name: &str) -> Result<WasmModule, Error>;
+
+    /// Checks if module already exists in the store, that is if loading it would find it.
+    async fn contains_module(&self, name: &str) -> Result<bool, Error>;
+
+    /// Stores wasm module in store. Fails if the module exists, so modules are never replaced, except
+    /// in the DHT, which can't check it atomically.
+    async fn put_module(&self, name: &str, module: &WasmModuleRef<'_, '_>) -> Result<(), Error>;
+
+    /// Lists the names of all modules in the store.
+    async fn module_names(&self) -> Result<Vec<String>, Error> {
+        Err(Unsupported("Listing modules").into())
+    }
+
+    /// Removes the module from the store.
+    async fn remove_module(&self, _name: &str) -> Result<(), Error> {
+        Err(Unsupported("Removing modules").into())
+    }
+
+    /// Opens the key-value storage for guests. Returns `None` if not supported by the backend.
+    async fn guest_kv(&self, _transactional: bool) -> Result<Option<GuestKv>, Error> {
+        Ok(None)
+    }
+
+    /// Returns the size of the store. Returns `None` if not supported by the backend.
+    async fn stats(&self) -> Result<Option<StoreStats>, Error> {
+        Ok(None)
+    }
+
+    /// Checks that the store is able to serve requests.
+    async fn readiness_checks(&self) -> Vec<ReadinessCheck> {
+        Vec::new()
+    }
+}
+
+/// Store backend doing blocking I/O, with the operations of [`WasmStore`]. Used as a [`WasmStore`]
+/// through [`Blocking`].
+pub trait SyncWasmStore {
     fn load_module(&self, name: &str) -> Result<WasmModule, Error>;
 
-    /// Checks if module already exists in the store.
     fn contains_module(&self, name: &str) -> Result<bool, Error>;
 
-    /// Stores wasm module in store.
-    fn put_module(
-        &self,
-        name: &str,
-        code: &[u8],
-        host_modules: &[Cow<'_, str>],
-    ) -> Result<(), Error>;
+    fn put_module(&self, name: &str, module: &WasmModuleRef<'_, '_>) -> Result<(), Error>;
+
+    fn module_names(&self) -> Result<Vec<String>, Error> {
+        Err(Unsupported("Listing modules").into())
+    }
+
+    fn remove_module(&self, _name: &str) -> Result<(), Error> {
+        Err(Unsupported("Removing modules").into())
+    }
+
+    fn guest_kv(&self, _transactional: bool) -> Result<Option<GuestKv>, Error> {
+        Ok(None)
+    }
+
+    fn stats(&self) -> Result<Option<StoreStats>, Error> {
+        Ok(None)
+    }
+
+    fn readiness_checks(&self) -> Vec<ReadinessCheck> {
+        Vec::new()
+    }
+}
+
+/// Adapts a [`SyncWasmStore`] to a [`WasmStore`], running each operation on the blocking thread
+/// pool.
+pub struct Blocking<S>(pub Arc<S>);
+
+impl<S> Blocking<S> {
+    pub fn new(store: S) -> Self {
+        Self(Arc::new(store))
+    }
+}
+
+#[async_trait]
+impl<S> WasmStore for Blocking<S>
+where
+    S: SyncWasmStore + Send + Sync + 'static,
+{
+    async fn load_module(&self, name: &str) -> Result<WasmModule, Error> {
+        let (store, name) = (self.0.clone(), name.to_owned());
+        logger::spawn_blocking(move || store.load_module(&name)).await
+    }
+    async fn contains_module(&self, name: &str) -> Result<bool, Error> {
+        let (store, name) = (self.0.clone(), name.to_owned());
+        logger::spawn_blocking(move || store.contains_module(&name)).await
+    }
+    async fn put_module(&self, name: &str, module: &WasmModuleRef<'_, '_>) -> Result<(), Error> {
+        let (store, name, module) = (self.0.clone(), name.to_owned(), module.to_module());
+        logger::spawn_blocking(move || {
+            let host_modules: Vec<Cow<str>> = module
+                .host_modules
+                .iter()
+                .map(|m| m.as_str().into())
+                .collect();
+            let module = WasmModuleRef {
+                code: &module.code,
+                host_modules: &host_modules,
+                param_names: &module.param_names,
+                metadata: &module.metadata,
+                expires: module.expires,
+            };
+            store.put_module(&name, &module)
+        })
+        .await
+    }
+    async fn module_names(&self) -> Result<Vec<String>, Error> {
+        let store = self.0.clone();
+        logger::spawn_blocking(move || store.module_names()).await
+    }
+    async fn remove_module(&self, name: &str) -> Result<(), Error> {
+        let (store, name) = (self.0.clone(), name.to_owned());
+        logger::spawn_blocking(move || store.remove_module(&name)).await
+    }
+    async fn guest_kv(&self, transactional: bool) -> Result<Option<GuestKv>, Error> {
+        let store = self.0.clone();
+        logger::spawn_blocking(move || store.guest_kv(transactional)).await
+    }
+    async fn stats(&self) -> Result<Option<StoreStats>, Error> {
+        let store = self.0.clone();
+        logger::spawn_blocking(move || store.stats()).await
+    }
+    async fn readiness_checks(&self) -> Vec<ReadinessCheck> {
+        let store = self.0.clone();
+        logger::spawn_blocking(move || store.readiness_checks()).await
+    }
+}
+
+/// Stores chosen at runtime, like a backend wrapped in layers.
+#[async_trait]
+impl<S> WasmStore for Box<S>
+where
+    S: WasmStore + ?Sized,
+{
+    async fn load_module(&self, name: &str) -> Result<WasmModule, Error> {
+        (**self).load_module(name).await
+    }
+    async fn contains_module(&self, name: &str) -> Result<bool, Error> {
+        (**self).contains_module(name).await
+    }
+    async fn put_module(&self, name: &str, module: &WasmModuleRef<'_, '_>) -> Result<(), Error> {
+        (**self).put_module(name, module).await
+    }
+    async fn module_names(&self) -> Result<Vec<String>, Error> {
+        (**self).module_names().await
+    }
+    async fn remove_module(&self, name: &str) -> Result<(), Error> {
+        (**self).remove_module(name).await
+    }
+    async fn guest_kv(&self, transactional: bool) -> Result<Option<GuestKv>, Error> {
+        (**self).guest_kv(transactional).await
+    }
+    async fn stats(&self) -> Result<Option<StoreStats>, Error> {
+        (**self).stats().await
+    }
+    async fn readiness_checks(&self) -> Vec<ReadinessCheck> {
+        (**self).readiness_checks().await
+    }
+}
+
+/// Records of modules fetched from a store to be linked, by store key.
+pub type FetchedModules = HashMap<String, WasmModule>;
+
+/// Fetches the modules with the store keys and all of their dependencies. Dependencies are fetched
+/// level by level, with the host modules of all modules of a level fetched concurrently.
+pub async fn fetch_modules<S>(db: &S, keys: Vec<String>) -> Result<FetchedModules, Error>
+where
+    S: WasmStore + ?Sized,
+{
+    let mut modules = FetchedModules::new();
+    let mut level = keys;
+    while !level.is_empty() {
+        level.sort();
+        level.dedup();
+        let loaded = try_join_all(level.iter().map(|key| db.load_module(key))).await?;
+        let mut next = Vec::new();
+        for (key, module) in level.into_iter().zip(loaded) {
+            let namespace = namespace_of(&key);
+            next.extend(
+                module
+                    .host_modules
+                    .iter()
+                    .map(|m| resolve(m, namespace))
+                    .filter(|m| !modules.contains_key(m) && *m != key),
+            );
+            modules.insert(key, module);
+        }
+        next.retain(|m| !modules.contains_key(m));
+        level = next;
+    }
+    Ok(modules)
+}
+
+/// Loads wasm module from store, as well as loading all module dependencies, which are fetched
+/// concurrently. The host namespaces enabled in `host` are linked to every loaded module. Host
+/// modules are resolved in the namespace of the module.
+pub async fn load_wasm_module_recursive<S>(
+    db: &S,
+    module_name: &str,
+    host: &HostEnv,
+) -> Result<Instance, Error>
+where
+    S: WasmStore + ?Sized,
+{
+    let modules = fetch_modules(db, vec![module_name.to_owned()]).await?;
+    link_module(&modules, module_name, host)
 }
 
-/// Loads wasm module from store, as well as loading all module dependencies recursively.
-pub fn load_wasm_module_recursive<S>(db: &S, module_name: &str) -> Result<Instance, Error>
+/// Instantiates a fetched module, linked to its host modules, see [`fetch_modules`].
+pub fn link_module(
+    modules: &FetchedModules,
+    module_name: &str,
+    host: &HostEnv,
+) -> Result<Instance, Error> {
+    link_module_in(modules, module_name, host, &mut Vec::new())
+}
+
+/// Links the module, with `linking` the modules whose host modules are being linked. Fails if the
+/// module is one of them, as stores with hand-written records can have dependency cycles.
+fn link_module_in(
+    modules: &FetchedModules,
+    module_name: &str,
+    host: &HostEnv,
+    linking: &mut Vec<String>,
+) -> Result<Instance, Error> {
+    if linking.iter().any(|m| m == module_name) {
+        return Err(anyhow!("Dependency cycle through module {}", module_name));
+    }
+    let module = modules
+        .get(module_name)
+        .ok_or_else(|| anyhow!("Module {} was not fetched", module_name))?;
+    linking.push(module_name.to_owned());
+    let imports = link_host_modules_in(
+        modules,
+        &module.host_modules,
+        host,
+        namespace_of(module_name),
+        Some(module_name),
+        linking,
+    );
+    linking.pop();
+    instantiate_module(&module.code, &imports?, host, Some(module_name))
+}
+
+/// Instantiates the fetched host modules into an import object, along with the host namespaces
+/// enabled in `host`. Host modules are resolved in the `namespace`, and imported with the name
+/// they are referred to by. The `scope` is the name of the registered module being linked, if any.
+pub fn link_host_modules<N>(
+    modules: &FetchedModules,
+    host_modules: &[N],
+    host: &HostEnv,
+    namespace: &str,
+    scope: Option<&str>,
+) -> Result<ImportObject, Error>
 where
-    S: WasmStore,
+    N: AsRef<str>,
 {
-    let module = db.load_module(module_name)?;
+    let mut linking: Vec<String> = scope.map(str::to_owned).into_iter().collect();
+    link_host_modules_in(modules, host_modules, host, namespace, scope, &mut linking)
+}
 
+fn link_host_modules_in<N>(
+    modules: &FetchedModules,
+    host_modules: &[N],
+    host: &HostEnv,
+    namespace: &str,
+    scope: Option<&str>,
+    linking: &mut Vec<String>,
+) -> Result<ImportObject, Error>
+where
+    N: AsRef<str>,
+{
     let mut imports = ImportObject::new();
-    for sub_module in module.host_modules {
-        let loaded = load_wasm_module_recursive(db, sub_module.as_ref())?;
-        imports.register(sub_module, loaded);
+    for sub_module in host_modules {
+        let name = sub_module.as_ref();
+        let loaded = link_module_in(modules, &resolve(name, namespace), host, linking)?;
+        match host.tracer() {
+            Some(tracer) => imports.register(name, tracer.traced_namespace(name, loaded)),
+            None => imports.register(name, loaded),
+        };
+    }
+    host.register(&mut imports, scope)?;
+    Ok(imports)
+}
+
+/// Compiles and instantiates wasm code, recording the timings if tracing is enabled. The `name`
+/// is the registered module name, or `None` for the module being executed.
+pub fn instantiate_module(
+    code: &[u8],
+    imports: &ImportObject,
+    host: &HostEnv,
+    name: Option<&str>,
+) -> Result<Instance, Error> {
+    let start = Instant::now();
+    let module = compile(code).map_err(|e| anyhow!("{}", e))?;
+    let compiled = Instant::now();
+    let instance = module.instantiate(imports).map_err(|e| anyhow!("{}", e))?;
+
+    if let Some(tracer) = host.tracer() {
+        tracer.record_compile(name, compiled - start);
+        tracer.record_instantiate(name, compiled.elapsed());
     }
-    Ok(instantiate(module.code.as_ref(), &imports).map_err(|e| anyhow!("{}", e))?)
+    Ok(instance)
 }
 
 /// Stores wasm module to the database. This function also checks to make sure all of the
-/// dependency modules exist in the database before storing the code.
-pub fn store_wasm_module<S>(
+/// dependency modules exist in the database before storing the code, and that the module is within
+/// the `limits`. The `module_name` is the store key, which has the namespace of the module. With a
+/// `charge`, the module is charged to the usage counters, and removed from them if storing fails.
+pub async fn store_wasm_module<S>(
     db: &S,
     module_name: &str,
-    code: &[u8],
-    host_modules: &[Cow<'_, str>],
+    module: &WasmModuleRef<'_, '_>,
+    limits: &StoreLimits,
+    charge: Option<&Charge<'_>>,
 ) -> Result<(), Error>
 where
-    S: WasmStore,
+    S: WasmStore + ?Sized,
 {
+    namespace::validate_reference(module_name)?;
+    module.metadata.validate()?;
+
     // This check is just to short circuit the other logic, the insertion is unique.
-    if db.contains_module(module_name)? {
+    if db.contains_module(module_name).await? {
         return Err(anyhow!(
             "Could not store module: already exists in database",
         ));
     }
+    limits.check(module)?;
 
-    for module in host_modules {
-        if !db.contains_module(module)? {
+    for host_module in module.host_modules {
+        namespace::validate_reference(host_module)?;
+        if !db
+            .contains_module(&resolve(host_module, namespace_of(module_name)))
+            .await?
+        {
             return Err(anyhow!(
                 "Could not store module: dependency module {} does not exist in database",
-                module
+                host_module
             ));
         }
     }
+    check_dependency_cycle(db, module_name, module.host_modules).await?;
 
-    db.put_module(module_name, code, host_modules)?;
+    let size = module.code.len() as u64;
+    if let Some(charge) = charge {
+        charge.counters.charge(module_name, size, limits, charge)?;
+    }
+    if let Err(e) = db.put_module(module_name, module).await {
+        if let Some(charge) = charge {
+            charge.counters.release(module_name, size, charge.owner)?;
+        }
+        return Err(e);
+    }
 
     Ok(())
 }
+
+/// Returns an error if the module would depend on itself through its host modules. Registered
+/// modules can only depend on existing ones, but hand-written records of a directory store can
+/// refer to modules which don't exist yet. The dependencies are loaded level by level.
+async fn check_dependency_cycle<S, N>(
+    db: &S,
+    module_name: &str,
+    host_modules: &[N],
+) -> Result<(), Error>
+where
+    S: WasmStore + ?Sized,
+    N: AsRef<str>,
+{
+    let namespace = namespace_of(module_name);
+    let mut level: Vec<String> = host_modules
+        .iter()
+        .map(|m| resolve(m.as_ref(), namespace))
+        .collect();
+    let mut seen = HashSet::new();
+    while !level.is_empty() {
+        if level.iter().any(|m| m == module_name) {
+            return Err(anyhow!(
+                "Could not store module: dependency cycle through module {}",
+                module_name
+            ));
+        }
+        level.retain(|m| seen.insert(m.clone()));
+        let loaded = try_join_all(level.iter().map(|m| db.load_module(m))).await?;
+        level = level
+            .iter()
+            .zip(loaded)
+            .flat_map(|(name, module)| {
+                let namespace = namespace_of(name);
+                module
+                    .host_modules
+                    .iter()
+                    .map(|m| resolve(m, namespace))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+    }
+    Ok(())
+}
+
+/// Removes wasm module from the database. This function checks that no other module depends on
+/// the module before removing it. Returns the removed module.
+pub async fn delete_wasm_module<S>(db: &S, module_name: &str) -> Result<WasmModule, Error>
+where
+    S: WasmStore + ?Sized,
+{
+    if !db.contains_module(module_name).await? {
+        return Err(anyhow!(
+            "Could not delete module: {} does not exist in database",
+            module_name
+        ));
+    }
+    let module = db.load_module(module_name).await?;
+
+    for name in db.module_names().await? {
+        if db
+            .load_module(&name)
+            .await?
+            .host_modules
+            .iter()
+            .any(|m| resolve(m, namespace_of(&name)) == module_name)
+        {
+            return Err(anyhow!(
+                "Could not delete module: module {} depends on {}",
+                name,
+                module_name
+            ));
+        }
+    }
+
+    db.remove_module(module_name).await?;
+    Ok(module)
+}
@@ -0,0 +1,84 @@
+use anyhow::{anyhow, Error};
+
+/// Namespace of modules registered without one. Its modules are stored under their name, as
+/// before namespaces were added.
+pub const DEFAULT_NAMESPACE: &str = "default";
+/// Separator of the namespace and the name in module references and store keys.
+pub const SEPARATOR: char = '/';
+
+/// Store key of a module in a namespace.
+pub fn key(namespace: &str, name: &str) -> String {
+    if namespace == DEFAULT_NAMESPACE {
+        name.to_owned()
+    } else {
+        format!("{}{}{}", namespace, SEPARATOR, name)
+    }
+}
+
+/// Splits a module reference or store key into its namespace and name, if it has a namespace.
+pub fn split(reference: &str) -> Option<(&str, &str)> {
+    let mut parts = reference.splitn(2, SEPARATOR);
+    let namespace = parts.next()?;
+    parts.next().map(|name| (namespace, name))
+}
+
+/// Namespace of a store key.
+pub fn namespace_of(key: &str) -> &str {
+    match split(key) {
+        Some((namespace, _)) => namespace,
+        None => DEFAULT_NAMESPACE,
+    }
+}
+
+/// Resolves a module reference to its store key. A `namespace/module` reference is in that
+/// namespace, other references are in the namespace given.
+pub fn resolve(reference: &str, namespace: &str) -> String {
+    match split(reference) {
+        Some((namespace, name)) => key(namespace, name),
+        None => key(namespace, reference),
+    }
+}
+
+/// Checks that a namespace is not empty and only has lowercase letters, digits, `-` and `_`.
+pub fn validate_namespace(namespace: &str) -> Result<(), Error> {
+    let valid = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_';
+    if namespace.is_empty() || !namespace.chars().all(valid) {
+        return Err(anyhow!("Invalid namespace {:?}", namespace));
+    }
+    Ok(())
+}
+
+/// Checks that a module reference has a valid namespace, if any, and a name.
+pub fn validate_reference(reference: &str) -> Result<(), Error> {
+    let name = match split(reference) {
+        Some((namespace, name)) => {
+            validate_namespace(namespace)?;
+            name
+        }
+        None => reference,
+    };
+    if name.is_empty() || name.contains(SEPARATOR) {
+        return Err(anyhow!("Invalid module name {:?}", reference));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_references() {
+        assert_eq!(resolve("utils", DEFAULT_NAMESPACE), "utils");
+        assert_eq!(resolve("utils", "team-a"), "team-a/utils");
+        assert_eq!(resolve("team-b/utils", "team-a"), "team-b/utils");
+        assert_eq!(resolve("default/utils", "team-a"), "utils");
+        assert_eq!(namespace_of("team-a/utils"), "team-a");
+        assert_eq!(namespace_of("utils"), DEFAULT_NAMESPACE);
+
+        assert!(validate_reference("team-a/utils").is_ok());
+        assert!(validate_reference("Team/utils").is_err());
+        assert!(validate_reference("team-a/").is_err());
+        assert!(validate_reference("a/b/c").is_err());
+    }
+}
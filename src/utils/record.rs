@@ -0,0 +1,149 @@
+use super::{ModuleMetadata, WasmModule};
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+use serde_cbor::value::{from_value, to_value};
+use serde_cbor::{from_slice, to_vec, Value};
+use serde_tuple::Deserialize_tuple;
+use std::collections::HashMap;
+
+/// Version of the module records written by this version. Records written by older versions are
+/// upgraded when read, by applying the migrations from their version in order. Version 1 records
+/// are the positional tuples stored before the envelope was added.
+pub const CURRENT_VERSION: u32 = 2;
+
+/// Envelope of a stored record.
+#[derive(Serialize, Deserialize)]
+struct Envelope<T> {
+    version: u32,
+    data: T,
+}
+
+/// Upgrades the data of a record to a version from the version before it.
+pub struct Migration {
+    pub to: u32,
+    pub description: &'static str,
+    migrate: fn(Value) -> Result<Value, Error>,
+}
+
+/// Migrations of each version after the first, in order.
+pub const MIGRATIONS: &[Migration] = &[Migration {
+    to: 2,
+    description: "positional tuple to versioned envelope with named fields",
+    migrate: tuple_to_named,
+}];
+
+/// Module record of version 1.
+#[derive(Deserialize_tuple)]
+struct ModuleV1 {
+    code: Vec<u8>,
+    host_modules: Vec<String>,
+    #[serde(default)]
+    param_names: HashMap<String, Vec<String>>,
+}
+
+fn tuple_to_named(data: Value) -> Result<Value, Error> {
+    let v1: ModuleV1 = from_value(data)?;
+    Ok(to_value(WasmModule {
+        code: v1.code,
+        host_modules: v1.host_modules,
+        param_names: v1.param_names,
+        metadata: ModuleMetadata::default(),
+        expires: None,
+    })?)
+}
+
+/// Migrations to apply to a record of the version.
+pub fn migrations_from(version: u32) -> impl Iterator<Item = &'static Migration> {
+    MIGRATIONS.iter().filter(move |m| m.to > version)
+}
+
+/// Splits a record into its version and data.
+fn open(bytes: &[u8]) -> Result<(u32, Value), Error> {
+    match from_slice(bytes)? {
+        data @ Value::Array(_) => Ok((1, data)),
+        envelope @ Value::Map(_) => {
+            let Envelope { version, data } = from_value(envelope)?;
+            if version > CURRENT_VERSION {
+                return Err(anyhow!(
+                    "Record version {} is newer than the supported version {}",
+                    version,
+                    CURRENT_VERSION
+                ));
+            }
+            Ok((version, data))
+        }
+        _ => Err(anyhow!("Invalid module record")),
+    }
+}
+
+/// Version of a stored record.
+pub fn record_version(bytes: &[u8]) -> Result<u32, Error> {
+    Ok(open(bytes)?.0)
+}
+
+/// Encodes a `WasmModule` or `WasmModuleRef` as a record of the current version.
+pub fn encode_module<M: Serialize>(module: &M) -> Result<Vec<u8>, Error> {
+    Ok(to_vec(&Envelope {
+        version: CURRENT_VERSION,
+        data: module,
+    })?)
+}
+
+/// Decodes a record, upgrading it if it was written by an older version. Returns whether it was
+/// upgraded, so it can be stored again.
+pub fn decode_module(bytes: &[u8]) -> Result<(WasmModule, bool), Error> {
+    let (version, mut data) = open(bytes)?;
+    for migration in migrations_from(version) {
+        log::debug!(
+            "Upgrading record to version {}: {}",
+            migration.to,
+            migration.description
+        );
+        data = (migration.migrate)(data)?;
+    }
+    Ok((from_value(data)?, version < CURRENT_VERSION))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::WasmModuleRef;
+
+    #[test]
+    fn upgrade_records() {
+        let param_names = [("run".to_owned(), vec!["a".to_owned()])]
+            .iter()
+            .cloned()
+            .collect();
+        let module = WasmModuleRef {
+            code: b"test code",
+            host_modules: &["one".into()],
+            param_names: &param_names,
+            metadata: &ModuleMetadata::default(),
+            expires: None,
+        };
+        let record = encode_module(&module).unwrap();
+        assert_eq!(record_version(&record).unwrap(), CURRENT_VERSION);
+        let (decoded, upgraded) = decode_module(&record).unwrap();
+        assert!(!upgraded);
+        assert_eq!(decoded.code, b"test code");
+        assert_eq!(decoded.param_names, param_names);
+
+        // Version 1 records, with and without param names.
+        let v1 = to_vec(&(b"test code".to_vec(), ["one"], &param_names)).unwrap();
+        assert_eq!(record_version(&v1).unwrap(), 1);
+        let (decoded, upgraded) = decode_module(&v1).unwrap();
+        assert!(upgraded);
+        assert_eq!(decoded.host_modules, ["one"]);
+        assert_eq!(decoded.param_names, param_names);
+        let v1 = to_vec(&(b"test code".to_vec(), ["one"])).unwrap();
+        assert!(decode_module(&v1).unwrap().0.param_names.is_empty());
+
+        let newer = to_vec(&Envelope {
+            version: CURRENT_VERSION + 1,
+            data: (),
+        })
+        .unwrap();
+        assert!(decode_module(&newer).is_err());
+    }
+}
@@ -0,0 +1,137 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use wasmer_runtime::{DynFunc, Instance};
+use wasmer_runtime_core::{export::Export, import::Namespace, typed_func::DynamicFunc};
+
+/// Timings of an execution, in microseconds.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default, Clone, PartialEq)]
+pub struct Trace {
+    /// Time to load all host modules of the executed module, including storage reads.
+    pub load_us: u64,
+    /// Time to compile the executed module.
+    pub compile_us: u64,
+    /// Time to instantiate the executed module.
+    pub instantiate_us: u64,
+    /// Time of the function call.
+    pub call_us: u64,
+    /// Time of the whole execution.
+    pub total_us: u64,
+    /// Number of calls from guests into host modules.
+    pub host_module_calls: u64,
+    /// Timings of each host module loaded, keyed by module name.
+    pub modules: BTreeMap<String, ModuleTrace>,
+}
+
+/// Timings of a host module, in microseconds.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default, Clone, PartialEq)]
+pub struct ModuleTrace {
+    /// Total time spent compiling the module.
+    pub compile_us: u64,
+    /// Total time spent instantiating the module.
+    pub instantiate_us: u64,
+    /// Number of times functions of the module were called by other guests.
+    pub calls: u64,
+}
+
+/// Collects the trace of an execution, shared by all modules loaded for the request.
+pub struct Tracer {
+    trace: Mutex<Trace>,
+    started: Instant,
+}
+
+impl Default for Tracer {
+    fn default() -> Self {
+        Self {
+            trace: Default::default(),
+            started: Instant::now(),
+        }
+    }
+}
+
+impl Tracer {
+    /// Records the compile time of a module. If `module` is `None`, this is the executed module.
+    pub fn record_compile(&self, module: Option<&str>, duration: Duration) {
+        let mut trace = self.trace.lock().unwrap();
+        match module {
+            Some(name) => trace.module(name).compile_us += as_micros(duration),
+            None => trace.compile_us += as_micros(duration),
+        }
+    }
+
+    /// Records the instantiate time of a module. If `module` is `None`, this is the executed
+    /// module.
+    pub fn record_instantiate(&self, module: Option<&str>, duration: Duration) {
+        let mut trace = self.trace.lock().unwrap();
+        match module {
+            Some(name) => trace.module(name).instantiate_us += as_micros(duration),
+            None => trace.instantiate_us += as_micros(duration),
+        }
+    }
+
+    /// Records the time to load the host modules of the executed module.
+    pub fn record_load(&self, duration: Duration) {
+        self.trace.lock().unwrap().load_us += as_micros(duration);
+    }
+
+    /// Records the time of the function call.
+    pub fn record_call(&self, duration: Duration) {
+        self.trace.lock().unwrap().call_us += as_micros(duration);
+    }
+
+    fn record_host_call(&self, module: &str) {
+        let mut trace = self.trace.lock().unwrap();
+        trace.host_module_calls += 1;
+        trace.module(module).calls += 1;
+    }
+
+    /// Returns the trace, with the total time since the tracer was created.
+    pub fn finish(&self) -> Trace {
+        let mut trace = self.trace.lock().unwrap().clone();
+        trace.total_us = as_micros(self.started.elapsed());
+        trace
+    }
+
+    /// Wraps the function exports of a host module instance to count calls into the module.
+    pub fn traced_namespace(self: &Arc<Self>, module: &str, instance: Instance) -> Namespace {
+        let exports: Vec<_> = instance.exports().collect();
+        let instance = Rc::new(instance);
+        let mut ns = Namespace::new();
+        for (name, export) in exports {
+            match export {
+                Export::Function { signature, .. } => {
+                    let (instance, tracer) = (instance.clone(), self.clone());
+                    let (module, fn_name) = (module.to_owned(), name.clone());
+                    let func = DynamicFunc::new(signature, move |_, args| {
+                        tracer.record_host_call(&module);
+                        let func: DynFunc = instance
+                            .exports
+                            .get(&fn_name)
+                            .expect("function was exported by the instance");
+                        // Panics are caught by the runtime and converted into a trap.
+                        func.call(args).unwrap_or_else(|e| panic!("{}", e))
+                    });
+                    ns.insert(name, func);
+                }
+                // Memories, tables and globals are shared, they keep their own backing alive.
+                export => {
+                    ns.insert(name, export);
+                }
+            }
+        }
+        ns
+    }
+}
+
+impl Trace {
+    fn module(&mut self, name: &str) -> &mut ModuleTrace {
+        self.modules.entry(name.to_owned()).or_default()
+    }
+}
+
+fn as_micros(duration: Duration) -> u64 {
+    duration.as_micros() as u64
+}
@@ -1,35 +1,226 @@
+use super::host::HostEnv;
+use super::instantiate_module;
+use crate::metrics::METRICS;
 use anyhow::{anyhow, Error};
-use serde_json::Number;
+use schemars::JsonSchema;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{Number, Value};
+use std::collections::HashMap;
 use std::string::ToString;
-use wasmer_runtime::{
-    instantiate, types::Type, DynFunc, ImportObject, Instance, Value as WasmValue,
-};
+use std::time::Instant;
+use wasmer_runtime::{compile, types::Type, DynFunc, ImportObject, Instance, Value as WasmValue};
+use wasmer_runtime_core::backend::ExceptionCode;
+use wasmer_runtime_core::error::{CallError, InvokeError, RuntimeError};
+use wasmer_runtime_core::{module::ExportIndex, structures::TypedIndex};
+use wasmparser::{Name, NameSectionReader};
+
+/// Parameters to call a function with, either positional or keyed by argument name.
+#[derive(Serialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Params {
+    Positional(Vec<Number>),
+    Named(HashMap<String, Number>),
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Params::Positional(Vec::new())
+    }
+}
+
+impl From<Vec<Number>> for Params {
+    fn from(params: Vec<Number>) -> Self {
+        Params::Positional(params)
+    }
+}
+
+impl<'de> Deserialize<'de> for Params {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        use serde::de::Error as _;
+        // Deserialized through a value, untagged buffering doesn't support arbitrary precision
+        // numbers.
+        let to_number = |v: Value| match v {
+            Value::Number(n) => Ok(n),
+            v => Err(D::Error::custom(format!(
+                "Invalid param, expected number, was {}",
+                v
+            ))),
+        };
+        match Value::deserialize(deserializer)? {
+            Value::Array(values) => Ok(Params::Positional(
+                values
+                    .into_iter()
+                    .map(to_number)
+                    .collect::<Result<_, _>>()?,
+            )),
+            Value::Object(map) => Ok(Params::Named(
+                map.into_iter()
+                    .map(|(k, v)| Ok((k, to_number(v)?)))
+                    .collect::<Result<_, _>>()?,
+            )),
+            v => Err(D::Error::custom(format!(
+                "Invalid params, expected array or object, was {}",
+                v
+            ))),
+        }
+    }
+}
+
+/// Names of the functions exported by the wasm code, sorted.
+pub fn exported_functions(code: &[u8]) -> Result<Vec<String>, Error> {
+    let module = compile(code).map_err(|e| anyhow!("{}", e))?;
+    let mut names: Vec<String> = module
+        .info()
+        .exports
+        .iter()
+        .filter(|(_, index)| matches!(index, ExportIndex::Func(_)))
+        .map(|(name, _)| name.clone())
+        .collect();
+    names.sort();
+    Ok(names)
+}
 
 /// Instantiates Wasm module and calls function name provided from the module.
 pub fn execute_wasm(
     wasm_bytes: &[u8],
     function_name: &str,
-    params: Vec<Number>,
+    params: Params,
     imports: &ImportObject,
+    host: &HostEnv,
 ) -> Result<Vec<WasmValue>, Error> {
     // Instantiate the wasm runtime
-    let instance = instantiate(wasm_bytes, imports).map_err(|e| anyhow!("{}", e))?;
+    let instance = instantiate_module(wasm_bytes, imports, host, None)?;
 
-    call_fn(&instance, &function_name, params)
+    let start = Instant::now();
+    let res = call_fn(&instance, function_name, params, None);
+    if let Some(tracer) = host.tracer() {
+        tracer.record_call(start.elapsed());
+    }
+    res
 }
 
 /// Calls the dynamic function with the params deserialized based on the function signature type.
+///
+/// Named params are matched to the `param_names` given, or to the local names of the function
+/// in the module's name section if not provided.
 pub fn call_fn(
     instance: &Instance,
     fn_name: &str,
-    params: Vec<Number>,
+    params: Params,
+    param_names: Option<&[String]>,
 ) -> Result<Vec<WasmValue>, Error> {
-    let function: DynFunc = instance.exports.get(&fn_name)?;
+    let function: DynFunc = instance.exports.get(fn_name)?;
     let sig_params = function.signature().params();
 
+    let params = match params {
+        Params::Positional(params) => params,
+        Params::Named(named) => {
+            let names = match param_names {
+                Some(names) => names.to_vec(),
+                None => {
+                    param_local_names(instance, fn_name, sig_params.len())?.ok_or_else(|| {
+                        anyhow!(
+                            "Could not call {} with named params: no parameter names found",
+                            fn_name
+                        )
+                    })?
+                }
+            };
+            named_to_positional(named, &names)?
+        }
+    };
+
     let wasm_params = params_to_wasm(params, sig_params)?;
 
-    Ok(function.call(&wasm_params).map_err(|e| anyhow!("{}", e))?)
+    function.call(&wasm_params).map_err(|e| {
+        METRICS.record_trap(trap_kind(&e));
+        anyhow!("{}", e)
+    })
+}
+
+/// Kind of a failed call, used as the metrics label.
+fn trap_kind(err: &CallError) -> &'static str {
+    match err {
+        CallError::Resolve(_) => "resolve",
+        CallError::Runtime(RuntimeError::InvokeError(InvokeError::TrapCode { code, .. })) => {
+            match code {
+                ExceptionCode::Unreachable => "unreachable",
+                ExceptionCode::MemoryOutOfBounds => "memory_out_of_bounds",
+                ExceptionCode::IllegalArithmetic => "illegal_arithmetic",
+                ExceptionCode::IncorrectCallIndirectSignature | ExceptionCode::CallIndirectOOB => {
+                    "call_indirect"
+                }
+                ExceptionCode::MisalignedAtomicAccess => "misaligned_atomic_access",
+            }
+        }
+        // Errors returned by host functions, including guest aborts and panics.
+        CallError::Runtime(RuntimeError::User(_)) => "host",
+        CallError::Runtime(_) => "other",
+    }
+}
+
+/// Orders the named param values by the parameter names of the function.
+fn named_to_positional(
+    mut named: HashMap<String, Number>,
+    names: &[String],
+) -> Result<Vec<Number>, Error> {
+    let params = names
+        .iter()
+        .map(|name| {
+            named
+                .remove(name)
+                .ok_or_else(|| anyhow!("Missing parameter {}", name))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if let Some(name) = named.keys().next() {
+        return Err(anyhow!("Unknown parameter {}", name));
+    }
+    Ok(params)
+}
+
+/// Reads the parameter names of an exported function from the local names of the module's
+/// name custom section. Returns `None` if any of the parameters are not named.
+fn param_local_names(
+    instance: &Instance,
+    fn_name: &str,
+    param_count: usize,
+) -> Result<Option<Vec<String>>, Error> {
+    let module = instance.module();
+    let func_index = match module.info().exports.get(fn_name) {
+        Some(ExportIndex::Func(index)) => index.index() as u32,
+        _ => return Ok(None),
+    };
+
+    let mut names = vec![None; param_count];
+    for section in module.custom_sections("name").unwrap_or_default() {
+        let mut reader = NameSectionReader::new(section, 0)?;
+        while !reader.eof() {
+            let locals = match reader.read()? {
+                Name::Local(locals) => locals,
+                _ => continue,
+            };
+            let mut func_reader = locals.get_function_local_reader()?;
+            for _ in 0..func_reader.get_count() {
+                let func = func_reader.read()?;
+                if func.func_index != func_index {
+                    continue;
+                }
+                let mut map = func.get_map()?;
+                for _ in 0..map.get_count() {
+                    let naming = map.read()?;
+                    // Params are the first locals of the function, other locals are ignored.
+                    if let Some(name) = names.get_mut(naming.index as usize) {
+                        *name = Some(naming.name.to_string());
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(names.into_iter().collect())
 }
 
 /// Converts the parameter values to the Wasmer value types to be used in execution.